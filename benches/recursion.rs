@@ -0,0 +1,96 @@
+// Timings for the kind of recursive code test.dbt is full of, run with:
+//
+//   cargo bench
+//
+// Each program runs a few times on a fresh interpreter and the best time is
+// reported, so the numbers are comparable from one build to the next, first
+// walking the syntax tree and then as bytecode (--vm).
+
+extern crate doubtful;
+
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use doubtful::tokenizer;
+use doubtful::parser;
+use doubtful::evaluator;
+use doubtful::encoding::Interpreter;
+use doubtful::encoding::Evaluation;
+
+const RUNS: usize = 5;
+
+const HELPERS: &str = "
+len(list):?(=(list,nil),~(0),nil);+(1,len(cdr(list)));;
+
+@(list,func):
+  ?(=(cdr(list),nil),~([$(func,car(list))]),nil);
+  +([$(func,car(list))],@(cdr(list),func));;
+
+.(n,m):
+  ?(=(n,m),~([m]),nil);
+  +([n],.(+(n,1),m));;
+";
+
+const PROGRAMS: &[(&str, &str)] = &[
+  ("deep recursion", "
+count(n):?(=(n,0),~(0),nil);+(1,count(-(n,1)));;
+count(2000);
+"),
+  ("fibonacci", "
+fib(n):?(<(n,2),~(n),nil);+(fib(-(n,1)),fib(-(n,2)));;
+fib(18);
+"),
+  ("list building", "
+len(@(.(1,1000),(n):*(n,n);));
+"),
+  ("long function bodies", "
+long(n):
+  a:+(n,1);; b:+(a,1);; c:+(b,1);; d:+(c,1);; e:+(d,1);;
+  f:+(e,1);; g:+(f,1);; h:+(g,1);; i:+(h,1);; j:+(i,1);;
+  ?(=(n,0),~(j),nil);
+  *(1,long(-(n,1)));;
+long(1000);
+"),
+  ("string arguments", "
+s:repeat(\"doubtful \",1000);;
+walk(str,n):?(=(n,0),~(strlen(str)),nil);walk(str,-(n,1));;
+walk(s,2000);
+"),
+];
+
+fn time(source: &str, bytecode: bool) -> Duration {
+  let tokens = tokenizer::tokenize(source).unwrap();
+  let block = parser::parse(&tokens).unwrap();
+  let mut best = None;
+  for _ in 0..RUNS {
+    let mut interp = Interpreter::new();
+    interp.bytecode = bytecode;
+    let start = Instant::now();
+    let rc = interp.run(&block);
+    let elapsed = start.elapsed();
+    if let Evaluation::Exception(e) = rc {
+      panic!("benchmark failed: {}", e);
+    }
+    if best.is_none_or(|b| elapsed < b) {
+      best = Some(elapsed);
+    }
+  }
+  best.unwrap()
+}
+
+fn main() {
+  let stack = evaluator::stack_size(evaluator::DEFAULT_MAX_DEPTH);
+  let child = thread::Builder::new().stack_size(stack).spawn(|| {
+    println!("{:24} {:>13} {:>13} {:>8}", "", "tree", "bytecode", "speedup");
+    for &(name, program) in PROGRAMS {
+      let source = format!("{}{}", HELPERS, program);
+      let tree = time(&source, false);
+      let bytecode = time(&source, true);
+      println!("{:24} {:>10.3} ms {:>10.3} ms {:>7.2}x", name,
+               tree.as_secs_f64() * 1000.0, bytecode.as_secs_f64() * 1000.0,
+               tree.as_secs_f64() / bytecode.as_secs_f64());
+    }
+  });
+  child.unwrap().join().unwrap();
+}
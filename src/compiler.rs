@@ -0,0 +1,198 @@
+// Compile a parsed program to a self-contained C file.  The generated code
+// does what the interpreter would, expression by expression, on top of a
+// small runtime (runtime.c, included verbatim) with the same values, scoping,
+// exceptions and primitives, so a compiled program prints the same output as
+// an interpreted one.
+//
+// Scoping is dynamic, so an argument never needs anything from where it was
+// written, only the bindings at the time it's evaluated: every argument (and
+// list item) becomes a C function of no arguments that the runtime calls when
+// it wants the value, and every block becomes a C function of its calling
+// context.  Calls, definitions and constants are static data that the runtime
+// works through.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use evaluator;
+use symbols;
+
+use encoding::Block;
+use encoding::Expression;
+use encoding::Call;
+use encoding::Definition;
+
+const RUNTIME: &str = include_str!("runtime.c");
+
+struct Emitter {
+  code: String,
+  next: usize,
+  // Bodies already emitted, function bodies being shared between the
+  // definitions they were copied to
+  bodies: HashMap<*const Block, String>
+}
+
+// A C string literal, with everything but plain ASCII escaped so the result
+// doesn't depend on the C compiler's idea of the source character set
+fn literal(s: &str) -> String {
+  let mut rc = "\"".to_string();
+  for b in s.bytes() {
+    if (0x20..0x7f).contains(&b) && b != b'"' && b != b'\\' && b != b'?' {
+      rc.push(b as char);
+    } else {
+      rc += &format!("\\{:03o}", b);
+    }
+  }
+  rc.push('"');
+  rc
+}
+
+impl Emitter {
+  fn name(&mut self, prefix: &str) -> String {
+    self.next += 1;
+    format!("{}{}", prefix, self.next)
+  }
+
+  // A C expression for the value of an expression
+  fn expression(&mut self, e: &Expression) -> String {
+    match e {
+      &Expression::Nil => "dbt_nil()".to_string(),
+      &Expression::True => "dbt_true()".to_string(),
+      &Expression::False => "dbt_false()".to_string(),
+      &Expression::Integer(x) => {
+        if x == i64::MIN {
+          "dbt_int(INT64_MIN)".to_string()
+        } else {
+          format!("dbt_int(INT64_C({}))", x)
+        }
+      },
+      // The exact bits, since C and Rust needn't agree on decimal conversion
+      &Expression::Float(x) => {
+        format!("dbt_float_bits(UINT64_C({:#x}))", x.to_bits())
+      },
+      &Expression::String(ref s) => {
+        let name = self.name("s");
+        self.code += &format!("static const Str {} = {{ {}, {} }};\n", name,
+                              s.len(), literal(s));
+        format!("dbt_string(&{})", name)
+      },
+      &Expression::List(ref list) => {
+        let thunks = self.thunks(&list.items);
+        format!("dbt_list({}, {})", list.items.len(), thunks)
+      },
+      &Expression::Call(ref call) => self.call(call),
+      &Expression::Definition(ref def) => self.definition(def),
+    }
+  }
+
+  // An array of functions evaluating each of the expressions, or NULL
+  fn thunks(&mut self, items: &Vec<Expression>) -> String {
+    if items.is_empty() {
+      return "NULL".to_string();
+    }
+    let mut names = Vec::new();
+    for i in items {
+      let value = self.expression(i);
+      let name = self.name("a");
+      self.code += &format!("static V {}(void) {{\n  return {};\n}}\n", name,
+                            value);
+      names.push(name);
+    }
+    let name = self.name("k");
+    self.code += &format!("static const Thunk {}[] = {{ {} }};\n", name,
+                          names.join(", "));
+    name
+  }
+
+  fn call(&mut self, call: &Call) -> String {
+    let thunks = self.thunks(&call.params);
+    let name = self.name("c");
+    self.code += &format!("static Site {} = {{ {}, {}, {}, {}, NULL, 0 }};\n",
+                          name, literal(&symbols::name(call.id)), call.id,
+                          call.params.len(),
+                          thunks);
+    format!("dbt_call(&{})", name)
+  }
+
+  fn definition(&mut self, def: &Definition) -> String {
+    let body = self.block(&def.block);
+    let (params, slots) = if def.params.is_empty() {
+      ("NULL".to_string(), "NULL".to_string())
+    } else {
+      let params = self.name("p");
+      let names: Vec<String> = def.params.iter().map(|p| {
+        literal(&symbols::name(*p))
+      }).collect();
+      self.code += &format!("static const char *const {}[] = {{ {} }};\n",
+                            params, names.join(", "));
+      let slots = self.name("n");
+      let numbers: Vec<String> = def.params.iter().map(|p| {
+        p.to_string()
+      }).collect();
+      self.code += &format!("static const int {}[] = {{ {} }};\n", slots,
+                            numbers.join(", "));
+      (params, slots)
+    };
+    let func = self.name("f");
+    self.code += &format!("static const Fn {} = {{ {}, {}, {}, {}, NULL }};\n",
+                          func, def.params.len(), params, slots, body);
+    let name = self.name("d");
+    self.code += &format!("static const Def {} = {{ {}, {}, &{} }};\n", name,
+                          literal(&symbols::name(def.id)), def.id, func);
+    format!("dbt_define(&{})", name)
+  }
+
+  // A C function evaluating the block in a scope of its own, returning the
+  // name of the function
+  fn block(&mut self, block: &Block) -> String {
+    let key = block as *const Block;
+    if let Some(name) = self.bodies.get(&key) {
+      return name.clone();
+    }
+    let mut statements = Vec::new();
+    for e in &block.expressions {
+      statements.push(self.expression(e));
+    }
+    let name = self.name("b");
+    let mut s = format!("static V {}(const char *context) {{\n", name);
+    s += "  V value = dbt_nil();\n";
+    s += "  dbt_push_scope();\n";
+    for st in statements {
+      s += &format!("  value = {};\n", st);
+      s += "  if (value.type == T_EXCEPTION) {\n";
+      s += "    goto done;\n";
+      s += "  }\n";
+    }
+    s += " done:\n";
+    s += "  value = dbt_leave_block(value, context);\n";
+    s += "  dbt_pop_scope();\n";
+    s += "  return value;\n";
+    s += "}\n";
+    self.code += &s;
+    self.bodies.insert(key, name.clone());
+    name
+  }
+}
+
+pub fn compile(block: &Block) -> String {
+  // main(args) is called at the end, as evaluate_with does it
+  let mut program = block.clone();
+  let has_main = evaluator::has_main(block);
+  if has_main {
+    let args = Call { id: symbols::ARGS, params: Vec::new() };
+    let call = Call { id: symbols::intern("main"),
+                      params: vec![Expression::Call(Rc::new(args))] };
+    program.expressions.push(Expression::Call(Rc::new(call)));
+  }
+
+  let mut emitter = Emitter { code: String::new(), next: 0,
+                              bodies: HashMap::new() };
+  let main = emitter.block(&program);
+  let mut rc = RUNTIME.to_string();
+  rc += "\n/* The program */\n\n";
+  rc += &emitter.code;
+  rc += &format!("\nint main(int argc, char **argv) {{\n  \
+                  return dbt_main(argc, argv, {}, {});\n}}\n", main,
+                 if has_main { 1 } else { 0 });
+  rc
+}
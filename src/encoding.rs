@@ -1,21 +1,38 @@
 // Our internal representation of the language
 
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
+
+use memo::Memo;
+use vm::Chunk;
 
 pub enum Token {
   Colon, Semicolon, Comma,
   OpenParen, CloseParen, OpenBracket, CloseBracket, OpenBrace, CloseBrace,
   ID(String), Integer(i64), Float(f64), String(String),
+  Interpolated(Vec<StringPart>),
   True, False, Nil, EOF
 }
 
+// Pieces of a string literal with ${...} in it
+pub enum StringPart {
+  Text(String), Code(Vec<Token>)
+}
+
+// An interned identifier (see symbols.rs)
+pub type Symbol = usize;
+
 pub struct Block {
   pub expressions: Vec<Expression>
 }
 
+// Lists and calls are shared, like function bodies, so the evaluator can keep
+// hold of the one it's in the middle of without borrowing the whole tree
 pub enum Expression {
-  Nil, True, False, Integer(i64), Float(f64), String(String), List(List),
-  Call(Call), Definition(Definition)
+  Nil, True, False, Integer(i64), Float(f64), String(Rc<str>), List(Rc<List>),
+  Call(Rc<Call>), Definition(Definition)
   //, Hash(Hash)
 }
 
@@ -24,18 +41,74 @@ pub struct List {
 }
 
 pub struct Call {
-  pub id: String,
+  pub id: Symbol,
   pub params: Vec<Expression>
 }
 
+// Function parameters and bodies are shared between the definition and every
+// function value made from it, so passing functions around never copies them
 pub struct Definition {
-  pub id: String,
-  pub params: Vec<String>,
-  pub block: Block
+  pub id: Symbol,
+  pub params: Rc<Vec<Symbol>>,
+  pub block: Rc<Block>
 }
 
+// The symbols bound by one level of the dynamic scope, so they can be
+// unbound when it ends
 pub struct Scope {
-  pub bindings: HashMap<String, FunctionOrValue>
+  pub symbols: Vec<Symbol>
+}
+
+pub struct Binding {
+  // Index of the scope the binding was made in
+  pub level: usize,
+  pub value: FunctionOrValue
+}
+
+// Evaluation state: the dynamic scope stack plus call depth tracking and
+// resource limits for running untrusted code.  Bindings are kept as a stack
+// per symbol, the innermost on top, which is the same thing as searching the
+// scopes from the inside out
+pub struct Interpreter {
+  pub scope: Vec<Scope>,
+  pub bindings: Vec<Vec<Binding>>,
+  pub depth: usize,
+  pub max_depth: usize,
+  // Primitives calling back into functions (like sort_by) nest on the Rust
+  // stack rather than the evaluator's, so they have a limit of their own,
+  // which the thread's stack has to have room for (see evaluator::stack_size)
+  pub callbacks: usize,
+  pub max_callbacks: usize,
+  pub capabilities: Capabilities,
+  // Command-line arguments, visible to the program as "args"
+  pub args: Vec<String>,
+  // Limits, all unlimited when None: evaluation steps, items in any one list
+  // or bytes in any one string, and wall-clock time for a run
+  pub fuel: Option<u64>,
+  pub max_size: Option<usize>,
+  pub timeout: Option<Duration>,
+  // How the last run was terminated by a limit, if it was
+  pub terminated: Option<ExceptionType>,
+  pub remaining: u64,
+  pub deadline: Option<Instant>,
+  // Run programs as bytecode on the stack machine in vm.rs rather than by
+  // walking the syntax tree, with function bodies compiled on first use
+  pub bytecode: bool,
+  pub compiled: HashMap<usize, (Rc<Block>, Rc<Chunk>)>,
+  // Run programs through the optimizer in optimizer.rs first
+  pub optimize: bool
+}
+
+// Which groups of I/O primitives scripts are allowed to use
+pub struct Capabilities {
+  // Writing to stdout
+  pub output: bool,
+  // Reading, writing and listing files and directories
+  pub files: bool,
+  // Reading environment variables
+  pub environment: bool,
+  // Running other programs
+  pub process: bool
 }
 
 pub enum FunctionOrValue {
@@ -43,27 +116,49 @@ pub enum FunctionOrValue {
 }
 
 pub enum Evaluation {
-  Nil, True, False, Integer(i64), Float(f64), String(String), List(ListEval),
+  Nil, True, False, Integer(i64), Float(f64), String(Rc<str>), List(ListEval),
   Function(Function), Exception(Exception)
   //, Hash(Hash)
 }
 
+// Lists are persistent balanced trees that share structure, so taking the
+// car or cdr, adding items at either end, or joining two lists never copies
+// more than a path through the tree (see encoding_list)
 pub struct ListEval {
-  pub items: Vec<Evaluation>
+  pub root: Option<Rc<ListNode>>
+}
+
+pub struct ListNode {
+  pub left: Option<Rc<ListNode>>,
+  pub item: Evaluation,
+  pub right: Option<Rc<ListNode>>,
+  pub size: usize,
+  pub height: usize
 }
 
 pub struct Function {
-  pub params: Vec<String>,
-  pub block: Block
+  pub params: Rc<Vec<Symbol>>,
+  pub block: Rc<Block>,
+  // The cache of results if it was made by memo()
+  pub memo: Option<Rc<Memo>>
 }
 
 pub struct Exception {
   pub flavor: ExceptionType,
   pub payload: Box<Evaluation>,
-  pub stack: Vec<String>
+  // Contexts the exception has left, innermost first
+  pub stack: Vec<Symbol>,
+  // The exception this one was raised in response to, if any
+  pub cause: Option<Box<Exception>>
 }
 
 pub enum ExceptionType {
   Return, Error, ArityError, ParseError, TypeError, TypeMismatch, DivByZero,
-  RuntimeError, UndefError, RedefError
+  RuntimeError, UndefError, RedefError, StackOverflow, PermissionError,
+  IOError,
+  // Resource limits and exit(), these terminate the program and can't be
+  // caught
+  OutOfFuel, OutOfMemory, Timeout, Exit,
+  // User-defined exception types, created with raise(type, payload)
+  User(String)
 }
@@ -3,7 +3,10 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt::Error;
 
+use symbols;
+
 use encoding::Token;
+use encoding::StringPart;
 
 use encoding::Expression;
 
@@ -33,6 +36,17 @@ impl Debug for Token {
       &Token::Integer(ref x) => "INTEGER:".to_string() + &x.to_string(),
       &Token::Float(ref x) => "FLOAT:".to_string() + &x.to_string(),
       &Token::String(ref x) => "STRING:".to_string() + &x,
+      &Token::Interpolated(ref x) => {
+        let mut s2 = "INTERPOLATED:[ ".to_string();
+        for p in x {
+          match p {
+            &StringPart::Text(ref t) => s2 += &format!("STRING:{} ", t),
+            &StringPart::Code(ref c) => s2 += &format!("CODE:{:?} ", c),
+          }
+        }
+        s2 += "]";
+        s2
+      },
       &Token::EOF => "EOF".to_string(),
     };
     write!(f, "{}", s)
@@ -57,10 +71,10 @@ impl Debug for Expression {
         s2
       },
       &Expression::Call(ref x) => {
-        let mut s2 = "CALL:".to_string() + &x.id;
+        let mut s2 = "CALL:".to_string() + &symbols::name(x.id);
         if x.params.len() > 0 {
           s2 += "( ";
-          for i in &x.params {
+          for i in x.params.iter() {
             s2 += &format!("{:?} ", i);
           }
           s2 += ")";
@@ -68,11 +82,11 @@ impl Debug for Expression {
         s2
       },
       &Expression::Definition(ref x) => {
-        let mut s2 = "DEFINITION:".to_string() + &x.id;
+        let mut s2 = "DEFINITION:".to_string() + &symbols::name(x.id);
         if x.params.len() > 0 {
           s2 += "( ";
-          for i in &x.params {
-            s2 += &i;
+          for i in x.params.iter() {
+            s2 += &symbols::name(*i);
             s2 += &" ".to_string();
           }
           s2 += "):";
@@ -90,8 +104,8 @@ impl Debug for Expression {
 impl Debug for Scope {
   fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
     let mut s = "SCOPE:".to_string();
-    for (id, f) in &self.bindings {
-      s += &format!(" {}:{:?}", id, f);
+    for symbol in &self.symbols {
+      s += &format!(" {}", symbols::name(*symbol));
     }
     write!(f, "{}", s)
   }
@@ -122,7 +136,7 @@ impl Debug for Evaluation {
       &Evaluation::String(ref x) => "STRING:".to_string() + &x,
       &Evaluation::List(ref x) => {
         let mut s2 = "LIST:[ ".to_string();
-        for i in &x.items {
+        for i in x.iter() {
           s2 += &format!("{:?} ", i);
         }
         s2 += "]";
@@ -133,7 +147,7 @@ impl Debug for Evaluation {
         s2 += &format!("{}, ", x.payload);
         let mut stack = Vec::new();
         for i in &x.stack {
-          stack.push(i.clone());
+          stack.push(symbols::name(*i).to_string());
         }
         s2 += &stack.join(", ");
         s2 += "]]";
@@ -143,8 +157,8 @@ impl Debug for Evaluation {
         let mut s2 = "FUNCTION:".to_string();
         if x.params.len() > 0 {
           s2 += "( ";
-          for i in &x.params {
-            s2 += &i;
+          for i in x.params.iter() {
+            s2 += &symbols::name(*i);
             s2 += &" ".to_string();
           }
           s2 += "):";
@@ -162,8 +176,8 @@ impl Debug for Evaluation {
 impl Debug for Function {
   fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
     let mut s = "( ".to_string();
-    for p in &self.params {
-      s += &p;
+    for p in self.params.iter() {
+      s += &symbols::name(*p);
       s += " ";
     }
     s += "):";
@@ -188,7 +202,7 @@ impl Display for Evaluation {
       &Evaluation::List(ref x) => {
         let mut s2 = "[".to_string();
         let mut items = Vec::new();
-        for i in &x.items {
+        for i in x.iter() {
           items.push(format!("{}", i));
         }
         s2 += &items.join(", ");
@@ -200,7 +214,7 @@ impl Display for Evaluation {
         s2 += &format!("{}, ", x.payload);
         let mut stack = Vec::new();
         for i in &x.stack {
-          stack.push(i.clone());
+          stack.push(symbols::name(*i).to_string());
         }
         s2 += &stack.join(", ");
         s2 += "]]";
@@ -211,8 +225,8 @@ impl Display for Evaluation {
         if x.params.len() > 0 {
           s2 += "(";
           let mut params = Vec::new();
-          for p in &x.params {
-            params.push(p.clone());
+          for p in x.params.iter() {
+            params.push(symbols::name(*p).to_string());
           }
           s2 += &params.join(", ");
           s2 += ")";
@@ -229,10 +243,30 @@ impl Display for Exception {
   fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
     let mut s = format!("\nRUNTIME EXCEPTION: {}\n{}:\n\n  calling context:\n",
                         self.flavor.to_string().to_uppercase(), self.payload);
+    // Runs of the same function (i.e., recursion) are summarized as one line
     let mut n = self.stack.len();
-    for i in &self.stack {
-      s += &format!("   -- called from function {}: {}\n", n - 1, i);
-      n -= 1;
+    let mut x = 0;
+    while x < self.stack.len() {
+      let mut repeats = 1;
+      while x + repeats < self.stack.len() &&
+        self.stack[x + repeats] == self.stack[x] {
+        repeats += 1;
+      }
+      if repeats > 2 {
+        s += &format!("   -- called from functions {}-{}: {} (repeated {} times)\n",
+                      n - 1, n - repeats, symbols::name(self.stack[x]),
+                      repeats);
+      } else {
+        for i in 0..repeats {
+          s += &format!("   -- called from function {}: {}\n", n - 1 - i,
+                        symbols::name(self.stack[x]));
+        }
+      }
+      n -= repeats;
+      x += repeats;
+    }
+    if let Some(ref cause) = self.cause {
+      s += &format!("\n  caused by:{}", cause);
     }
     write!(f, "{}", s)
   }
@@ -251,6 +285,14 @@ impl Display for ExceptionType {
       &ExceptionType::RuntimeError => "runtime error".to_string(),
       &ExceptionType::UndefError => "undefined function".to_string(),
       &ExceptionType::RedefError => "redefinition error".to_string(),
+      &ExceptionType::StackOverflow => "stack overflow".to_string(),
+      &ExceptionType::PermissionError => "permission error".to_string(),
+      &ExceptionType::IOError => "io error".to_string(),
+      &ExceptionType::OutOfFuel => "out of fuel".to_string(),
+      &ExceptionType::OutOfMemory => "out of memory".to_string(),
+      &ExceptionType::Timeout => "timeout".to_string(),
+      &ExceptionType::Exit => "exit".to_string(),
+      &ExceptionType::User(ref s) => s.clone(),
     };
     write!(f, "{}", s)
   }
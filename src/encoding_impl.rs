@@ -1,15 +1,24 @@
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
 
 use evaluator;
-use primitives;
+use optimizer;
+use symbols;
+use vm;
 
 use encoding::Block;
 use encoding::Expression;
 use encoding::List;
 use encoding::Call;
 use encoding::Definition;
+use encoding::Symbol;
 
 use encoding::Scope;
+use encoding::Binding;
+use encoding::Interpreter;
+use encoding::Capabilities;
 use encoding::FunctionOrValue;
 use encoding::Evaluation;
 use encoding::ListEval;
@@ -18,14 +27,6 @@ use encoding::Exception;
 use encoding::ExceptionType;
 
 impl List {
-  pub fn evaluate(&self, scope: &mut Vec<Scope>) -> Evaluation {
-    let mut list = ListEval { items: Vec::new() };
-    for i in &self.items {
-      list.items.push(i.evaluate(scope));
-    }
-    Evaluation::List(list)
-  }
-
   pub fn clone(&self) -> List {
     let mut list = List { items: Vec::new() };
     for i in &self.items {
@@ -36,101 +37,8 @@ impl List {
 }
 
 impl Call {
-  pub fn evaluate(&self, scope: &mut Vec<Scope>) -> Evaluation {
-    let mut check = false;
-    let rc;
-
-    // Debug here:
-    //println!("CALLING {} IN", &self.id);
-    //for n in 0..scope.len() {
-    //  println!("  {:?}", scope[n]);
-    //}
-
-    // Search through scopes in reverse order for function (or value)
-    let mut binding = FunctionOrValue::Value(Evaluation::Nil);
-    for x in (0..scope.len()).rev() {
-      if scope[x].bindings.contains_key(&self.id) {
-        check = true;
-        binding = scope[x].bindings[&self.id].clone();
-        break;
-      }
-    }
-    if check {
-      match binding {
-        FunctionOrValue::Function(ref func) => {
-          // Add a scope for the function parameters, evaluate, and populate
-          let mut p_scope = Scope { bindings: HashMap::new() };
-          if self.params.len() != func.params.len() {
-            return evaluator::exception(ExceptionType::ArityError, &self.id,
-                                        format!("expected {} arguments but got {}",
-                                                self.params.len(),
-                                                func.params.len()));
-          }
-          for y in 0..self.params.len() {
-            let mut block = Block { expressions: Vec::new() };
-            block.expressions.push(self.params[y].clone());
-            let eval = block.evaluate(scope, &self.id);
-            p_scope.bindings.insert(func.params[y].clone(),
-                                    FunctionOrValue::Value(eval));
-          }
-          scope.push(p_scope);
-          rc = func.block.evaluate(scope, &self.id);
-          scope.pop();
-        },
-        FunctionOrValue::Value(ref value) => {
-          // This value has already been evaluated, i.e., it's a passed param
-          rc = value.clone();
-        },
-      }
-    } else if self.id == "$" {
-      if self.params.len() < 1 {
-        rc = evaluator::exception(ExceptionType::ArityError, &self.id,
-                                  "expected at least 1 argument but got 0".to_string());
-      } else {
-        let eval = self.params[0].evaluate(scope);
-        match eval {
-          Evaluation::Exception(_) => { return eval.clone(); },
-          Evaluation::Function(ref func) => {
-            // TODO: make helper function
-            // This is (almost) repeated for user functions (minus first param)
-            let mut p_scope = Scope { bindings: HashMap::new() };
-            if self.params.len() - 1 != func.params.len() {
-              return evaluator::exception(ExceptionType::ArityError, &self.id,
-                                          format!("called function expected {} arguments but got {}",
-                                                  self.params.len() - 1,
-                                                  func.params.len()));
-            }
-            for y in 1..self.params.len() {
-              let mut block = Block { expressions: Vec::new() };
-              block.expressions.push(self.params[y].clone());
-              let eval = block.evaluate(scope, &self.id);
-              p_scope.bindings.insert(func.params[y-1].clone(),
-                                      FunctionOrValue::Value(eval));
-            }
-            scope.push(p_scope);
-            rc = func.block.evaluate(scope, &self.id);
-            scope.pop();
-          },
-          _ => {
-            rc = evaluator::exception(ExceptionType::TypeError, &self.id,
-                                      "function expected as first argument".to_string());
-          },
-        }
-      }
-    } else {
-      // Try low-level system functions
-      let mut params = Vec::new();
-      for p in &self.params {
-        let eval = p.evaluate(scope);
-        params.push(eval);
-      }
-      rc = primitives::system_functions(self.id.clone(), params);
-    }
-    rc
-  }
-
   pub fn clone(&self) -> Call {
-    let mut call = Call { id: self.id.clone(), params: Vec::new() };
+    let mut call = Call { id: self.id, params: Vec::new() };
     for p in &self.params {
       call.params.push(p.clone());
     }
@@ -139,49 +47,33 @@ impl Call {
 }
 
 impl Definition {
-  pub fn evaluate(&self, scope: &mut Vec<Scope>) -> Evaluation {
-    let last = scope.last_mut();
-    if let Some(s) = last {
-      if s.bindings.contains_key(&self.id) {
+  pub fn evaluate(&self, interp: &mut Interpreter) -> Evaluation {
+    if !interp.scope.is_empty() {
+      if interp.bound_here(self.id) {
         return evaluator::exception(ExceptionType::RedefError, &"".to_string(),
                                     format!("attempt to redefine {}",
-                                            self.id));
-      }
-      let mut func = Function { params: Vec::new(), block: self.block.clone() };
-      for p in &self.params {
-        func.params.push(p.clone());
+                                            symbols::name(self.id)));
       }
-      s.bindings.insert(self.id.clone(),
-                         FunctionOrValue::Function(func.clone()));
+      let func = Function { params: self.params.clone(),
+                            block: self.block.clone(), memo: None };
+      interp.bind(self.id, FunctionOrValue::Function(func.clone()));
       Evaluation::Function(func)
     } else {
-      panic!("internal error: no scope supplied to definition evaluation");
+      evaluator::exception(ExceptionType::RuntimeError, &symbols::name(self.id),
+                           "internal error: no scope supplied to definition evaluation".to_string())
     }
   }
 
   pub fn clone(&self) -> Definition {
-    let mut def = Definition { id: self.id.clone(), params: Vec::new(),
-                               block: self.block.clone() };
-    for p in &self.params {
-      def.params.push(p.clone());
-    }
-    def
+    Definition { id: self.id, params: self.params.clone(),
+                 block: self.block.clone() }
   }
 }
 
 impl Expression {
-  pub fn evaluate(&self, scope: &mut Vec<Scope>) -> Evaluation {
-    match self {
-      &Expression::Nil => Evaluation::Nil,
-      &Expression::True => Evaluation::True,
-      &Expression::False => Evaluation::False,
-      &Expression::Integer(x) => Evaluation::Integer(x),
-      &Expression::Float(x) => Evaluation::Float(x),
-      &Expression::String(ref s) => Evaluation::String(s.clone()),
-      &Expression::List(ref list) => list.evaluate(scope),
-      &Expression::Call(ref call) => call.evaluate(scope),
-      &Expression::Definition(ref def) => def.evaluate(scope),
-    }
+  // The value of the expression in the current scope (see evaluator.rs)
+  pub fn evaluate(&self, interp: &mut Interpreter) -> Evaluation {
+    evaluator::evaluate_expression(self, interp)
   }
 
   pub fn clone(&self) -> Expression {
@@ -200,37 +92,6 @@ impl Expression {
 }
 
 impl Block {
-  pub fn evaluate(&self, scope: &mut Vec<Scope>, context: &String) ->
-    Evaluation {
-
-    // Add current context
-    let current = Scope { bindings: HashMap::new() };
-    scope.push(current);
-
-    // Evaluate
-    let mut value = Evaluation::Nil;
-    for e in &self.expressions {
-      match e.evaluate(scope) {
-        Evaluation::Exception(ref ex) => {
-          match &ex.flavor {
-            &ExceptionType::Return => { return ex.payload.clone(); },
-            _ => {
-              let mut rc = ex.clone();
-              rc.stack.push(context.clone());
-              return Evaluation::Exception(rc);
-            },
-          }
-        },
-        ev => { value = ev },
-      }
-      // Debug output:
-      //println!("{:?}", value);
-    }
-    // Current context going out of scope
-    scope.pop();
-    value
-  }
-
   pub fn clone(&self) -> Block {
     let mut rc = Block { expressions: Vec::new() };
     for i in &self.expressions {
@@ -240,6 +101,204 @@ impl Block {
   }
 }
 
+impl Interpreter {
+  pub fn new() -> Interpreter {
+    Interpreter {
+      scope: Vec::new(),
+      bindings: Vec::new(),
+      depth: 0,
+      max_depth: evaluator::DEFAULT_MAX_DEPTH,
+      callbacks: 0,
+      max_callbacks: evaluator::DEFAULT_MAX_CALLBACKS,
+      capabilities: Capabilities::all(),
+      args: Vec::new(),
+      fuel: None,
+      max_size: None,
+      timeout: None,
+      terminated: None,
+      remaining: 0,
+      deadline: None,
+      bytecode: false,
+      compiled: HashMap::new(),
+      optimize: false
+    }
+  }
+
+  // Start a new innermost scope
+  pub fn push_scope(&mut self) {
+    self.scope.push(Scope { symbols: Vec::new() });
+  }
+
+  // End the innermost scope, along with everything bound in it
+  pub fn pop_scope(&mut self) {
+    if let Some(s) = self.scope.pop() {
+      for symbol in s.symbols {
+        self.bindings[symbol].pop();
+      }
+    }
+  }
+
+  // Bind in the innermost scope, which there has to be
+  pub fn bind(&mut self, symbol: Symbol, value: FunctionOrValue) {
+    if symbol >= self.bindings.len() {
+      self.bindings.resize_with(symbol + 1, Vec::new);
+    }
+    let level = self.scope.len() - 1;
+    self.bindings[symbol].push(Binding { level: level, value: value });
+    self.scope[level].symbols.push(symbol);
+  }
+
+  // Innermost binding for a symbol
+  pub fn lookup(&self, symbol: Symbol) -> Option<&FunctionOrValue> {
+    match self.bindings.get(symbol).and_then(|b| b.last()) {
+      Some(b) => Some(&b.value),
+      None => None,
+    }
+  }
+
+  // Is the symbol bound in the innermost scope?
+  pub fn bound_here(&self, symbol: Symbol) -> bool {
+    match self.bindings.get(symbol).and_then(|b| b.last()) {
+      Some(b) => b.level + 1 == self.scope.len(),
+      None => false,
+    }
+  }
+
+  // Evaluate a whole program in a fresh top-level scope
+  pub fn run(&mut self, block: &Block) -> Evaluation {
+    self.scope.clear();
+    self.bindings.clear();
+    // Code for the last program's functions, which this one won't be calling
+    self.compiled.clear();
+    self.depth = 0;
+    self.callbacks = 0;
+    self.terminated = None;
+    self.remaining = self.fuel.unwrap_or(0);
+    self.deadline = self.timeout.map(|t| Instant::now() + t);
+    // Global scope, under the program's own, for values from the host
+    let mut args = ListEval::new();
+    for a in &self.args {
+      args.push(Evaluation::String(Rc::from(a.as_str())));
+    }
+    self.push_scope();
+    self.bind(symbols::ARGS, FunctionOrValue::Value(Evaluation::List(args)));
+    let optimized;
+    let block = if self.optimize {
+      optimized = optimizer::optimize(block, self);
+      &optimized
+    } else {
+      block
+    };
+    let context = symbols::intern("[main program]");
+    let rc = if self.bytecode {
+      vm::run(block, self, context)
+    } else {
+      evaluator::evaluate_block(&Rc::new(block.clone()), self, context)
+    };
+    self.pop_scope();
+    // The program may have discarded the termination (say, in the unused
+    // branch of a ?) on its way out, but it still counts
+    let discarded = match rc {
+      Evaluation::Exception(ref e) => !e.flavor.is_fatal(),
+      _ => true,
+    };
+    if self.terminated.is_some() && discarded {
+      self.termination()
+    } else {
+      rc
+    }
+  }
+
+  // Count one evaluation step against the limits; once any limit is hit
+  // every further step fails, so the termination can't be caught or ignored
+  pub fn step(&mut self) -> Option<Evaluation> {
+    if self.terminated.is_some() {
+      return Some(self.termination());
+    }
+    if self.fuel.is_some() {
+      if self.remaining == 0 {
+        self.terminated = Some(ExceptionType::OutOfFuel);
+        return Some(self.termination());
+      }
+      self.remaining -= 1;
+    }
+    self.check_deadline()
+  }
+
+  // Check the time limit (and any limit already hit) without counting a
+  // step, for primitives that can run a long time by themselves
+  pub fn check_deadline(&mut self) -> Option<Evaluation> {
+    if self.terminated.is_some() {
+      return Some(self.termination());
+    }
+    if let Some(deadline) = self.deadline {
+      if Instant::now() >= deadline {
+        self.terminated = Some(ExceptionType::Timeout);
+        return Some(self.termination());
+      }
+    }
+    None
+  }
+
+  // Enforce the size limit on a freshly evaluated value
+  pub fn check_size(&mut self, eval: Evaluation) -> Evaluation {
+    let size = match eval {
+      Evaluation::String(ref s) => s.len(),
+      Evaluation::List(ref list) => list.len(),
+      _ => 0,
+    };
+    match self.reserve(size) {
+      Some(e) => e,
+      None => eval,
+    }
+  }
+
+  // Check the size limit before building a value, for primitives that could
+  // otherwise allocate far more than the limit before it gets checked
+  pub fn reserve(&mut self, size: usize) -> Option<Evaluation> {
+    if let Some(max) = self.max_size {
+      if size > max {
+        self.terminated = Some(ExceptionType::OutOfMemory);
+        return Some(self.termination());
+      }
+    }
+    None
+  }
+
+  // The exception for the limit that was hit
+  pub fn termination(&self) -> Evaluation {
+    let (flavor, msg) = match self.terminated {
+      Some(ExceptionType::OutOfFuel) => {
+        (ExceptionType::OutOfFuel,
+         format!("evaluation step limit of {} exceeded",
+                 self.fuel.unwrap_or(0)))
+      },
+      Some(ExceptionType::OutOfMemory) => {
+        (ExceptionType::OutOfMemory,
+         format!("size limit of {} exceeded", self.max_size.unwrap_or(0)))
+      },
+      _ => {
+        (ExceptionType::Timeout,
+         format!("time limit of {:?} exceeded",
+                 self.timeout.unwrap_or(Duration::from_secs(0))))
+      },
+    };
+    evaluator::exception(flavor, &"[interpreter]".to_string(), msg)
+  }
+}
+
+impl Capabilities {
+  pub fn all() -> Capabilities {
+    Capabilities { output: true, files: true, environment: true,
+                   process: true }
+  }
+
+  pub fn none() -> Capabilities {
+    Capabilities { output: false, files: false, environment: false,
+                   process: false }
+  }
+}
+
 impl FunctionOrValue {
   pub fn clone(&self) -> FunctionOrValue {
     match self {
@@ -269,23 +328,32 @@ impl Evaluation {
   }
 }
 
-impl ListEval {
-  pub fn clone(&self) -> ListEval {
-    let mut list = ListEval { items: Vec::new() };
-    for i in &self.items {
-      list.items.push(i.clone());
+impl Function {
+  // Call with arguments that have already been evaluated, which is how
+  // primitives like sort_by call back into user functions.  The function
+  // itself is also bound as "self" (unless a parameter hides it) so that
+  // anonymous functions can recurse with $(self, ...)
+  pub fn apply(&self, args: Vec<Evaluation>, interp: &mut Interpreter,
+               context: Symbol) -> Evaluation {
+    if interp.callbacks >= interp.max_callbacks {
+      return evaluator::exception(ExceptionType::StackOverflow,
+                                  &symbols::name(context),
+                                  format!("maximum depth of {} nested callbacks exceeded",
+                                          interp.max_callbacks));
     }
-    list
+    interp.callbacks += 1;
+    let rc = if interp.bytecode {
+      vm::apply(self, args, interp, context)
+    } else {
+      evaluator::apply(self, args, interp, context)
+    };
+    interp.callbacks -= 1;
+    rc
   }
-}
 
-impl Function {
   pub fn clone(&self) -> Function {
-    let mut func = Function { params: Vec::new(), block: self.block.clone() };
-    for p in &self.params {
-      func.params.push(p.clone());
-    }
-    func
+    Function { params: self.params.clone(), block: self.block.clone(),
+               memo: self.memo.clone() }
   }
 }
 
@@ -294,20 +362,65 @@ impl Exception {
     Exception {
       flavor: flavor.clone(),
       payload: Box::new(payload.clone()),
-      stack: Vec::new()
+      stack: Vec::new(),
+      cause: None
     }
   }
 
   pub fn clone(&self) -> Exception {
     let mut e = Exception::new(&self.flavor, &*self.payload);
-    for i in &self.stack {
-      e.stack.push(i.clone());
+    e.stack = self.stack.clone();
+    if let Some(ref cause) = self.cause {
+      e.cause = Some(Box::new((**cause).clone()));
     }
     e
   }
 }
 
 impl ExceptionType {
+  // Exceptions that end the program pass through catch untouched
+  pub fn is_fatal(&self) -> bool {
+    match self {
+      &ExceptionType::OutOfFuel | &ExceptionType::OutOfMemory |
+      &ExceptionType::Timeout | &ExceptionType::Exit => true,
+      _ => false,
+    }
+  }
+
+  // Inverse of Display, anything unrecognized is a user-defined type
+  pub fn from_name(name: &str) -> ExceptionType {
+    match name {
+      "return" => ExceptionType::Return,
+      "error" => ExceptionType::Error,
+      "arity error" => ExceptionType::ArityError,
+      "parse error" => ExceptionType::ParseError,
+      "type error" => ExceptionType::TypeError,
+      "type mismatch" => ExceptionType::TypeMismatch,
+      "division by zero" => ExceptionType::DivByZero,
+      "runtime error" => ExceptionType::RuntimeError,
+      "undefined function" => ExceptionType::UndefError,
+      "redefinition error" => ExceptionType::RedefError,
+      "stack overflow" => ExceptionType::StackOverflow,
+      "permission error" => ExceptionType::PermissionError,
+      "io error" => ExceptionType::IOError,
+      "out of fuel" => ExceptionType::OutOfFuel,
+      "out of memory" => ExceptionType::OutOfMemory,
+      "timeout" => ExceptionType::Timeout,
+      "exit" => ExceptionType::Exit,
+      _ => ExceptionType::User(name.to_string()),
+    }
+  }
+
+  // Names a user-defined type can't have, so a type string from catch always
+  // says where the exception came from ("ok" being what catch gives for a
+  // value that isn't one)
+  pub fn is_reserved(name: &str) -> bool {
+    match ExceptionType::from_name(name) {
+      ExceptionType::User(_) => name == "ok",
+      _ => true,
+    }
+  }
+
   pub fn clone(&self) -> ExceptionType {
     match self {
       &ExceptionType::Return => ExceptionType::Return,
@@ -320,6 +433,14 @@ impl ExceptionType {
       &ExceptionType::RuntimeError => ExceptionType::RuntimeError,
       &ExceptionType::UndefError => ExceptionType::UndefError,
       &ExceptionType::RedefError => ExceptionType::RedefError,
+      &ExceptionType::StackOverflow => ExceptionType::StackOverflow,
+      &ExceptionType::OutOfFuel => ExceptionType::OutOfFuel,
+      &ExceptionType::OutOfMemory => ExceptionType::OutOfMemory,
+      &ExceptionType::Timeout => ExceptionType::Timeout,
+      &ExceptionType::Exit => ExceptionType::Exit,
+      &ExceptionType::PermissionError => ExceptionType::PermissionError,
+      &ExceptionType::IOError => ExceptionType::IOError,
+      &ExceptionType::User(ref s) => ExceptionType::User(s.clone()),
     }
   }
 }
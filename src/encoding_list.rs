@@ -0,0 +1,213 @@
+// Persistent lists: AVL trees in list order where every operation builds new
+// nodes along one path and shares the rest with the original, so car, cdr,
+// indexing, adding at either end and joining lists are all O(log n), and
+// copying a list is just another reference to the same root
+
+use std::ops::Index;
+use std::rc::Rc;
+
+use encoding::Evaluation;
+use encoding::ListEval;
+use encoding::ListNode;
+
+type Tree = Option<Rc<ListNode>>;
+
+fn size(tree: &Tree) -> usize {
+  match tree {
+    &Some(ref n) => n.size,
+    &None => 0,
+  }
+}
+
+fn height(tree: &Tree) -> usize {
+  match tree {
+    &Some(ref n) => n.height,
+    &None => 0,
+  }
+}
+
+fn node(left: Tree, item: Evaluation, right: Tree) -> Tree {
+  let s = size(&left) + size(&right) + 1;
+  let h = height(&left).max(height(&right)) + 1;
+  Some(Rc::new(ListNode { left: left, item: item, right: right, size: s,
+                          height: h }))
+}
+
+// Like node, but fixes up sides whose heights differ by two with a rotation
+fn balance(left: Tree, item: Evaluation, right: Tree) -> Tree {
+  let hl = height(&left);
+  let hr = height(&right);
+  if hl > hr + 1 {
+    let l = left.unwrap();
+    if height(&l.left) >= height(&l.right) {
+      node(l.left.clone(), l.item.clone(), node(l.right.clone(), item, right))
+    } else {
+      let lr = l.right.as_ref().unwrap();
+      node(node(l.left.clone(), l.item.clone(), lr.left.clone()),
+           lr.item.clone(), node(lr.right.clone(), item, right))
+    }
+  } else if hr > hl + 1 {
+    let r = right.unwrap();
+    if height(&r.right) >= height(&r.left) {
+      node(node(left, item, r.left.clone()), r.item.clone(), r.right.clone())
+    } else {
+      let rl = r.left.as_ref().unwrap();
+      node(node(left, item, rl.left.clone()), rl.item.clone(),
+           node(rl.right.clone(), r.item.clone(), r.right.clone()))
+    }
+  } else {
+    node(left, item, right)
+  }
+}
+
+// Everything in left, then item, then everything in right, whatever their
+// heights
+fn join(left: Tree, item: Evaluation, right: Tree) -> Tree {
+  let hl = height(&left);
+  let hr = height(&right);
+  if hl > hr + 1 {
+    let l = left.unwrap();
+    balance(l.left.clone(), l.item.clone(), join(l.right.clone(), item, right))
+  } else if hr > hl + 1 {
+    let r = right.unwrap();
+    balance(join(left, item, r.left.clone()), r.item.clone(), r.right.clone())
+  } else {
+    node(left, item, right)
+  }
+}
+
+fn split_first(n: &ListNode) -> (Evaluation, Tree) {
+  match n.left {
+    Some(ref l) => {
+      let (first, rest) = split_first(l);
+      (first, join(rest, n.item.clone(), n.right.clone()))
+    },
+    None => (n.item.clone(), n.right.clone()),
+  }
+}
+
+// Balanced tree from the next count items, in order
+fn build<I: Iterator<Item = Evaluation>>(items: &mut I, count: usize) -> Tree {
+  if count == 0 {
+    return None;
+  }
+  let left = build(items, count / 2);
+  let item = items.next().unwrap_or(Evaluation::Nil);
+  let right = build(items, count - count / 2 - 1);
+  node(left, item, right)
+}
+
+pub struct ListIter<'a> {
+  stack: Vec<&'a ListNode>
+}
+
+impl<'a> ListIter<'a> {
+  fn push_left(&mut self, mut tree: &'a Tree) {
+    while let Some(ref n) = *tree {
+      self.stack.push(n);
+      tree = &n.left;
+    }
+  }
+}
+
+impl<'a> Iterator for ListIter<'a> {
+  type Item = &'a Evaluation;
+
+  fn next(&mut self) -> Option<&'a Evaluation> {
+    match self.stack.pop() {
+      Some(n) => {
+        self.push_left(&n.right);
+        Some(&n.item)
+      },
+      None => None,
+    }
+  }
+}
+
+impl ListEval {
+  pub fn new() -> ListEval {
+    ListEval { root: None }
+  }
+
+  pub fn from_items(items: Vec<Evaluation>) -> ListEval {
+    let count = items.len();
+    ListEval { root: build(&mut items.into_iter(), count) }
+  }
+
+  pub fn len(&self) -> usize {
+    size(&self.root)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.root.is_none()
+  }
+
+  pub fn get(&self, index: usize) -> Option<&Evaluation> {
+    let mut tree = &self.root;
+    let mut index = index;
+    while let Some(ref n) = *tree {
+      let left = size(&n.left);
+      if index < left {
+        tree = &n.left;
+      } else if index == left {
+        return Some(&n.item);
+      } else {
+        index -= left + 1;
+        tree = &n.right;
+      }
+    }
+    None
+  }
+
+  pub fn first(&self) -> Option<&Evaluation> {
+    self.get(0)
+  }
+
+  // Everything but the first item
+  pub fn rest(&self) -> ListEval {
+    match self.root {
+      Some(ref n) => ListEval { root: split_first(n).1 },
+      None => ListEval::new(),
+    }
+  }
+
+  pub fn push(&mut self, item: Evaluation) {
+    self.root = join(self.root.take(), item, None);
+  }
+
+  pub fn push_front(&mut self, item: Evaluation) {
+    self.root = join(None, item, self.root.take());
+  }
+
+  // This list followed by other
+  pub fn concat(&self, other: &ListEval) -> ListEval {
+    match other.root {
+      Some(ref n) => {
+        let (first, rest) = split_first(n);
+        ListEval { root: join(self.root.clone(), first, rest) }
+      },
+      None => self.clone(),
+    }
+  }
+
+  pub fn iter<'a>(&'a self) -> ListIter<'a> {
+    let mut iter = ListIter { stack: Vec::new() };
+    iter.push_left(&self.root);
+    iter
+  }
+
+  pub fn clone(&self) -> ListEval {
+    ListEval { root: self.root.clone() }
+  }
+}
+
+impl Index<usize> for ListEval {
+  type Output = Evaluation;
+
+  fn index(&self, index: usize) -> &Evaluation {
+    match self.get(index) {
+      Some(item) => item,
+      None => panic!("list index {} out of range", index),
+    }
+  }
+}
@@ -1,27 +1,437 @@
-// Evaluate parsed stuff
+// Evaluate parsed stuff.  Walking the syntax tree is done by a machine that
+// keeps everything it's in the middle of on stacks of its own, one frame for
+// each list, call or block being evaluated, rather than by recursion, so how
+// deep a program can go is only up to the depth limit (and memory), not the
+// size of the Rust stack.  The only Rust recursion left is primitives like
+// sort_by calling back into functions, which run on a machine of their own.
+
+use std::rc::Rc;
+
+use memo;
+use primitives;
+use symbols;
 
 use encoding::Block;
+use encoding::Expression;
+use encoding::List;
+use encoding::Call;
+use encoding::Symbol;
+use encoding::FunctionOrValue;
 use encoding::Evaluation;
+use encoding::ListEval;
+use encoding::Function;
 use encoding::Exception;
 use encoding::ExceptionType;
+use encoding::Interpreter;
 
-// Look how simple this is!  ...Because we hid all of the logic in the types
+use memo::Memo;
+use memo::Key;
 
 pub fn exception(flavor: ExceptionType, id: &String, msg: String) ->
   Evaluation {
   Evaluation::Exception(Exception::new(&flavor,
-                        &Evaluation::String(format!("{} : {}", id, msg))))
+                        &Evaluation::String(Rc::from(format!("{} : {}", id, msg)))))
+}
+
+// Process exit codes for programs that fail, other than through exit()
+pub const EXIT_RUNTIME_ERROR: i32 = 1;
+pub const EXIT_SYNTAX_ERROR: i32 = 2;
+
+// Default limit on nested function calls before raising a stack overflow
+// exception
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+// Default limit on primitives calling back into functions, low enough for
+// the 2 MiB stack a spawned thread gets, even in an unoptimized build
+pub const DEFAULT_MAX_CALLBACKS: usize = 50;
+
+// Rough upper bound on Rust stack used per nested call made by a primitive
+// calling back into a function (say, sort_by whose comparison sorts), this is
+// mostly determined by unoptimized builds
+const BYTES_PER_CALL: usize = 32 * 1024;
+
+// Thread stack size needed to nest primitive callbacks to a given depth, for
+// embedders that raise Interpreter.max_callbacks above the default
+pub fn stack_size(max_callbacks: usize) -> usize {
+  (max_callbacks + 64) * BYTES_PER_CALL
 }
 
 pub fn evaluate(block: &Block) {
-  let mut scope = Vec::new();
-  let result = block.evaluate(&mut scope, &"[main program]".to_string());
+  evaluate_with(block, &mut Interpreter::new());
+}
+
+// Does the program define a main(args) entry point at the top level?
+pub fn has_main(block: &Block) -> bool {
+  let main = symbols::intern("main");
+  for e in &block.expressions {
+    if let &Expression::Definition(ref def) = e {
+      if def.id == main && def.params.len() == 1 {
+        return true;
+      }
+    }
+  }
+  false
+}
+
+// Run a program, returning the exit code for the process: if the program
+// defines main(args), it gets called after everything else and an integer
+// return value becomes the exit code (as long as it's one; exit() only takes
+// 0 to 255 too).  Otherwise it's whatever was passed to exit(), or an error
+// code for an uncaught exception
+pub fn evaluate_with(block: &Block, interp: &mut Interpreter) -> i32 {
+  let result = if has_main(block) {
+    let mut program = block.clone();
+    let args = Call { id: symbols::ARGS, params: Vec::new() };
+    let call = Call { id: symbols::intern("main"),
+                      params: vec![Expression::Call(Rc::new(args))] };
+    program.expressions.push(Expression::Call(Rc::new(call)));
+    interp.run(&program)
+  } else {
+    interp.run(block)
+  };
+  let result = match result {
+    Evaluation::Integer(n) if has_main(block) && !(0..=255).contains(&n) => {
+      exception(ExceptionType::RuntimeError, &"main".to_string(),
+                format!("exit code {} is not between 0 and 255", n))
+    },
+    _ => result,
+  };
   match &result {
     &Evaluation::Exception(ref e) => {
-      println!("{}", e);
+      match (&e.flavor, &*e.payload) {
+        (&ExceptionType::Exit, &Evaluation::Integer(n)) => n as i32,
+        _ => {
+          println!("{}", e);
+          EXIT_RUNTIME_ERROR
+        },
+      }
     },
-    _ => {
-      // not an error, do nothing
+    &Evaluation::Integer(n) if has_main(block) => n as i32,
+    _ => 0,
+  }
+}
+
+// An exception leaving a block picks up the block's context on its way out,
+// unless it's a ~, which the block returns the payload of
+pub fn leave_block(value: Evaluation, context: Symbol) -> Evaluation {
+  match value {
+    Evaluation::Exception(mut ex) => {
+      match ex.flavor {
+        ExceptionType::Return => *ex.payload,
+        _ => {
+          ex.stack.push(context);
+          Evaluation::Exception(ex)
+        },
+      }
     },
+    _ => value,
+  }
+}
+
+// What happens to the value of a block once it's done
+enum End {
+  // Nothing, it's the value of the whole run
+  Block,
+  // It's a function body, whose call is over
+  Apply,
+  // It's a function body called from an expression, whose value also gets
+  // checked against the size limit
+  Call
+}
+
+// Something being evaluated, with the values evaluated for it so far on the
+// value stack from base up
+enum Frame {
+  List { list: Rc<List>, base: usize },
+  // Arguments to a primitive, evaluated plainly
+  Primitive { call: Rc<Call>, base: usize },
+  // The first argument to $, which is the function to call
+  Callee { call: Rc<Call> },
+  // Arguments to a function, each evaluated like a block of its own, in a
+  // scope that's open while it's being evaluated.  The arguments to $ skip
+  // the function
+  Arguments { call: Rc<Call>, func: Function, skip: usize, base: usize,
+              open: bool },
+  // Statements of a block, next being the one to evaluate after the value
+  // on top, if any
+  Block { block: Rc<Block>, next: usize, context: Symbol, end: End },
+  // A call to a memoized function, whose value on top goes in the cache
+  Memo { memo: Rc<Memo>, key: Vec<Key> }
+}
+
+struct Machine {
+  values: Vec<Evaluation>,
+  frames: Vec<Frame>
+}
+
+impl Machine {
+  fn new() -> Machine {
+    Machine { values: Vec::new(), frames: Vec::new() }
   }
+
+  fn pop(&mut self) -> Evaluation {
+    self.values.pop().unwrap_or(Evaluation::Nil)
+  }
+
+  // Run until everything has been evaluated
+  fn run(&mut self, interp: &mut Interpreter) -> Evaluation {
+    while let Some(frame) = self.frames.pop() {
+      self.resume(frame, interp);
+    }
+    self.pop()
+  }
+
+  // The value of an expression, checked against the size limit
+  fn result(&mut self, value: Evaluation, interp: &mut Interpreter) {
+    let value = interp.check_size(value);
+    self.values.push(value);
+  }
+
+  // Start evaluating an expression; anything that needs more than a look is
+  // left to a frame
+  fn expression(&mut self, e: &Expression, interp: &mut Interpreter) {
+    if let Some(e) = interp.step() {
+      self.values.push(e);
+      return;
+    }
+    let value = match e {
+      &Expression::Nil => Evaluation::Nil,
+      &Expression::True => Evaluation::True,
+      &Expression::False => Evaluation::False,
+      &Expression::Integer(x) => Evaluation::Integer(x),
+      &Expression::Float(x) => Evaluation::Float(x),
+      &Expression::String(ref s) => Evaluation::String(s.clone()),
+      &Expression::List(ref list) => {
+        let base = self.values.len();
+        self.frames.push(Frame::List { list: list.clone(), base: base });
+        return;
+      },
+      &Expression::Call(ref call) => {
+        self.call(call, interp);
+        return;
+      },
+      &Expression::Definition(ref def) => def.evaluate(interp),
+    };
+    self.result(value, interp);
+  }
+
+  // Work out what sort of call this is: a function bound to the name, a
+  // value (i.e., a passed param), $, or a primitive
+  fn call(&mut self, call: &Rc<Call>, interp: &mut Interpreter) {
+    let base = self.values.len();
+    match interp.lookup(call.id).map(|b| b.clone()) {
+      Some(FunctionOrValue::Function(func)) => {
+        if call.params.len() != func.params.len() {
+          let e = exception(ExceptionType::ArityError, &symbols::name(call.id),
+                            format!("expected {} arguments but got {}",
+                                    call.params.len(), func.params.len()));
+          self.result(e, interp);
+          return;
+        }
+        self.frames.push(Frame::Arguments { call: call.clone(), func: func,
+                                            skip: 0, base: base,
+                                            open: false });
+      },
+      Some(FunctionOrValue::Value(value)) => self.result(value, interp),
+      None if call.id == symbols::DOLLAR => {
+        if call.params.is_empty() {
+          let e = exception(ExceptionType::ArityError, &symbols::name(call.id),
+                            "expected at least 1 argument but got 0".to_string());
+          self.result(e, interp);
+          return;
+        }
+        self.frames.push(Frame::Callee { call: call.clone() });
+        self.expression(&call.params[0], interp);
+      },
+      None => {
+        self.frames.push(Frame::Primitive { call: call.clone(), base: base });
+      },
+    }
+  }
+
+  // Carry on with a frame now that the last thing it started has a value
+  fn resume(&mut self, frame: Frame, interp: &mut Interpreter) {
+    match frame {
+      Frame::List { list, base } => {
+        let n = self.values.len() - base;
+        if n < list.items.len() {
+          let next = list.clone();
+          self.frames.push(Frame::List { list: list, base: base });
+          self.expression(&next.items[n], interp);
+        } else {
+          let items = self.values.split_off(base);
+          self.result(Evaluation::List(ListEval::from_items(items)), interp);
+        }
+      },
+      Frame::Primitive { call, base } => {
+        let n = self.values.len() - base;
+        if n < call.params.len() {
+          let next = call.clone();
+          self.frames.push(Frame::Primitive { call: call, base: base });
+          self.expression(&next.params[n], interp);
+        } else {
+          let args = self.values.split_off(base);
+          let rc = primitives::guarded_system_functions(call.id, args, interp);
+          self.result(rc, interp);
+        }
+      },
+      Frame::Callee { call } => {
+        let callee = self.pop();
+        match callee {
+          Evaluation::Exception(_) => self.result(callee, interp),
+          Evaluation::Function(func) => {
+            if call.params.len() - 1 != func.params.len() {
+              let e = exception(ExceptionType::ArityError, &symbols::name(call.id),
+                                format!("called function expected {} arguments but got {}",
+                                        call.params.len() - 1,
+                                        func.params.len()));
+              self.result(e, interp);
+              return;
+            }
+            let base = self.values.len();
+            self.frames.push(Frame::Arguments { call: call, func: func,
+                                                skip: 1, base: base,
+                                                open: false });
+          },
+          _ => {
+            let e = exception(ExceptionType::TypeError, &symbols::name(call.id),
+                              "function expected as first argument".to_string());
+            self.result(e, interp);
+          },
+        }
+      },
+      Frame::Arguments { call, func, skip, base, open } => {
+        if open {
+          let value = self.pop();
+          self.values.push(leave_block(value, call.id));
+          interp.pop_scope();
+        }
+        let n = self.values.len() - base;
+        if n + skip < call.params.len() {
+          let next = call.clone();
+          interp.push_scope();
+          self.frames.push(Frame::Arguments { call: call, func: func,
+                                              skip: skip, base: base,
+                                              open: true });
+          self.expression(&next.params[n + skip], interp);
+        } else {
+          let args = self.values.split_off(base);
+          self.apply(&func, args, call.id, End::Call, interp);
+        }
+      },
+      Frame::Block { block, next, context, end } => {
+        if next > 0 {
+          let value = self.pop();
+          let stop = match value {
+            Evaluation::Exception(_) => true,
+            _ => false,
+          };
+          if stop || next == block.expressions.len() {
+            self.finish(leave_block(value, context), end, interp);
+            return;
+          }
+        } else if block.expressions.is_empty() {
+          self.finish(Evaluation::Nil, end, interp);
+          return;
+        }
+        let statements = block.clone();
+        self.frames.push(Frame::Block { block: block, next: next + 1,
+                                        context: context, end: end });
+        self.expression(&statements.expressions[next], interp);
+      },
+      Frame::Memo { memo, key } => {
+        if let Some(value) = self.values.last() {
+          memo.insert(key, value);
+        }
+      },
+    }
+  }
+
+  // A memoized function's cached result if it has one, otherwise the call
+  // depth check, then a scope with the parameters in it for the body to run
+  // in
+  fn apply(&mut self, func: &Function, args: Vec<Evaluation>,
+           context: Symbol, end: End, interp: &mut Interpreter) {
+    let cache = memo::entry(func, &args);
+    if let Some((ref memo, ref key)) = cache {
+      if let Some(value) = memo.get(key) {
+        match end {
+          End::Call => self.result(value, interp),
+          _ => self.values.push(value),
+        }
+        return;
+      }
+    }
+    if interp.depth >= interp.max_depth {
+      let e = exception(ExceptionType::StackOverflow, &symbols::name(context),
+                        format!("maximum call depth of {} exceeded",
+                                interp.max_depth));
+      match end {
+        End::Call => self.result(e, interp),
+        _ => self.values.push(e),
+      }
+      return;
+    }
+    if let Some((memo, key)) = cache {
+      self.frames.push(Frame::Memo { memo: memo, key: key });
+    }
+    interp.push_scope();
+    interp.bind(symbols::SELF,
+                FunctionOrValue::Value(Evaluation::Function(func.clone())));
+    for (y, eval) in args.into_iter().enumerate() {
+      interp.bind(func.params[y], FunctionOrValue::Value(eval));
+    }
+    interp.depth += 1;
+    self.block(func.block.clone(), context, end, interp);
+  }
+
+  fn block(&mut self, block: Rc<Block>, context: Symbol, end: End,
+           interp: &mut Interpreter) {
+    interp.push_scope();
+    self.frames.push(Frame::Block { block: block, next: 0, context: context,
+                                    end: end });
+  }
+
+  // A block is done: end its scope, and the function's if it was a body
+  fn finish(&mut self, value: Evaluation, end: End, interp: &mut Interpreter) {
+    interp.pop_scope();
+    match end {
+      End::Block => self.values.push(value),
+      End::Apply => {
+        interp.depth -= 1;
+        interp.pop_scope();
+        self.values.push(value);
+      },
+      End::Call => {
+        interp.depth -= 1;
+        interp.pop_scope();
+        self.result(value, interp);
+      },
+    }
+  }
+}
+
+// The value of an expression, in the current scope
+pub fn evaluate_expression(e: &Expression, interp: &mut Interpreter) ->
+  Evaluation {
+  let mut machine = Machine::new();
+  machine.expression(e, interp);
+  machine.run(interp)
+}
+
+// The value of a block in a scope of its own, with exceptions leaving it
+// picking up the context
+pub fn evaluate_block(block: &Rc<Block>, interp: &mut Interpreter,
+                      context: Symbol) -> Evaluation {
+  let mut machine = Machine::new();
+  machine.block(block.clone(), context, End::Block, interp);
+  machine.run(interp)
+}
+
+// Call a function with arguments that have already been evaluated
+pub fn apply(func: &Function, args: Vec<Evaluation>, interp: &mut Interpreter,
+             context: Symbol) -> Evaluation {
+  let mut machine = Machine::new();
+  machine.apply(func, args, context, End::Apply, interp);
+  machine.run(interp)
 }
@@ -1,9 +1,19 @@
 pub mod encoding;
 pub mod encoding_impl;
+pub mod encoding_list;
 pub mod encoding_display;
 
 pub mod tokenizer;
 pub mod parser;
+pub mod symbols;
+pub mod optimizer;
 pub mod evaluator;
+pub mod vm;
+pub mod compiler;
+pub mod memo;
 
 pub mod primitives;
+pub mod primitives_io;
+pub mod primitives_string;
+pub mod primitives_order;
+pub mod regex;
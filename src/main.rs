@@ -3,36 +3,190 @@ extern crate doubtful;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 use doubtful::tokenizer;
 use doubtful::parser;
 use doubtful::evaluator;
+use doubtful::compiler;
+use doubtful::encoding::Interpreter;
+use doubtful::encoding::Capabilities;
 
-fn main() {
-  let args: Vec<String> = env::args().collect();
+fn usage() -> ! {
+  panic!("usage: doubtful [--max-depth N] [--fuel N] [--max-size N] \
+          [--timeout SECONDS] [--sandbox] [--vm] [--optimize] <source file> [args...]\n       \
+          doubtful compile [-o OUTPUT] <source file>");
+}
 
-  // TODO: better command line
-  if args.len() != 2 {
-    panic!("incorrect number of arguments: expecting source file as argument");
+fn option_value<T: FromStr>(args: &Vec<String>, index: usize) -> T {
+  match args.get(index).and_then(|s| s.parse::<T>().ok()) {
+    Some(v) => v,
+    None => usage(),
   }
-  let filename = &args[1];
+}
 
+// Write out a program as C source (see compiler.rs), to stdout unless told
+// otherwise
+fn compile(args: &Vec<String>) -> i32 {
+  let mut output = None;
+  let mut filename = None;
+  let mut index = 0;
+  while index < args.len() {
+    if args[index] == "-o" {
+      index += 1;
+      output = Some(option_value::<String>(args, index));
+    } else if filename.is_none() {
+      filename = Some(args[index].clone());
+    } else {
+      usage();
+    }
+    index += 1;
+  }
+  let filename = match filename {
+    Some(f) => f,
+    None => usage(),
+  };
+  let mut source = String::new();
   match File::open(&filename) {
     Ok(mut file) => {
-      let mut source = String::new();
-      match &file.read_to_string(&mut source) {
-        &Ok(_) => {
-          let tokens = tokenizer::tokenize(&source);
-          let block = parser::parse(&tokens);
-          evaluator::evaluate(&block);
-        },
-        _ => {
-          panic!("failed to read source file");
-        }
+      if file.read_to_string(&mut source).is_err() {
+        panic!("failed to read source file");
       }
     },
     _ => {
       panic!("failed to open source file");
     },
   }
+  let parsed = tokenizer::tokenize(&source).and_then(|tokens| {
+    parser::parse(&tokens)
+  });
+  let code = match parsed {
+    Ok(block) => compiler::compile(&block),
+    Err(msg) => {
+      println!("\nSYNTAX ERROR: {}", msg);
+      return evaluator::EXIT_SYNTAX_ERROR;
+    },
+  };
+  match output {
+    Some(path) => {
+      match File::create(&path).and_then(|mut f| f.write_all(code.as_bytes())) {
+        Ok(_) => 0,
+        Err(err) => panic!("failed to write {}: {}", path, err),
+      }
+    },
+    None => {
+      print!("{}", code);
+      0
+    },
+  }
+}
+
+fn main() {
+  let args: Vec<String> = env::args().collect();
+
+  if args.len() > 1 && args[1] == "compile" {
+    std::process::exit(compile(&args[2..].to_vec()));
+  }
+
+  // TODO: better command line
+  // Interpreter values aren't Send, so gather up the settings here and build
+  // the interpreter on the thread that runs it
+  let mut max_depth = evaluator::DEFAULT_MAX_DEPTH;
+  let mut fuel = None;
+  let mut max_size = None;
+  let mut timeout = None;
+  let mut capabilities = Capabilities::all();
+  let mut bytecode = false;
+  let mut optimize = false;
+  let mut program_args = Vec::new();
+  let mut filename = None;
+  let mut index = 1;
+  while index < args.len() {
+    if args[index] == "--max-depth" {
+      index += 1;
+      max_depth = option_value(&args, index);
+    } else if args[index] == "--fuel" {
+      index += 1;
+      fuel = Some(option_value(&args, index));
+    } else if args[index] == "--max-size" {
+      index += 1;
+      max_size = Some(option_value(&args, index));
+    } else if args[index] == "--timeout" {
+      index += 1;
+      let secs: f64 = option_value(&args, index);
+      timeout = Some(Duration::from_millis((secs * 1000.0) as u64));
+    } else if args[index] == "--sandbox" {
+      capabilities = Capabilities::none();
+    } else if args[index] == "--vm" {
+      bytecode = true;
+    } else if args[index] == "--optimize" {
+      optimize = true;
+    } else {
+      // Everything after the source file is for the program
+      filename = Some(args[index].clone());
+      program_args = args[index + 1..].to_vec();
+      break;
+    }
+    index += 1;
+  }
+  let filename = match filename {
+    Some(f) => f,
+    None => usage(),
+  };
+
+  // Calls are kept on the evaluator's own stacks, whichever way the program
+  // runs, so Rust stack is only needed for primitives calling back into
+  // functions; with a thread sized for it, those can go as deep as calls do
+  // (up to the default depth), rather than the interpreter's usual handful
+  let max_callbacks = max_depth.min(evaluator::DEFAULT_MAX_DEPTH);
+  let stack = evaluator::stack_size(max_callbacks);
+  let child = thread::Builder::new().stack_size(stack).spawn(move || {
+    let mut interp = Interpreter::new();
+    interp.max_depth = max_depth;
+    interp.max_callbacks = max_callbacks;
+    interp.fuel = fuel;
+    interp.max_size = max_size;
+    interp.timeout = timeout;
+    interp.capabilities = capabilities;
+    interp.bytecode = bytecode;
+    interp.optimize = optimize;
+    interp.args = program_args;
+    match File::open(&filename) {
+      Ok(mut file) => {
+        let mut source = String::new();
+        match &file.read_to_string(&mut source) {
+          &Ok(_) => {
+            let parsed = tokenizer::tokenize(&source).and_then(|tokens| {
+              parser::parse(&tokens)
+            });
+            match parsed {
+              Ok(block) => evaluator::evaluate_with(&block, &mut interp),
+              Err(msg) => {
+                println!("\nSYNTAX ERROR: {}", msg);
+                evaluator::EXIT_SYNTAX_ERROR
+              },
+            }
+          },
+          _ => {
+            panic!("failed to read source file");
+          }
+        }
+      },
+      _ => {
+        panic!("failed to open source file");
+      },
+    }
+  });
+  match child {
+    Ok(handle) => {
+      match handle.join() {
+        Ok(code) => std::process::exit(code),
+        // Panic message has already been printed by the thread
+        Err(_) => std::process::exit(101),
+      }
+    },
+    _ => panic!("failed to start interpreter thread"),
+  }
 }
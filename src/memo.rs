@@ -0,0 +1,221 @@
+// Memoized functions: memo(f) gives a copy of the function with a cache of
+// its results, keyed by the arguments it was called with, so calling it again
+// with equal arguments gives back the result without running the body.  Only
+// plain data can be a key (nil, booleans, numbers, strings and lists of
+// those), compared structurally, with floats by their bits; a call with a
+// function or exception among its arguments runs as usual, and results that
+// are exceptions aren't kept.  The cache holds a bounded number of results,
+// dropping the oldest to make room, and memo_clear(f) empties it.
+//
+// Scoping is dynamic, so a function's result can depend on more than its
+// arguments; memoizing one like that is the program's lookout.  A memoized
+// function is bound as self while it runs, so recursion through $(self, ...)
+// goes through the cache, while recursion by name calls the original.
+//
+// The cache belongs to the value memo() returns, and definitions are
+// functions, so f:memo(g);; calls memo() afresh (with an empty cache) every
+// time f is used.  To share a cache, memo() once and pass the result along as
+// a parameter.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::rc::Rc;
+
+use evaluator;
+use primitives::expect_args;
+use primitives::Primitive;
+
+use encoding::Evaluation;
+use encoding::Function;
+use encoding::ExceptionType;
+
+// How many results memo(f) keeps without a size
+pub const DEFAULT_MEMO_SIZE: usize = 1000;
+
+// An argument as part of a cache key
+pub enum Key {
+  Nil, True, False, Integer(i64), Float(u64), String(Rc<str>), List(Vec<Key>)
+}
+
+impl Hash for Key {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    match self {
+      &Key::Nil => 0u8.hash(state),
+      &Key::True => 1u8.hash(state),
+      &Key::False => 2u8.hash(state),
+      &Key::Integer(x) => {
+        3u8.hash(state);
+        x.hash(state);
+      },
+      &Key::Float(x) => {
+        4u8.hash(state);
+        x.hash(state);
+      },
+      &Key::String(ref s) => {
+        5u8.hash(state);
+        s.hash(state);
+      },
+      &Key::List(ref items) => {
+        6u8.hash(state);
+        items.hash(state);
+      },
+    }
+  }
+}
+
+impl PartialEq for Key {
+  fn eq(&self, other: &Key) -> bool {
+    match (self, other) {
+      (&Key::Nil, &Key::Nil) => true,
+      (&Key::True, &Key::True) => true,
+      (&Key::False, &Key::False) => true,
+      (&Key::Integer(x), &Key::Integer(y)) => x == y,
+      (&Key::Float(x), &Key::Float(y)) => x == y,
+      (&Key::String(ref x), &Key::String(ref y)) => x == y,
+      (&Key::List(ref x), &Key::List(ref y)) => x == y,
+      _ => false,
+    }
+  }
+}
+
+impl Eq for Key {}
+
+fn key(value: &Evaluation) -> Option<Key> {
+  match value {
+    &Evaluation::Nil => Some(Key::Nil),
+    &Evaluation::True => Some(Key::True),
+    &Evaluation::False => Some(Key::False),
+    &Evaluation::Integer(x) => Some(Key::Integer(x)),
+    &Evaluation::Float(x) => Some(Key::Float(x.to_bits())),
+    &Evaluation::String(ref s) => Some(Key::String(s.clone())),
+    &Evaluation::List(ref list) => {
+      let mut items = Vec::new();
+      for i in list.iter() {
+        items.push(key(i)?);
+      }
+      Some(Key::List(items))
+    },
+    _ => None,
+  }
+}
+
+struct Cache {
+  results: HashMap<Rc<Vec<Key>>, Evaluation>,
+  // Keys in the order they were added, oldest first
+  order: VecDeque<Rc<Vec<Key>>>
+}
+
+// The results of a function made by memo(), shared by every copy of the
+// function value
+pub struct Memo {
+  cache: RefCell<Cache>,
+  capacity: usize
+}
+
+impl Memo {
+  pub fn new(capacity: usize) -> Memo {
+    Memo { cache: RefCell::new(Cache { results: HashMap::new(),
+                                       order: VecDeque::new() }),
+           capacity: capacity }
+  }
+
+  pub fn get(&self, key: &Vec<Key>) -> Option<Evaluation> {
+    self.cache.borrow().results.get(key).map(|v| v.clone())
+  }
+
+  pub fn insert(&self, key: Vec<Key>, value: &Evaluation) {
+    if let &Evaluation::Exception(_) = value {
+      return;
+    }
+    let mut cache = self.cache.borrow_mut();
+    if let Some(v) = cache.results.get_mut(&key) {
+      *v = value.clone();
+      return;
+    }
+    if cache.results.len() >= self.capacity {
+      if let Some(oldest) = cache.order.pop_front() {
+        cache.results.remove(&oldest);
+      }
+    }
+    let key = Rc::new(key);
+    cache.order.push_back(key.clone());
+    cache.results.insert(key, value.clone());
+  }
+
+  pub fn clear(&self) {
+    let mut cache = self.cache.borrow_mut();
+    cache.results.clear();
+    cache.order.clear();
+  }
+}
+
+// Where the result of calling the function with these arguments is cached,
+// if it's memoized and the arguments can be a key
+pub fn entry(func: &Function, args: &Vec<Evaluation>) ->
+  Option<(Rc<Memo>, Vec<Key>)> {
+  let memo = match func.memo {
+    Some(ref memo) => memo.clone(),
+    None => { return None; },
+  };
+  let mut keys = Vec::new();
+  for a in args {
+    keys.push(key(a)?);
+  }
+  Some((memo, keys))
+}
+
+pub fn memo(op: &Primitive, id: &String, params: &Vec<Evaluation>) ->
+  Evaluation {
+  match op {
+    &Primitive::Memo => {
+      if params.len() != 1 && params.len() != 2 {
+        return evaluator::exception(ExceptionType::ArityError, id,
+                                    format!("expected 1 or 2 arguments but got {}",
+                                            params.len()));
+      }
+      let func = match params[0] {
+        Evaluation::Function(ref func) => func,
+        _ => {
+          return evaluator::exception(ExceptionType::TypeError, id,
+                                      "function expected for argument 1".to_string());
+        },
+      };
+      let capacity = if params.len() == 2 {
+        match params[1] {
+          Evaluation::Integer(n) if n >= 1 => n as usize,
+          Evaluation::Integer(_) => {
+            return evaluator::exception(ExceptionType::RuntimeError, id,
+                                        "cache size must be at least 1".to_string());
+          },
+          _ => {
+            return evaluator::exception(ExceptionType::TypeError, id,
+                                        "integer expected for argument 2".to_string());
+          },
+        }
+      } else {
+        DEFAULT_MEMO_SIZE
+      };
+      let mut rc = func.clone();
+      rc.memo = Some(Rc::new(Memo::new(capacity)));
+      Evaluation::Function(rc)
+    },
+    &Primitive::MemoClear => {
+      if let Some(e) = expect_args(1, params, id) {
+        return e;
+      }
+      match params[0] {
+        Evaluation::Function(Function { memo: Some(ref memo), .. }) => {
+          memo.clear();
+          Evaluation::Nil
+        },
+        _ => evaluator::exception(ExceptionType::TypeError, id,
+                                  "memoized function expected".to_string()),
+      }
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
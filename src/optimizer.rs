@@ -0,0 +1,377 @@
+// An optional pass over the syntax tree before running it, taking out work
+// whose result is already known: calls to pure primitives on constants are
+// folded into the constant they'd give, ?(...) on a constant condition becomes
+// the branch it picks, statements that can never run or whose constant values
+// are thrown away are dropped, and calls to trivial functions (one expression
+// of constants and parameters) are replaced by what they return.
+//
+// Nothing is changed unless it makes no difference to what the program does,
+// exception stacks included.  Scoping is dynamic, so a name can only be taken
+// to mean the primitive if nothing anywhere in the program binds it, and a
+// function is only inlined where its definition has to be the binding in
+// effect and the call couldn't have hit the depth limit.  Folding never makes
+// a value bigger than --max-size disappear, either.  What does change is the
+// number of steps counted against --fuel, and the time taken, which is the
+// point.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use primitives;
+use symbols;
+
+use encoding::Block;
+use encoding::Expression;
+use encoding::List;
+use encoding::Call;
+use encoding::Definition;
+use encoding::Symbol;
+use encoding::Interpreter;
+use encoding::Capabilities;
+use encoding::Evaluation;
+use encoding::ListEval;
+
+// Primitives whose result depends on nothing but their arguments, and which
+// are cheap enough to run on anything that could be written in a program
+const PURE: &[&str] = &[
+  "int", "float", "string", "+", "-", "*", "/", "%", "!", "&", "|", "?", "=",
+  ">", "<", "substr", "strlen", "car", "cdr", "split", "join", "find",
+  "contains?", "replace", "starts_with?", "ends_with?", "trim", "upper",
+  "lower", "chars", "ord", "chr", "format", "re_match", "re_find_all",
+  "re_replace", "re_split", "compare", "sort", "${}"
+];
+
+// Primitives that give an exception whatever they're passed, so nothing after
+// them in a block is ever evaluated
+const ALWAYS_RAISE: &[&str] = &["~", "raise", "rethrow", "exit"];
+
+struct Optimizer {
+  // Every name the program binds somewhere, as a function or a parameter
+  bound: HashSet<Symbol>,
+  // Top-level functions that can be inlined, and those of them whose
+  // definitions have been passed at the top level
+  inlinable: HashMap<Symbol, Definition>,
+  defined: HashSet<Symbol>,
+  // Whether the top level of the program can call a function at all
+  calls: bool,
+  max_size: Option<usize>,
+  // For running primitives
+  scratch: Interpreter
+}
+
+fn is_constant(e: &Expression) -> bool {
+  match e {
+    &Expression::Nil | &Expression::True | &Expression::False |
+    &Expression::Integer(_) | &Expression::Float(_) |
+    &Expression::String(_) => true,
+    &Expression::List(ref list) => list.items.iter().all(is_constant),
+    _ => false,
+  }
+}
+
+fn value(e: &Expression) -> Option<Evaluation> {
+  match e {
+    &Expression::Nil => Some(Evaluation::Nil),
+    &Expression::True => Some(Evaluation::True),
+    &Expression::False => Some(Evaluation::False),
+    &Expression::Integer(x) => Some(Evaluation::Integer(x)),
+    &Expression::Float(x) => Some(Evaluation::Float(x)),
+    &Expression::String(ref s) => Some(Evaluation::String(s.clone())),
+    &Expression::List(ref list) => {
+      let mut items = Vec::new();
+      for i in &list.items {
+        items.push(value(i)?);
+      }
+      Some(Evaluation::List(ListEval::from_items(items)))
+    },
+    _ => None,
+  }
+}
+
+// The constant for a value, if it has one
+fn constant(eval: &Evaluation) -> Option<Expression> {
+  match eval {
+    &Evaluation::Nil => Some(Expression::Nil),
+    &Evaluation::True => Some(Expression::True),
+    &Evaluation::False => Some(Expression::False),
+    &Evaluation::Integer(x) => Some(Expression::Integer(x)),
+    &Evaluation::Float(x) => Some(Expression::Float(x)),
+    &Evaluation::String(ref s) => Some(Expression::String(s.clone())),
+    &Evaluation::List(ref list) => {
+      let mut items = Vec::new();
+      for i in list.iter() {
+        items.push(constant(i)?);
+      }
+      Some(Expression::List(Rc::new(List { items: items })))
+    },
+    _ => None,
+  }
+}
+
+// Names bound anywhere in the block, with a count of the definitions of each
+fn collect(block: &Block, definitions: &mut HashMap<Symbol, usize>,
+           params: &mut HashSet<Symbol>) {
+  for e in &block.expressions {
+    collect_expression(e, definitions, params);
+  }
+}
+
+fn collect_expression(e: &Expression, definitions: &mut HashMap<Symbol, usize>,
+                      params: &mut HashSet<Symbol>) {
+  match e {
+    &Expression::List(ref list) => {
+      for i in &list.items {
+        collect_expression(i, definitions, params);
+      }
+    },
+    &Expression::Call(ref call) => {
+      for p in &call.params {
+        collect_expression(p, definitions, params);
+      }
+    },
+    &Expression::Definition(ref def) => {
+      *definitions.entry(def.id).or_insert(0) += 1;
+      for p in def.params.iter() {
+        params.insert(*p);
+      }
+      collect(&def.block, definitions, params);
+    },
+    _ => {},
+  }
+}
+
+// Replace calls to parameters by the arguments in a trivial function body
+fn substitute(e: &Expression, args: &HashMap<Symbol, Expression>) ->
+  Expression {
+  match e {
+    &Expression::List(ref list) => {
+      Expression::List(Rc::new(List { items: list.items.iter().map(|i| {
+        substitute(i, args)
+      }).collect() }))
+    },
+    &Expression::Call(ref call) => {
+      match args.get(&call.id) {
+        Some(arg) if call.params.is_empty() => arg.clone(),
+        _ => {
+          let mut rc = Call::clone(call);
+          rc.params = call.params.iter().map(|p| substitute(p, args)).collect();
+          Expression::Call(Rc::new(rc))
+        },
+      }
+    },
+    _ => e.clone(),
+  }
+}
+
+impl Optimizer {
+  // Does the name always mean the primitive?
+  fn primitive(&self, id: Symbol, names: &[&str]) -> bool {
+    !self.bound.contains(&id) && names.contains(&symbols::name(id).as_str())
+  }
+
+  // Is the value no bigger than the size limit, all the way down?
+  fn fits(&self, eval: &Evaluation) -> bool {
+    let max = match self.max_size {
+      Some(max) => max,
+      None => { return true; },
+    };
+    match eval {
+      &Evaluation::String(ref s) => s.len() <= max,
+      &Evaluation::List(ref list) => {
+        list.len() <= max && list.iter().all(|i| self.fits(i))
+      },
+      _ => true,
+    }
+  }
+
+  // Can this be left unevaluated without changing anything?  Evaluating a
+  // constant too big for the size limit stops the program
+  fn inert(&self, e: &Expression) -> bool {
+    match value(e) {
+      Some(eval) => self.fits(&eval),
+      None => false,
+    }
+  }
+
+  // Can a call to this function be replaced by its body, given constants
+  // for the parameters?
+  fn trivial(&self, e: &Expression, params: &Vec<Symbol>) -> bool {
+    match e {
+      &Expression::List(ref list) => {
+        list.items.iter().all(|i| self.trivial(i, params))
+      },
+      &Expression::Call(ref call) => {
+        if params.contains(&call.id) {
+          call.params.is_empty()
+        } else {
+          self.primitive(call.id, PURE) &&
+            call.params.iter().all(|p| self.trivial(p, params))
+        }
+      },
+      &Expression::Definition(_) => false,
+      _ => true,
+    }
+  }
+
+  fn block(&mut self, block: &Block, top: bool) -> Block {
+    let mut expressions = Vec::new();
+    for e in &block.expressions {
+      let e = self.expression(e, top);
+      let stops = match e {
+        Expression::Call(ref call) => self.primitive(call.id, ALWAYS_RAISE),
+        _ => false,
+      };
+      if top {
+        if let Expression::Definition(ref def) = e {
+          if self.inlinable.contains_key(&def.id) {
+            self.defined.insert(def.id);
+          }
+        }
+      }
+      expressions.push(e);
+      if stops {
+        break;
+      }
+    }
+    // Constants are evaluated for nothing unless they're the block's value
+    let last = expressions.len().saturating_sub(1);
+    let expressions = expressions.into_iter().enumerate().filter(|&(i, ref e)| {
+      i == last || !self.inert(e)
+    }).map(|(_, e)| e).collect();
+    Block { expressions: expressions }
+  }
+
+  // The top level of the program runs outside any function, where calls are
+  // never deep enough to fail (unless no calls are allowed at all)
+  fn expression(&mut self, e: &Expression, top: bool) -> Expression {
+    match e {
+      &Expression::List(ref list) => {
+        Expression::List(Rc::new(List { items: list.items.iter().map(|i| {
+          self.expression(i, top)
+        }).collect() }))
+      },
+      &Expression::Call(ref call) => {
+        let mut rc = Call::clone(call);
+        rc.params = call.params.iter().map(|p| self.expression(p, top)).collect();
+        if let Some(e) = self.choose(&rc) {
+          return e;
+        }
+        if let Some(e) = self.fold(&rc) {
+          return e;
+        }
+        if top && self.calls {
+          if let Some(e) = self.inline(&rc) {
+            return e;
+          }
+        }
+        Expression::Call(Rc::new(rc))
+      },
+      &Expression::Definition(ref def) => {
+        let mut rc = def.clone();
+        rc.block = Rc::new(self.block(&def.block, false));
+        Expression::Definition(rc)
+      },
+      _ => e.clone(),
+    }
+  }
+
+  // ?(...) on a constant condition is the branch it picks, as long as the
+  // other one doesn't need evaluating (and fits the size limit)
+  fn choose(&self, call: &Call) -> Option<Expression> {
+    if !self.primitive(call.id, &["?"]) || call.params.len() != 3 {
+      return None;
+    }
+    let (chosen, other) = match call.params[0] {
+      Expression::True => (&call.params[1], &call.params[2]),
+      Expression::False => (&call.params[2], &call.params[1]),
+      _ => { return None; },
+    };
+    if self.inert(other) {
+      Some(chosen.clone())
+    } else {
+      None
+    }
+  }
+
+  // Run a pure primitive on constants, if it succeeds
+  fn fold(&mut self, call: &Call) -> Option<Expression> {
+    if !self.primitive(call.id, PURE) {
+      return None;
+    }
+    let mut params = Vec::new();
+    for p in &call.params {
+      let param = value(p)?;
+      if !self.fits(&param) {
+        return None;
+      }
+      params.push(param);
+    }
+    let rc = primitives::guarded_system_functions(call.id, params,
+                                                  &mut self.scratch);
+    self.scratch.terminated = None;
+    if self.fits(&rc) {
+      constant(&rc)
+    } else {
+      None
+    }
+  }
+
+  fn inline(&mut self, call: &Call) -> Option<Expression> {
+    if !self.defined.contains(&call.id) {
+      return None;
+    }
+    let def = self.inlinable[&call.id].clone();
+    if def.params.len() != call.params.len() ||
+      !call.params.iter().all(is_constant) {
+      return None;
+    }
+    let mut args = HashMap::new();
+    for (p, a) in def.params.iter().zip(&call.params) {
+      args.insert(*p, a.clone());
+    }
+    let rc = self.expression(&substitute(&def.block.expressions[0], &args),
+                             false);
+    if is_constant(&rc) {
+      Some(rc)
+    } else {
+      None
+    }
+  }
+}
+
+// Optimize a program for running with the interpreter's limits
+pub fn optimize(block: &Block, interp: &Interpreter) -> Block {
+  let mut definitions = HashMap::new();
+  let mut params = HashSet::new();
+  collect(block, &mut definitions, &mut params);
+  let mut bound: HashSet<Symbol> = definitions.keys().cloned().collect();
+  bound.extend(params.iter().cloned());
+  let mut scratch = Interpreter::new();
+  // Folding runs primitives, which are held to the same size and time limits
+  // as the program (run sets the deadline before optimizing)
+  scratch.max_size = interp.max_size;
+  scratch.timeout = interp.timeout;
+  scratch.deadline = interp.deadline;
+  scratch.capabilities = Capabilities::none();
+  let mut optimizer = Optimizer { bound: bound, inlinable: HashMap::new(),
+                                  defined: HashSet::new(),
+                                  calls: interp.max_depth > 0,
+                                  max_size: interp.max_size,
+                                  scratch: scratch };
+
+  // Functions defined once, at the top level, are the only binding their
+  // names ever have once the definition has run
+  for e in &block.expressions {
+    if let &Expression::Definition(ref def) = e {
+      let reserved = def.id == symbols::intern("") || def.id == symbols::SELF ||
+        def.id == symbols::ARGS;
+      if !reserved && definitions[&def.id] == 1 && !params.contains(&def.id) &&
+        def.block.expressions.len() == 1 &&
+        optimizer.trivial(&def.block.expressions[0], &def.params) {
+        optimizer.inlinable.insert(def.id, def.clone());
+      }
+    }
+  }
+  optimizer.block(block, true)
+}
@@ -1,6 +1,12 @@
 // Simple parser, which turns tokens into our internal encoding:
 
+use std::rc::Rc;
+
+use symbols;
+
 use encoding::Token;
+use encoding::Symbol;
+use encoding::StringPart;
 
 use encoding::Block;
 use encoding::Expression;
@@ -8,29 +14,28 @@ use encoding::List;
 use encoding::Call;
 use encoding::Definition;
 
-fn get_token(tokens: &Vec<Token>, start: usize) -> &Token {
-  if start > tokens.len() - 1 {
-    // TODO: replace all the panics in the parser with better handling,
-    // line number, blah blah
-    panic!("unexpected end of file; statement unterminated")
+fn get_token(tokens: &Vec<Token>, start: usize) -> Result<&Token, String> {
+  if start >= tokens.len() {
+    // TODO: better error handling, line number, blah blah
+    return Err("unexpected end of file; statement unterminated".to_string());
   }
-  &tokens[start]
+  Ok(&tokens[start])
 }
 
 fn parse_params(tokens: &Vec<Token>, start: usize) ->
-  (Option<Vec<String>>, usize) {
+  Result<(Option<Vec<Symbol>>, usize), String> {
   let mut rc = Vec::new();
   let mut index = start;
   loop {
-    match get_token(tokens, index) {
+    match get_token(tokens, index)? {
       &Token::CloseParen => {
         index += 1;
         break;
       },
       &Token::ID(ref s) => {
-        rc.push(s.clone());
+        rc.push(symbols::intern(s));
         index += 1;
-        match get_token(tokens, index) {
+        match get_token(tokens, index)? {
           &Token::Comma => {
             index += 1;
           },
@@ -38,109 +43,113 @@ fn parse_params(tokens: &Vec<Token>, start: usize) ->
             // do nothing, next loop will catch it
           },
           _ => {
-            return (None, 0);
+            return Ok((None, 0));
           }
         }
       },
       _ => {
-        return (None, 0);
+        return Ok((None, 0));
       },
     }
   }
-  (Some(rc), index)
+  Ok((Some(rc), index))
 }
 
 fn parse_definition(tokens: &Vec<Token>, start: usize) ->
-  (Option<Definition>, usize) {
-  match get_token(tokens, start) {
+  Result<(Option<Definition>, usize), String> {
+  match get_token(tokens, start)? {
     &Token::Colon => {
       // anonymous function with no parameters
-      let (block, index) = parse_block(tokens, start + 1);
-      (Some(Definition { id: "".to_string(), params: Vec::new(),
-                         block: block }), index)
+      let (block, index) = parse_block(tokens, start + 1)?;
+      Ok((Some(Definition { id: symbols::intern(""),
+                            params: Rc::new(Vec::new()),
+                            block: Rc::new(block) }), index))
     },
     &Token::OpenParen => {
       // anonymous function
-      let (opt, index) = parse_params(tokens, start + 1);
+      let (opt, index) = parse_params(tokens, start + 1)?;
       match opt {
         Some(params) => {
-          match get_token(tokens, index) {
+          match get_token(tokens, index)? {
             &Token::Colon => {
-              let (block, last) = parse_block(tokens, index + 1);
-              (Some(Definition { id:"".to_string(), params: params,
-                                 block: block }), last)
+              let (block, last) = parse_block(tokens, index + 1)?;
+              Ok((Some(Definition { id: symbols::intern(""),
+                                    params: Rc::new(params),
+                                    block: Rc::new(block) }), last))
             },
-            _ => (None, 0),
+            _ => Ok((None, 0)),
           }
         },
-        None => (None, 0),
+        None => Ok((None, 0)),
       }
     },
     &Token::ID(ref id) => {
       let mut index = start + 1;
-      match get_token(tokens, index) {
+      match get_token(tokens, index)? {
         &Token::Colon => {
           index += 1;
-          let (block, change) = parse_block(tokens, index);
-          (Some(Definition { id: id.clone(), params: Vec::new(),
-                             block: block }), change)
+          let (block, change) = parse_block(tokens, index)?;
+          Ok((Some(Definition { id: symbols::intern(id),
+                                params: Rc::new(Vec::new()),
+                                block: Rc::new(block) }), change))
         },
         &Token::OpenParen => {
-          let (opt, change) = parse_params(tokens, index + 1);
+          let (opt, change) = parse_params(tokens, index + 1)?;
           match opt {
             Some(params) => {
               index = change;
-              match get_token(tokens, index) {
+              match get_token(tokens, index)? {
                 &Token::Colon => {
                   index += 1;
-                  let (block, last) = parse_block(tokens, index);
-                  (Some(Definition { id: id.clone(), params: params,
-                                     block: block }), last)
+                  let (block, last) = parse_block(tokens, index)?;
+                  Ok((Some(Definition { id: symbols::intern(id),
+                                        params: Rc::new(params),
+                                        block: Rc::new(block) }), last))
                 },
-                _ => (None, 0),
+                _ => Ok((None, 0)),
               }
             },
-            None => (None, 0),
+            None => Ok((None, 0)),
           }
         },
-        _ => (None, 0),
+        _ => Ok((None, 0)),
       }
     },
-    _ => (None, 0),
+    _ => Ok((None, 0)),
   }
 }
 
-fn parse_call(tokens: &Vec<Token>, start: usize) -> (Call, usize) {
-  let id = match get_token(tokens, start) {
-    &Token::ID(ref s) => s.clone(),
-    _ => panic!("if you see this, there's a bug in the parser"),
+fn parse_call(tokens: &Vec<Token>, start: usize) -> Result<(Call, usize), String> {
+  let id = match get_token(tokens, start)? {
+    &Token::ID(ref s) => symbols::intern(s),
+    _ => { return Err("if you see this, there's a bug in the parser".to_string()); },
   };
   let mut rc = Call { id: id, params: Vec::new() };
   let mut index = start + 1;
-  match get_token(tokens, index) {
+  match get_token(tokens, index)? {
     &Token::OpenParen => {
       index += 1;
       loop {
-        match get_token(tokens, index) {
+        match get_token(tokens, index)? {
           &Token::CloseParen => {
             index += 1;
             break;
           },
           _ => {
-            let (param, change) = parse_next_expression(tokens, index);
+            let (param, change) = parse_next_expression(tokens, index)?;
             match param {
               Some(exp) => rc.params.push(exp),
-              None => panic!("expression or close paren expected")
+              None => { return Err("expression or close paren expected".to_string()); },
             }
             index = change;
-            match get_token(tokens, index) {
+            match get_token(tokens, index)? {
               &Token::Comma => {
                 index += 1;
               },
               &Token::CloseParen => {
                 // do nothing, will be caught at beginning of next loop
               },
-              _ => panic!("comma or close paren expected")
+              _ => { return Err("comma or close paren expected".to_string()); },
             }
           }
         }
@@ -150,84 +159,112 @@ fn parse_call(tokens: &Vec<Token>, start: usize) -> (Call, usize) {
       // Do nothing, bare function call
     },
   }
-  (rc, index)
+  Ok((rc, index))
 }
 
-fn parse_list(tokens: &Vec<Token>, start: usize) -> (List, usize) {
+fn parse_list(tokens: &Vec<Token>, start: usize) -> Result<(List, usize), String> {
   let mut rc = List { items: Vec::new() };
   let mut index = start + 1;
   loop {
-    match get_token(tokens, index) {
+    match get_token(tokens, index)? {
       &Token::CloseBracket => {
         break;
       },
       _ => {
-        let (item, change) = parse_next_expression(tokens, index);
+        let (item, change) = parse_next_expression(tokens, index)?;
         match item {
           Some(exp) => rc.items.push(exp),
-          None => panic!("expression or close bracket expected"),
+          None => { return Err("expression or close bracket expected".to_string()); },
         }
         index = change;
-        match get_token(tokens, index) {
+        match get_token(tokens, index)? {
           &Token::Comma => {
             index += 1;
           },
           &Token::CloseBracket => {
             // do nothing, will be caught at beginning of next loop
           },
-          _ => panic!("comma or close bracket expected")
+          _ => { return Err("comma or close bracket expected".to_string()); },
         }
       },
     }
   }
-  (rc, index + 1)
+  Ok((rc, index + 1))
+}
+
+// "a ${x} b" becomes a call to the interpolation primitive with "a", x and
+// " b", so the embedded expressions are evaluated right where the string is,
+// and nothing the program binds can change what the string turns into
+fn parse_interpolation(parts: &Vec<StringPart>) -> Result<Expression, String> {
+  let mut pieces = Vec::new();
+  for p in parts {
+    match p {
+      &StringPart::Text(ref s) => pieces.push(Expression::String(Rc::from(s.as_str()))),
+      &StringPart::Code(ref tokens) => {
+        let (opt, index) = parse_next_expression(tokens, 0)?;
+        let exp = match opt {
+          Some(exp) => exp,
+          None => { return Err("expression expected in ${}".to_string()); },
+        };
+        match get_token(tokens, index)? {
+          &Token::EOF => {},
+          _ => { return Err("unexpected token in ${}".to_string()); },
+        }
+        pieces.push(exp);
+      },
+    }
+  }
+  Ok(Expression::Call(Rc::new(Call { id: symbols::INTERPOLATE, params: pieces })))
 }
 
 fn parse_next_expression(tokens: &Vec<Token>, start: usize) ->
-  (Option<Expression>, usize) {
-  match get_token(tokens, start) {
-    &Token::Nil => (Some(Expression::Nil), start + 1),
-    &Token::True => (Some(Expression::True), start + 1),
-    &Token::False => (Some(Expression::False), start + 1),
-    &Token::Integer(x) => (Some(Expression::Integer(x)), start + 1),
-    &Token::Float(x) => (Some(Expression::Float(x)), start + 1),
-    &Token::String(ref s) => (Some(Expression::String(s.clone())), start + 1),
+  Result<(Option<Expression>, usize), String> {
+  match get_token(tokens, start)? {
+    &Token::Nil => Ok((Some(Expression::Nil), start + 1)),
+    &Token::True => Ok((Some(Expression::True), start + 1)),
+    &Token::False => Ok((Some(Expression::False), start + 1)),
+    &Token::Integer(x) => Ok((Some(Expression::Integer(x)), start + 1)),
+    &Token::Float(x) => Ok((Some(Expression::Float(x)), start + 1)),
+    &Token::String(ref s) => Ok((Some(Expression::String(Rc::from(s.as_str()))), start + 1)),
+    &Token::Interpolated(ref parts) => {
+      Ok((Some(parse_interpolation(parts)?), start + 1))
+    },
     &Token::OpenBracket => {
-      let (list, index) = parse_list(tokens, start);
-      (Some(Expression::List(list)), index)
+      let (list, index) = parse_list(tokens, start)?;
+      Ok((Some(Expression::List(Rc::new(list))), index))
     },
     &Token::ID(_) => {
-      let (opt, index) = parse_definition(tokens, start);
+      let (opt, index) = parse_definition(tokens, start)?;
       match opt {
         Some(def) => {
-          (Some(Expression::Definition(def)), index - 1)
+          Ok((Some(Expression::Definition(def)), index - 1))
         },
         None => {
-          let (call, index) = parse_call(tokens, start);
-          (Some(Expression::Call(call)), index)
+          let (call, index) = parse_call(tokens, start)?;
+          Ok((Some(Expression::Call(Rc::new(call))), index))
         },
       }
     },
     &Token::Colon | &Token::OpenParen => {
-      let (opt, index) = parse_definition(tokens, start);
+      let (opt, index) = parse_definition(tokens, start)?;
       match opt {
         Some(def) => {
-          (Some(Expression::Definition(def)), index - 1)
+          Ok((Some(Expression::Definition(def)), index - 1))
         },
         None => {
-          panic!("expected function definition, didn't get one");
+          Err("expected function definition, didn't get one".to_string())
         },
       }
     },
-    _ => (None, 0),
+    _ => Ok((None, 0)),
   }
 }
 
-fn parse_block(tokens: &Vec<Token>, start: usize) -> (Block, usize) {
+fn parse_block(tokens: &Vec<Token>, start: usize) -> Result<(Block, usize), String> {
   let mut rc = Block { expressions: Vec::new() };
   let mut index = start;
   loop {
-    let (next, change) = parse_next_expression(tokens, index);
+    let (next, change) = parse_next_expression(tokens, index)?;
     match next {
       Some(value) => {
         index = change;
@@ -240,24 +277,24 @@ fn parse_block(tokens: &Vec<Token>, start: usize) -> (Block, usize) {
         break;
       },
     }
-    let check = get_token(tokens, index);
+    let check = get_token(tokens, index)?;
     match check {
       &Token::Semicolon => {
         // do nothing
       },
       _ => {
-        panic!("semicolon expected after expression");
+        return Err("semicolon expected after expression".to_string());
       },
     }
     index += 1;
   }
-  (rc, index)
+  Ok((rc, index))
 }
 
-pub fn parse(tokens: &Vec<Token>) -> Block {
-  let (block, index) = parse_block(&tokens, 0);
+pub fn parse(tokens: &Vec<Token>) -> Result<Block, String> {
+  let (block, index) = parse_block(&tokens, 0)?;
   if index < tokens.len() {
-    panic!("syntax error, unexpected token")
+    return Err("syntax error, unexpected token".to_string());
   }
-  block
+  Ok(block)
 }
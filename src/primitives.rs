@@ -1,13 +1,95 @@
 // Primitive functions
 
+use std::cell::Cell;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+use std::sync::Once;
+
 use evaluator;
+use primitives_string;
+use primitives_order;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use primitives_io;
+use memo;
+use symbols;
 
+use encoding::Symbol;
+use encoding::Interpreter;
+use encoding::Capabilities;
 use encoding::Evaluation;
 use encoding::ListEval;
 use encoding::Exception;
 use encoding::ExceptionType;
 
-fn expect_args(count: usize, params: &Vec<Evaluation>, id: &String) ->
+// What a call runs when nothing is bound to its name
+pub enum Primitive {
+  Int, Float, String, Print, ReadFile, WriteFile, AppendFile, Exists,
+  DeleteFile, ListDir, Getenv, Env, Run, Split, Join, Find, Contains, Replace,
+  StartsWith, EndsWith, Trim, Upper, Lower, Chars, Ord, Chr, Repeat, Format,
+  Interpolate, ReMatch, ReFindAll, ReReplace, ReSplit, Compare, Sort, SortBy,
+  Memo, MemoClear, Add, Subtract, Multiply, Divide, Remainder, Not, And, Or,
+  If, Equal, Greater, Less, Substr, Strlen, Car, Cdr, Catch, Raise, Rethrow,
+  Wrap, Ensure, Exit, Return
+}
+
+// Every primitive by name.  The symbol table interns these first, right after
+// the names in symbols.rs and in this order, so finding the primitive a call
+// names is a matter of indexing rather than comparing strings
+pub const PRIMITIVES: &[(&str, Primitive)] = &[
+  ("int", Primitive::Int), ("float", Primitive::Float),
+  ("string", Primitive::String), (">>", Primitive::Print),
+  ("read_file", Primitive::ReadFile), ("write_file", Primitive::WriteFile),
+  ("append_file", Primitive::AppendFile), ("exists?", Primitive::Exists),
+  ("delete_file", Primitive::DeleteFile), ("list_dir", Primitive::ListDir),
+  ("getenv", Primitive::Getenv), ("env", Primitive::Env),
+  ("run", Primitive::Run), ("split", Primitive::Split),
+  ("join", Primitive::Join), ("find", Primitive::Find),
+  ("contains?", Primitive::Contains), ("replace", Primitive::Replace),
+  ("starts_with?", Primitive::StartsWith), ("ends_with?", Primitive::EndsWith),
+  ("trim", Primitive::Trim), ("upper", Primitive::Upper),
+  ("lower", Primitive::Lower), ("chars", Primitive::Chars),
+  ("ord", Primitive::Ord), ("chr", Primitive::Chr),
+  ("repeat", Primitive::Repeat), ("format", Primitive::Format),
+  ("${}", Primitive::Interpolate), ("re_match", Primitive::ReMatch),
+  ("re_find_all", Primitive::ReFindAll), ("re_replace", Primitive::ReReplace),
+  ("re_split", Primitive::ReSplit), ("compare", Primitive::Compare),
+  ("sort", Primitive::Sort), ("sort_by", Primitive::SortBy),
+  ("memo", Primitive::Memo), ("memo_clear", Primitive::MemoClear),
+  ("+", Primitive::Add), ("-", Primitive::Subtract),
+  ("*", Primitive::Multiply), ("/", Primitive::Divide),
+  ("%", Primitive::Remainder), ("!", Primitive::Not), ("&", Primitive::And),
+  ("|", Primitive::Or), ("?", Primitive::If), ("=", Primitive::Equal),
+  (">", Primitive::Greater), ("<", Primitive::Less),
+  ("substr", Primitive::Substr), ("strlen", Primitive::Strlen),
+  ("car", Primitive::Car), ("cdr", Primitive::Cdr),
+  ("catch", Primitive::Catch), ("raise", Primitive::Raise),
+  ("rethrow", Primitive::Rethrow), ("wrap", Primitive::Wrap),
+  ("ensure", Primitive::Ensure), ("exit", Primitive::Exit),
+  ("~", Primitive::Return)
+];
+
+impl Primitive {
+  // Most primitives pass on an exception in their arguments without running,
+  // but these are there to deal with them
+  fn takes_exceptions(&self) -> bool {
+    match self {
+      &Primitive::If | &Primitive::Catch | &Primitive::Ensure |
+      &Primitive::Wrap => true,
+      _ => false,
+    }
+  }
+}
+
+// The primitive a symbol names, if any
+pub fn primitive(id: Symbol) -> Option<&'static Primitive> {
+  if id < symbols::FIRST_PRIMITIVE {
+    return None;
+  }
+  PRIMITIVES.get(id - symbols::FIRST_PRIMITIVE).map(|&(_, ref op)| op)
+}
+
+pub fn expect_args(count: usize, params: &Vec<Evaluation>, id: &String) ->
   Option<Evaluation> {
   if count != params.len() {
     Some(evaluator::exception(ExceptionType::ArityError, id,
@@ -18,21 +100,241 @@ fn expect_args(count: usize, params: &Vec<Evaluation>, id: &String) ->
   }
 }
 
-// TODO: break this up into functions?  Could abstract this substantially, too
-pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
-  if id != "?" && id != "catch" {
-    for p in &params {
-      match p {
-        &Evaluation::Exception(_) => { return p.clone(); },
-        _ => {
-          // Not an exception, move along
-        },
+fn overflow(id: &String) -> Evaluation {
+  evaluator::exception(ExceptionType::RuntimeError, id,
+                       "integer overflow".to_string())
+}
+
+fn div_by_zero(id: &String) -> Evaluation {
+  evaluator::exception(ExceptionType::DivByZero, id,
+                       "integer division by zero".to_string())
+}
+
+// I/O primitives are grouped by what they can touch; each group can be
+// compiled out with a cargo feature or switched off on the Interpreter
+fn check_capability(op: &Primitive, id: &String, caps: &Capabilities) ->
+  Option<Evaluation> {
+  let (group, compiled, enabled) = match op {
+    &Primitive::Print => ("output", cfg!(feature = "output"), caps.output),
+    &Primitive::ReadFile | &Primitive::WriteFile | &Primitive::AppendFile |
+    &Primitive::Exists | &Primitive::DeleteFile | &Primitive::ListDir => {
+      ("files", cfg!(feature = "files"), caps.files)
+    },
+    &Primitive::Getenv | &Primitive::Env => {
+      ("environment", cfg!(feature = "env"), caps.environment)
+    },
+    &Primitive::Run => ("process", cfg!(feature = "process"), caps.process),
+    _ => { return None; },
+  };
+  if !compiled {
+    Some(evaluator::exception(ExceptionType::PermissionError, id,
+                              format!("{} primitives are not included in this build",
+                                      group)))
+  } else if !enabled {
+    Some(evaluator::exception(ExceptionType::PermissionError, id,
+                              format!("{} primitives are disabled", group)))
+  } else {
+    None
+  }
+}
+
+// Check an exception's type against a list of type names for catch
+fn exception_in_list(e: &Exception, types: &Evaluation) -> bool {
+  let flavor = e.flavor.to_string();
+  match types {
+    &Evaluation::List(ref list) => {
+      for t in list.iter() {
+        match t {
+          &Evaluation::String(ref s) if **s == *flavor => { return true; },
+          _ => {
+            // not this one, keep looking
+          },
+        }
+      }
+      false
+    },
+    _ => false,
+  }
+}
+
+// The list catch turns an exception into: type, payload, stack, and (only if
+// there is one) the cause as another list of the same form
+fn exception_to_list(e: &Exception) -> Evaluation {
+  let mut list = ListEval::new();
+  list.push(Evaluation::String(Rc::from(e.flavor.to_string())));
+  list.push(e.payload.clone());
+  let mut stack = ListEval::new();
+  for s in &e.stack {
+    stack.push(Evaluation::String(Rc::from(symbols::name(*s).as_str())));
+  }
+  list.push(Evaluation::List(stack));
+  if let Some(ref cause) = e.cause {
+    list.push(exception_to_list(cause));
+  }
+  Evaluation::List(list)
+}
+
+// Raising a user-defined exception named like a built-in one
+fn reserved(id: &String, name: &str) -> Evaluation {
+  evaluator::exception(ExceptionType::RuntimeError, id,
+                       format!("{} is a reserved exception type", name))
+}
+
+// Inverse of exception_to_list, for rethrow
+fn list_to_exception(list: &ListEval) -> Option<Exception> {
+  if list.len() != 3 && list.len() != 4 {
+    return None;
+  }
+  // Catch never gives back a program-ending exception, or "ok"
+  let mut e = match list[0] {
+    Evaluation::String(ref s) if &**s != "ok" => {
+      let flavor = ExceptionType::from_name(s);
+      if flavor.is_fatal() {
+        return None;
       }
+      Exception::new(&flavor, &list[1])
+    },
+    _ => { return None; },
+  };
+  match list[2] {
+    Evaluation::List(ref stack) => {
+      for i in stack.iter() {
+        match i {
+          &Evaluation::String(ref s) => e.stack.push(symbols::intern(s)),
+          _ => { return None; },
+        }
+      }
+    },
+    _ => { return None; },
+  }
+  if list.len() == 4 {
+    match list[3] {
+      Evaluation::List(ref cause) => {
+        match list_to_exception(cause) {
+          Some(c) => e.cause = Some(Box::new(c)),
+          None => { return None; },
+        }
+      },
+      _ => { return None; },
+    }
+  }
+  Some(e)
+}
+
+thread_local!(static IN_PRIMITIVE: Cell<bool> = Cell::new(false));
+static QUIET_HOOK: Once = Once::new();
+
+// Run a primitive, turning any internal panic into a runtime error exception
+// instead of taking down the whole interpreter.  The panic hook is silenced
+// while we're inside a primitive since the exception reports it instead
+pub fn guarded_system_functions(id: Symbol, mut params: Vec<Evaluation>,
+                                interp: &mut Interpreter) -> Evaluation {
+  QUIET_HOOK.call_once(|| {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      if !IN_PRIMITIVE.with(|p| p.get()) {
+        default(info);
+      }
+    }));
+  });
+  let name = symbols::name(id);
+  let op = match primitive(id) {
+    Some(op) => op,
+    None => {
+      if let Some(e) = first_exception(&mut params) {
+        return e;
+      }
+      return evaluator::exception(ExceptionType::UndefError, &name,
+                                  "function is not defined in scope".to_string());
+    },
+  };
+  let outer = IN_PRIMITIVE.with(|p| p.replace(true));
+  let rc = panic::catch_unwind(AssertUnwindSafe(|| {
+    system_functions(op, &name, params, interp)
+  }));
+  IN_PRIMITIVE.with(|p| p.set(outer));
+  match rc {
+    Ok(eval) => eval,
+    Err(err) => {
+      let msg = if let Some(s) = err.downcast_ref::<&str>() {
+        s.to_string()
+      } else if let Some(s) = err.downcast_ref::<String>() {
+        s.clone()
+      } else {
+        "unknown panic".to_string()
+      };
+      evaluator::exception(ExceptionType::RuntimeError, &name,
+                           format!("internal error: {}", msg))
+    },
+  }
+}
+
+// The everyday cases of the arithmetic primitives and ?, on integers and
+// booleans, worked out without going through system_functions; everything
+// else, overflow included, is left to it.  For the bytecode machine, which
+// knows what primitive a call would run from when it was compiled
+pub fn quick(op: &Primitive, params: &[Evaluation]) -> Option<Evaluation> {
+  let boolean = |b: bool| if b { Evaluation::True } else { Evaluation::False };
+  match (op, params) {
+    (&Primitive::If, &[Evaluation::True, ref a, _]) => Some(a.clone()),
+    (&Primitive::If, &[Evaluation::False, _, ref b]) => Some(b.clone()),
+    (&Primitive::Equal, &[Evaluation::Nil, Evaluation::Nil]) => {
+      Some(Evaluation::True)
+    },
+    (&Primitive::Equal, &[Evaluation::List(_), Evaluation::Nil]) |
+    (&Primitive::Equal, &[Evaluation::Nil, Evaluation::List(_)]) => {
+      Some(Evaluation::False)
+    },
+    (_, &[Evaluation::Integer(x), Evaluation::Integer(y)]) => {
+      match op {
+        &Primitive::Add => x.checked_add(y).map(Evaluation::Integer),
+        &Primitive::Subtract => x.checked_sub(y).map(Evaluation::Integer),
+        &Primitive::Multiply => x.checked_mul(y).map(Evaluation::Integer),
+        &Primitive::Equal => Some(boolean(x == y)),
+        &Primitive::Greater => Some(boolean(x > y)),
+        &Primitive::Less => Some(boolean(x < y)),
+        _ => None,
+      }
+    },
+    _ => None,
+  }
+}
+
+// The first exception among the arguments, taken out of them
+fn first_exception(params: &mut Vec<Evaluation>) -> Option<Evaluation> {
+  for x in 0..params.len() {
+    match params[x] {
+      Evaluation::Exception(_) => {
+        // Hand back the exception itself rather than copying its stack
+        return Some(params.swap_remove(x));
+      },
+      _ => {
+        // Not an exception, move along
+      },
+    }
+  }
+  None
+}
+
+// TODO: break this up into functions?  Could abstract this substantially, too
+pub fn system_functions(op: &Primitive, id: &String, mut params: Vec<Evaluation>,
+                        interp: &mut Interpreter) -> Evaluation {
+  // Once a limit has been hit nothing more runs, even on arguments that were
+  // evaluated before it was (which ?, catch and the like would pass on)
+  if interp.terminated.is_some() {
+    return interp.termination();
+  }
+  if let Some(e) = check_capability(op, id, &interp.capabilities) {
+    return e;
+  }
+  if !op.takes_exceptions() {
+    if let Some(e) = first_exception(&mut params) {
+      return e;
     }
   }
-  match &*id {
+  match op {
     // Type Conversion
-    "int" => {
+    &Primitive::Int => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
@@ -51,7 +353,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "float" => {
+    &Primitive::Float => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
@@ -70,14 +372,21 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "string" => {
+    &Primitive::String => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
-        None => Evaluation::String(format!("{}", params[0])),
+        None => {
+          let mut rc = String::new();
+          match primitives_string::show(&params[0], &mut rc, interp) {
+            Ok(()) => Evaluation::String(Rc::from(rc)),
+            Err(e) => e,
+          }
+        },
       }
     },
     // IO
-    ">>" => {
+    #[cfg(feature = "output")]
+    &Primitive::Print => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
@@ -92,15 +401,49 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
+    #[cfg(feature = "files")]
+    &Primitive::ReadFile | &Primitive::WriteFile | &Primitive::AppendFile |
+    &Primitive::Exists | &Primitive::DeleteFile | &Primitive::ListDir => {
+      primitives_io::files(op, &id, &params, interp)
+    },
+    #[cfg(feature = "env")]
+    &Primitive::Getenv | &Primitive::Env => {
+      primitives_io::environment(op, &id, &params)
+    },
+    #[cfg(feature = "process")]
+    &Primitive::Run => primitives_io::process(&id, &params, interp),
+    // STRINGS (beyond substr and strlen, below)
+    &Primitive::Split | &Primitive::Join | &Primitive::Find |
+    &Primitive::Contains | &Primitive::Replace | &Primitive::StartsWith |
+    &Primitive::EndsWith | &Primitive::Trim | &Primitive::Upper |
+    &Primitive::Lower | &Primitive::Chars | &Primitive::Ord | &Primitive::Chr |
+    &Primitive::Repeat => primitives_string::strings(op, &id, &params, interp),
+    &Primitive::Format => primitives_string::format(&id, &params, interp),
+    &Primitive::Interpolate => primitives_string::interpolate(&params, interp),
+    &Primitive::ReMatch | &Primitive::ReFindAll | &Primitive::ReReplace |
+    &Primitive::ReSplit => {
+      primitives_string::regex(op, &id, &params, interp)
+    },
+    // ORDERING
+    &Primitive::Compare | &Primitive::Sort | &Primitive::SortBy => {
+      primitives_order::ordering(op, &id, &params, interp)
+    },
+    // MEMOIZATION
+    &Primitive::Memo | &Primitive::MemoClear => memo::memo(op, &id, &params),
     // MATH (plus appending things)
-    "+" => {
+    &Primitive::Add => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
             Evaluation::Integer(x) => {
               match params[1] {
-                Evaluation::Integer(y) => Evaluation::Integer(x + y),
+                Evaluation::Integer(y) => {
+                  match x.checked_add(y) {
+                    Some(n) => Evaluation::Integer(n),
+                    None => overflow(&id),
+                  }
+                },
                 Evaluation::Float(y) => Evaluation::Float(x as f64 + y),
                 _ => evaluator::exception(ExceptionType::TypeMismatch, &id,
                                           "mismatched argument types".to_string()),
@@ -117,9 +460,9 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
             Evaluation::String(ref s) => {
               match params[1] {
                 Evaluation::String(ref t) => {
-                  let mut rc = s.clone();
-                  rc += &t.clone();
-                  Evaluation::String(rc)
+                  let mut rc = s.to_string();
+                  rc += t;
+                  Evaluation::String(Rc::from(rc))
                 },
                 _ => evaluator::exception(ExceptionType::TypeMismatch, &id,
                                           "mismatched argument types".to_string()),
@@ -128,21 +471,11 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
             Evaluation::List(ref list) => {
               match params[1] {
                 Evaluation::List(ref list2) => {
-                  let mut rc = ListEval { items: Vec::new() };
-                  for i in &list.items {
-                    rc.items.push(i.clone());
-                  }
-                  for i in &list2.items {
-                    rc.items.push(i.clone());
-                  }
-                  Evaluation::List(rc)
+                  Evaluation::List(list.concat(list2))
                 },
                 _ => {
-                  let mut rc = ListEval { items: Vec::new() };
-                  for i in &list.items {
-                    rc.items.push(i.clone());
-                  }
-                  rc.items.push(params[1].clone());
+                  let mut rc = list.clone();
+                  rc.push(params[1].clone());
                   Evaluation::List(rc)
                 },
               }
@@ -153,14 +486,19 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "-" => {
+    &Primitive::Subtract => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
             Evaluation::Integer(x) => {
               match params[1] {
-                Evaluation::Integer(y) => Evaluation::Integer(x - y),
+                Evaluation::Integer(y) => {
+                  match x.checked_sub(y) {
+                    Some(n) => Evaluation::Integer(n),
+                    None => overflow(&id),
+                  }
+                },
                 Evaluation::Float(y) => Evaluation::Float(x as f64 - y),
                 _ => evaluator::exception(ExceptionType::TypeError, &id,
                                           "numeric arguments expected".to_string()),
@@ -180,14 +518,19 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "*" => {
+    &Primitive::Multiply => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
             Evaluation::Integer(x) => {
               match params[1] {
-                Evaluation::Integer(y) => Evaluation::Integer(x * y),
+                Evaluation::Integer(y) => {
+                  match x.checked_mul(y) {
+                    Some(n) => Evaluation::Integer(n),
+                    None => overflow(&id),
+                  }
+                },
                 Evaluation::Float(y) => Evaluation::Float(x as f64 * y),
                 _ => evaluator::exception(ExceptionType::TypeError, &id,
                                           "numeric arguments expected".to_string()),
@@ -207,15 +550,20 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "/" => {
+    &Primitive::Divide => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
-            // TODO: handle division by zero with proper error
             Evaluation::Integer(x) => {
               match params[1] {
-                Evaluation::Integer(y) => Evaluation::Integer(x / y),
+                Evaluation::Integer(0) => div_by_zero(&id),
+                Evaluation::Integer(y) => {
+                  match x.checked_div(y) {
+                    Some(n) => Evaluation::Integer(n),
+                    None => overflow(&id),
+                  }
+                },
                 Evaluation::Float(y) => Evaluation::Float(x as f64 / y),
                 _ => evaluator::exception(ExceptionType::TypeError, &id,
                                           "numeric arguments expected".to_string()),
@@ -235,15 +583,20 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "%" => {
+    &Primitive::Remainder => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
-            // TODO: handle division by zero with proper error
             Evaluation::Integer(x) => {
               match params[1] {
-                Evaluation::Integer(y) => Evaluation::Integer(x % y),
+                Evaluation::Integer(0) => div_by_zero(&id),
+                Evaluation::Integer(y) => {
+                  match x.checked_rem(y) {
+                    Some(n) => Evaluation::Integer(n),
+                    None => overflow(&id),
+                  }
+                },
                 _ => evaluator::exception(ExceptionType::TypeError, &id,
                                           "integer arguments expected".to_string()),
               }
@@ -255,7 +608,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
       }
     },
     // BOOLEAN
-    "!" => {
+    &Primitive::Not => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
@@ -268,7 +621,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     }
-    "&" => {
+    &Primitive::And => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
@@ -295,7 +648,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "|" => {
+    &Primitive::Or => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
@@ -323,7 +676,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
       }
     },
     // CONTROL
-    "?" => {
+    &Primitive::If => {
       match expect_args(3, &params, &id) {
         Some(e) => e,
         None => {
@@ -337,7 +690,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "=" => {
+    &Primitive::Equal => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
@@ -399,13 +752,13 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
             Evaluation::List(ref x) => {
               match params[1] {
                 Evaluation::List(ref y) => {
-                  if x.items.len() != y.items.len() {
+                  if x.len() != y.len() {
                     Evaluation::False
                   } else {
                     let mut rc = Evaluation::True;
-                    for n in 0..x.items.len() {
-                      let cmp = vec![x.items[n].clone(), y.items[n].clone()];
-                      match system_functions("=".to_string(), cmp) {
+                    for (i, j) in x.iter().zip(y.iter()) {
+                      let cmp = vec![i.clone(), j.clone()];
+                      match system_functions(op, id, cmp, interp) {
                         Evaluation::True => {
                           // do nothing, everything still matches
                         },
@@ -426,7 +779,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    ">" => {
+    &Primitive::Greater => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
@@ -477,7 +830,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "<" => {
+    &Primitive::Less => {
       match expect_args(2, &params, &id) {
         Some(e) => e,
         None => {
@@ -529,7 +882,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "substr" => {
+    &Primitive::Substr => {
       match expect_args(3, &params, &id) {
         Some(e) => e,
         None => {
@@ -539,15 +892,13 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
                 Evaluation::Integer(start) => {
                   match params[2] {
                     Evaluation::Integer(len) => {
-                      let chars = s.chars();
-                      if start as usize >= s.len() {
-                        Evaluation::String("".to_string())
-                      } else if (start + len) as usize >= s.len() {
-                        let rc = chars.skip(start as usize).take(s.len() - start as usize).collect();
-                        Evaluation::String(rc)
+                      if start < 0 || len < 0 {
+                        evaluator::exception(ExceptionType::RuntimeError, &id,
+                                             "start and length cannot be negative".to_string())
                       } else {
-                        let rc = chars.skip(start as usize).take(len as usize).collect();
-                        Evaluation::String(rc)
+                        // Running off the end just truncates the substring
+                        let rc: String = s.chars().skip(start as usize).take(len as usize).collect();
+                        Evaluation::String(Rc::from(rc))
                       }
                     },
                     _ => evaluator::exception(ExceptionType::TypeError, &id,
@@ -564,7 +915,7 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "strlen" => {
+    &Primitive::Strlen => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
@@ -578,13 +929,13 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "car" => {
+    &Primitive::Car => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
             Evaluation::List(ref list) => {
-              match list.items.first() {
+              match list.first() {
                 Some(item) => {
                   item.clone()
                 },
@@ -598,15 +949,18 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "cdr" => {
+    &Primitive::Cdr => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
           match params[0] {
+            Evaluation::List(ref list) if list.is_empty() => {
+              evaluator::exception(ExceptionType::RuntimeError, &id,
+                                   "attempt to get rest of empty list".to_string())
+            },
             Evaluation::List(ref list) => {
-              let mut rc = list.clone();
-              rc.items.remove(0);
-              if rc.items.len() > 0 {
+              let rc = list.rest();
+              if rc.len() > 0 {
                 Evaluation::List(rc)
               } else {
                 Evaluation::Nil
@@ -618,53 +972,180 @@ pub fn system_functions(id: String, params: Vec<Evaluation>) -> Evaluation {
         },
       }
     },
-    "catch" => {
+    &Primitive::Catch => {
+      if params.len() != 1 && params.len() != 2 {
+        return evaluator::exception(ExceptionType::ArityError, &id,
+                                    format!("expected 1 or 2 arguments but got {}",
+                                            params.len()));
+      }
+      if params.len() == 2 {
+        match params[1] {
+          Evaluation::Exception(_) => { return params[1].clone(); },
+          Evaluation::List(_) => {
+            // filter list checked below
+          },
+          _ => {
+            return evaluator::exception(ExceptionType::TypeError, &id,
+                                        "second argument must be list of exception types".to_string());
+          },
+        }
+      }
+      match params[0] {
+        Evaluation::Exception(ref e) if e.flavor.is_fatal() => {
+          params[0].clone()
+        },
+        Evaluation::Exception(ref e) => {
+          if params.len() == 2 && !exception_in_list(e, &params[1]) {
+            // Not one of the types we're catching, so let it keep going
+            return params[0].clone();
+          }
+          exception_to_list(e)
+        },
+        ref eval => {
+          let mut list = ListEval::new();
+          list.push(Evaluation::String(Rc::from("ok")));
+          list.push(eval.clone());
+          Evaluation::List(list)
+        },
+      }
+    },
+    &Primitive::Raise => {
+      match params.len() {
+        1 => {
+          Evaluation::Exception(Exception { flavor: ExceptionType::Error,
+                                            payload: Box::new(params[0].clone()),
+                                            stack: Vec::new(),
+                                            cause: None })
+        },
+        2 => {
+          match params[0] {
+            Evaluation::String(ref s) if ExceptionType::is_reserved(s) => {
+              reserved(&id, s)
+            },
+            Evaluation::String(ref s) => {
+              Evaluation::Exception(Exception { flavor: ExceptionType::User(s.to_string()),
+                                                payload: Box::new(params[1].clone()),
+                                                stack: Vec::new(),
+                                                cause: None })
+            },
+            _ => evaluator::exception(ExceptionType::TypeError, &id,
+                                      "first argument must be string for exception type".to_string()),
+          }
+        },
+        n => evaluator::exception(ExceptionType::ArityError, &id,
+                                  format!("expected 1 or 2 arguments but got {}", n)),
+      }
+    },
+    &Primitive::Rethrow => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
+          let rc = match params[0] {
+            Evaluation::List(ref list) => list_to_exception(list),
+            _ => None,
+          };
+          match rc {
+            Some(e) => Evaluation::Exception(e),
+            None => evaluator::exception(ExceptionType::TypeError, &id,
+                                         "caught exception list expected".to_string()),
+          }
+        },
+      }
+    },
+    &Primitive::Wrap => {
+      match expect_args(3, &params, &id) {
+        Some(e) => e,
+        None => {
+          for p in &params[1..] {
+            if let &Evaluation::Exception(_) = p {
+              return p.clone();
+            }
+          }
           match params[0] {
-            Evaluation::Exception(ref e) => {
-              let mut list = ListEval { items: Vec::new() };
-              list.items.push(Evaluation::String(e.flavor.to_string()));
-              list.items.push(e.payload.clone());
-              let mut stack = ListEval { items: Vec::new() };
-              for s in &e.stack {
-                stack.items.push(Evaluation::String(s.clone()));
-              }
-              list.items.push(Evaluation::List(stack));
-              Evaluation::List(list)
-            },
-            ref eval => {
-              let mut list = ListEval { items: Vec::new() };
-              list.items.push(Evaluation::String("ok".to_string()));
-              list.items.push(eval.clone());
-              Evaluation::List(list)
+            Evaluation::Exception(ref cause) if cause.flavor.is_fatal() => {
+              params[0].clone()
+            },
+            Evaluation::Exception(ref cause) => {
+              match params[1] {
+                Evaluation::String(ref s) if ExceptionType::is_reserved(s) => {
+                  reserved(&id, s)
+                },
+                Evaluation::String(ref s) => {
+                  let mut e = Exception::new(&ExceptionType::User(s.to_string()),
+                                             &params[2]);
+                  e.cause = Some(Box::new(cause.clone()));
+                  Evaluation::Exception(e)
+                },
+                _ => evaluator::exception(ExceptionType::TypeError, &id,
+                                          "second argument must be string for exception type".to_string()),
+              }
+            },
+            ref eval => eval.clone(),
+          }
+        },
+      }
+    },
+    &Primitive::Ensure => {
+      match expect_args(2, &params, &id) {
+        Some(e) => e,
+        None => {
+          // Both arguments have been evaluated by now, in order, so the
+          // cleanup has already run whether or not the body failed
+          match params[1] {
+            Evaluation::Exception(ref e) if !e.flavor.is_fatal() => {
+              if let Evaluation::Exception(ref body) = params[0] {
+                if body.flavor.is_fatal() {
+                  return params[0].clone();
+                }
+              }
+              let mut rc = e.clone();
+              if let Evaluation::Exception(ref body) = params[0] {
+                if rc.cause.is_none() {
+                  rc.cause = Some(Box::new(body.clone()));
+                }
+              }
+              Evaluation::Exception(rc)
             },
+            Evaluation::Exception(_) => params[1].clone(),
+            _ => params[0].clone(),
           }
         },
       }
     },
-    "raise" => {
+    &Primitive::Exit => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
-          Evaluation::Exception(Exception { flavor: ExceptionType::Error,
-                                            payload: Box::new(params[0].clone()),
-                                            stack: Vec::new() })
+          match params[0] {
+            Evaluation::Integer(n) if !(0..=255).contains(&n) => {
+              evaluator::exception(ExceptionType::RuntimeError, &id,
+                                   format!("exit code {} is not between 0 and 255", n))
+            },
+            Evaluation::Integer(_) => {
+              Evaluation::Exception(Exception::new(&ExceptionType::Exit,
+                                                   &params[0]))
+            },
+            _ => evaluator::exception(ExceptionType::TypeError, &id,
+                                      "integer argument expected".to_string()),
+          }
         },
       }
     },
-    "~" => {
+    &Primitive::Return => {
       match expect_args(1, &params, &id) {
         Some(e) => e,
         None => {
           Evaluation::Exception(Exception { flavor: ExceptionType::Return,
                                             payload: Box::new(params[0].clone()),
-                                            stack: Vec::new() })
+                                            stack: Vec::new(),
+                                            cause: None })
         },
       }
     },
-    _ => evaluator::exception(ExceptionType::UndefError, &id,
-                              "function is not defined in scope".to_string()),
+    // I/O left out of the build, which check_capability has already reported
+    #[cfg(not(all(feature = "output", feature = "files", feature = "env",
+                  feature = "process")))]
+    _ => evaluator::exception(ExceptionType::PermissionError, &id,
+                              "not included in this build".to_string()),
   }
 }
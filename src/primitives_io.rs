@@ -0,0 +1,334 @@
+// I/O primitive functions, by capability group (see check_capability).  Each
+// group is only compiled in with its cargo feature
+
+#[cfg(feature = "env")]
+use std::env;
+#[cfg(feature = "files")]
+use std::fs;
+#[cfg(feature = "files")]
+use std::fs::OpenOptions;
+#[cfg(feature = "process")]
+use std::io;
+#[cfg(any(feature = "files", feature = "process"))]
+use std::io::Error;
+#[cfg(any(feature = "files", feature = "process"))]
+use std::io::prelude::*;
+#[cfg(feature = "process")]
+use std::process::Child;
+#[cfg(feature = "process")]
+use std::process::Command;
+#[cfg(feature = "process")]
+use std::process::Output;
+#[cfg(feature = "process")]
+use std::process::Stdio;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use std::rc::Rc;
+#[cfg(feature = "process")]
+use std::thread;
+#[cfg(feature = "process")]
+use std::thread::JoinHandle;
+#[cfg(feature = "process")]
+use std::time::Duration;
+#[cfg(feature = "process")]
+use std::time::Instant;
+
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use evaluator;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use primitives::expect_args;
+#[cfg(any(feature = "files", feature = "env"))]
+use primitives::Primitive;
+
+#[cfg(any(feature = "files", feature = "process"))]
+use encoding::Interpreter;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use encoding::Evaluation;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use encoding::ListEval;
+#[cfg(any(feature = "files", feature = "env", feature = "process"))]
+use encoding::ExceptionType;
+
+#[cfg(any(feature = "files", feature = "process"))]
+fn io_error(id: &String, path: &String, err: Error) -> Evaluation {
+  evaluator::exception(ExceptionType::IOError, id, format!("{}: {}", path, err))
+}
+
+#[cfg(feature = "files")]
+fn path_arg(id: &String, params: &Vec<Evaluation>) -> Result<String, Evaluation> {
+  match params[0] {
+    Evaluation::String(ref s) => Ok(s.to_string()),
+    _ => Err(evaluator::exception(ExceptionType::TypeError, id,
+                                  "first argument must be string for path".to_string())),
+  }
+}
+
+#[cfg(feature = "files")]
+fn content_arg(id: &String, params: &Vec<Evaluation>) ->
+  Result<String, Evaluation> {
+  match params[1] {
+    Evaluation::String(ref s) => Ok(s.to_string()),
+    _ => Err(evaluator::exception(ExceptionType::TypeError, id,
+                                  "second argument must be string".to_string())),
+  }
+}
+
+#[cfg(feature = "files")]
+pub fn files(op: &Primitive, id: &String, params: &Vec<Evaluation>,
+             interp: &mut Interpreter) -> Evaluation {
+  let count = match op {
+    &Primitive::WriteFile | &Primitive::AppendFile => 2,
+    _ => 1,
+  };
+  if let Some(e) = expect_args(count, params, id) {
+    return e;
+  }
+  let path = match path_arg(id, params) {
+    Ok(p) => p,
+    Err(e) => { return e; },
+  };
+  match op {
+    &Primitive::ReadFile => {
+      // Check the size limit before reading the whole file in
+      if let Ok(meta) = fs::metadata(&path) {
+        if let Some(e) = interp.reserve(meta.len() as usize) {
+          return e;
+        }
+      }
+      match fs::read_to_string(&path) {
+        Ok(s) => Evaluation::String(Rc::from(s)),
+        Err(err) => io_error(id, &path, err),
+      }
+    },
+    &Primitive::WriteFile | &Primitive::AppendFile => {
+      let content = match content_arg(id, params) {
+        Ok(c) => c,
+        Err(e) => { return e; },
+      };
+      let file = if let &Primitive::WriteFile = op {
+        OpenOptions::new().write(true).create(true).truncate(true).open(&path)
+      } else {
+        OpenOptions::new().append(true).create(true).open(&path)
+      };
+      match file.and_then(|mut f| f.write_all(content.as_bytes())) {
+        Ok(_) => Evaluation::Nil,
+        Err(err) => io_error(id, &path, err),
+      }
+    },
+    &Primitive::Exists => {
+      if fs::metadata(&path).is_ok() {
+        Evaluation::True
+      } else {
+        Evaluation::False
+      }
+    },
+    &Primitive::DeleteFile => {
+      match fs::remove_file(&path) {
+        Ok(_) => Evaluation::Nil,
+        Err(err) => io_error(id, &path, err),
+      }
+    },
+    &Primitive::ListDir => {
+      let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(err) => { return io_error(id, &path, err); },
+      };
+      let mut names = Vec::new();
+      for entry in entries {
+        match entry {
+          Ok(e) => names.push(e.file_name().to_string_lossy().into_owned()),
+          Err(err) => { return io_error(id, &path, err); },
+        }
+      }
+      // Directory order is up to the OS, so make it predictable
+      names.sort();
+      let mut list = ListEval::new();
+      for n in names {
+        list.push(Evaluation::String(Rc::from(n)));
+      }
+      Evaluation::List(list)
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
+
+#[cfg(feature = "env")]
+pub fn environment(op: &Primitive, id: &String, params: &Vec<Evaluation>) ->
+  Evaluation {
+  match op {
+    &Primitive::Getenv => {
+      if let Some(e) = expect_args(1, params, id) {
+        return e;
+      }
+      match params[0] {
+        Evaluation::String(ref name) => {
+          match env::var(&**name) {
+            Ok(value) => Evaluation::String(Rc::from(value)),
+            Err(_) => Evaluation::Nil,
+          }
+        },
+        _ => evaluator::exception(ExceptionType::TypeError, id,
+                                  "string argument expected".to_string()),
+      }
+    },
+    &Primitive::Env => {
+      if let Some(e) = expect_args(0, params, id) {
+        return e;
+      }
+      let mut vars: Vec<(String, String)> = env::vars().collect();
+      vars.sort();
+      let mut list = ListEval::new();
+      for (name, value) in vars {
+        let mut pair = ListEval::new();
+        pair.push(Evaluation::String(Rc::from(name)));
+        pair.push(Evaluation::String(Rc::from(value)));
+        list.push(Evaluation::List(pair));
+      }
+      Evaluation::List(list)
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
+
+// What a pipe from the child has to say, read on another thread, stopping one
+// byte past the size limit so that going over it shows
+#[cfg(feature = "process")]
+fn read_all<R: Read + Send + 'static>(pipe: R, limit: usize) ->
+  JoinHandle<Vec<u8>> {
+  thread::spawn(move || {
+    let mut rc = Vec::new();
+    let _ = pipe.take((limit as u64).saturating_add(1)).read_to_end(&mut rc);
+    rc
+  })
+}
+
+// Child::wait_with_output, except that a child still running at the deadline
+// is killed, giving None, and so is one that goes over the size limit on
+// either pipe, giving what it said up to there for the caller to reject
+#[cfg(feature = "process")]
+fn wait_until(mut child: Child, deadline: Option<Instant>, limit: usize) ->
+  io::Result<Option<Output>> {
+  let mut readers = [child.stdout.take().map(|p| read_all(p, limit)),
+                     child.stderr.take().map(|p| read_all(p, limit))];
+  let mut read = [Vec::new(), Vec::new()];
+  loop {
+    for (r, bytes) in readers.iter_mut().zip(read.iter_mut()) {
+      if r.as_ref().is_some_and(|r| r.is_finished()) {
+        *bytes = r.take().and_then(|r| r.join().ok()).unwrap_or_default();
+      }
+    }
+    if read.iter().any(|bytes| bytes.len() > limit) {
+      let _ = child.kill();
+      let status = child.wait()?;
+      let [stdout, stderr] = read;
+      return Ok(Some(Output { status: status, stdout: stdout,
+                              stderr: stderr }));
+    }
+    if let Some(status) = child.try_wait()? {
+      for (r, bytes) in readers.iter_mut().zip(read.iter_mut()) {
+        if let Some(r) = r.take() {
+          *bytes = r.join().unwrap_or_default();
+        }
+      }
+      let [stdout, stderr] = read;
+      return Ok(Some(Output { status: status, stdout: stdout,
+                              stderr: stderr }));
+    }
+    if deadline.is_some_and(|d| Instant::now() >= d) {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Ok(None);
+    }
+    thread::sleep(Duration::from_millis(5));
+  }
+}
+
+#[cfg(feature = "process")]
+pub fn process(id: &String, params: &Vec<Evaluation>,
+               interp: &mut Interpreter) -> Evaluation {
+  if let Some(e) = expect_args(3, params, id) {
+    return e;
+  }
+  let cmd = match params[0] {
+    Evaluation::String(ref s) => s.to_string(),
+    _ => {
+      return evaluator::exception(ExceptionType::TypeError, id,
+                                  "first argument must be string for command".to_string());
+    },
+  };
+  let mut args = Vec::new();
+  match params[1] {
+    Evaluation::Nil => {
+      // no arguments
+    },
+    Evaluation::List(ref list) => {
+      for a in list.iter() {
+        match a {
+          &Evaluation::String(ref s) => args.push(s.to_string()),
+          _ => {
+            return evaluator::exception(ExceptionType::TypeError, id,
+                                        "second argument must be list of strings".to_string());
+          },
+        }
+      }
+    },
+    _ => {
+      return evaluator::exception(ExceptionType::TypeError, id,
+                                  "second argument must be list of strings".to_string());
+    },
+  }
+  let input = match params[2] {
+    Evaluation::Nil => None,
+    Evaluation::String(ref s) => Some(s.to_string()),
+    _ => {
+      return evaluator::exception(ExceptionType::TypeError, id,
+                                  "third argument must be string or nil for stdin".to_string());
+    },
+  };
+
+  let child = Command::new(&cmd).args(&args)
+    .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+    .stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+  let mut child = match child {
+    Ok(c) => c,
+    Err(err) => { return io_error(id, &cmd, err); },
+  };
+  // Feed stdin from another thread so a chatty child can't deadlock us by
+  // filling up its stdout before it's done reading
+  let writer = match (child.stdin.take(), input) {
+    (Some(mut stdin), Some(s)) => {
+      Some(thread::spawn(move || stdin.write_all(s.as_bytes())))
+    },
+    _ => None,
+  };
+  let output = match (interp.deadline, interp.max_size) {
+    (None, None) => child.wait_with_output().map(Some),
+    (deadline, limit) => wait_until(child, deadline,
+                                    limit.unwrap_or(usize::MAX)),
+  };
+  let output = match output {
+    Ok(Some(o)) => o,
+    Ok(None) => {
+      interp.terminated = Some(ExceptionType::Timeout);
+      return interp.termination();
+    },
+    Err(err) => { return io_error(id, &cmd, err); },
+  };
+  if let Some(w) = writer {
+    // A child that exits without reading all of its input is fine
+    let _ = w.join();
+  }
+  for o in &[&output.stdout, &output.stderr] {
+    if let Some(e) = interp.reserve(o.len()) {
+      return e;
+    }
+  }
+
+  let mut list = ListEval::new();
+  // No exit code means the process was killed by a signal
+  list.push(Evaluation::Integer(output.status.code().unwrap_or(-1) as i64));
+  list.push(Evaluation::String(Rc::from(String::from_utf8_lossy(&output.stdout))));
+  list.push(Evaluation::String(Rc::from(String::from_utf8_lossy(&output.stderr))));
+  Evaluation::List(list)
+}
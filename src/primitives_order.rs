@@ -0,0 +1,231 @@
+// Ordering of values and the sorting primitives built on it.  Every pair of
+// values has an order: within a type the obvious one (strings by character,
+// lists element by element), and across types nil < booleans < numbers <
+// strings < lists < functions < exceptions
+
+use std::cmp::Ordering;
+
+use evaluator;
+use primitives::expect_args;
+use primitives::Primitive;
+use symbols;
+
+use encoding::Interpreter;
+use encoding::Evaluation;
+use encoding::ListEval;
+use encoding::Function;
+use encoding::ExceptionType;
+
+fn rank(value: &Evaluation) -> u8 {
+  match value {
+    &Evaluation::Nil => 0,
+    &Evaluation::False => 1,
+    &Evaluation::True => 2,
+    &Evaluation::Integer(_) | &Evaluation::Float(_) => 3,
+    &Evaluation::String(_) => 4,
+    &Evaluation::List(_) => 5,
+    &Evaluation::Function(_) => 6,
+    &Evaluation::Exception(_) => 7,
+  }
+}
+
+// NaN goes after every other number so that floats still have an order
+fn float_order(x: f64, y: f64) -> Ordering {
+  match x.partial_cmp(&y) {
+    Some(o) => o,
+    None => x.is_nan().cmp(&y.is_nan()),
+  }
+}
+
+// An integer against a float by their exact values, since converting either
+// one to the other's type can round (integers above 2^53, say)
+fn mixed_order(x: i64, y: f64) -> Ordering {
+  // 2^63, the first float past every integer
+  let limit = 9223372036854775808.0;
+  if y.is_nan() || y >= limit {
+    return Ordering::Less;
+  } else if y < -limit {
+    return Ordering::Greater;
+  }
+  let whole = y.trunc();
+  x.cmp(&(whole as i64)).then_with(|| float_order(whole, y))
+}
+
+pub fn order(a: &Evaluation, b: &Evaluation) -> Ordering {
+  match (a, b) {
+    (&Evaluation::Integer(x), &Evaluation::Integer(y)) => x.cmp(&y),
+    (&Evaluation::Float(x), &Evaluation::Float(y)) => float_order(x, y),
+    // Equal integers and floats differ as far as = goes, integers go first
+    (&Evaluation::Integer(x), &Evaluation::Float(y)) => {
+      mixed_order(x, y).then(Ordering::Less)
+    },
+    (&Evaluation::Float(x), &Evaluation::Integer(y)) => {
+      mixed_order(y, x).reverse().then(Ordering::Greater)
+    },
+    (&Evaluation::String(ref x), &Evaluation::String(ref y)) => x.cmp(y),
+    (&Evaluation::List(ref x), &Evaluation::List(ref y)) => {
+      for (i, j) in x.iter().zip(y.iter()) {
+        let o = order(i, j);
+        if o != Ordering::Equal {
+          return o;
+        }
+      }
+      x.len().cmp(&y.len())
+    },
+    (&Evaluation::Exception(ref x), &Evaluation::Exception(ref y)) => {
+      x.flavor.to_string().cmp(&y.flavor.to_string())
+        .then_with(|| order(&x.payload, &y.payload))
+    },
+    // Functions have no useful order, so they're all alike
+    _ => rank(a).cmp(&rank(b)),
+  }
+}
+
+fn ordering_value(o: Ordering) -> Evaluation {
+  match o {
+    Ordering::Less => Evaluation::Integer(-1),
+    Ordering::Equal => Evaluation::Integer(0),
+    Ordering::Greater => Evaluation::Integer(1),
+  }
+}
+
+// Stable merge sort with a comparison that can fail (i.e., a user function
+// returning an exception), which also doesn't care whether the comparison is
+// consistent
+fn merge_sort<F>(items: Vec<Evaluation>, cmp: &mut F) ->
+  Result<Vec<Evaluation>, Evaluation>
+  where F: FnMut(&Evaluation, &Evaluation) -> Result<Ordering, Evaluation> {
+  if items.len() < 2 {
+    return Ok(items);
+  }
+  let mut left = items;
+  let right = left.split_off(left.len() / 2);
+  let left = merge_sort(left, cmp)?;
+  let right = merge_sort(right, cmp)?;
+  let mut rc = Vec::with_capacity(left.len() + right.len());
+  let mut left = left.into_iter().peekable();
+  let mut right = right.into_iter().peekable();
+  loop {
+    let take_left = match (left.peek(), right.peek()) {
+      (Some(l), Some(r)) => cmp(l, r)? != Ordering::Greater,
+      (Some(_), None) => true,
+      (None, Some(_)) => false,
+      (None, None) => break,
+    };
+    if take_left {
+      rc.extend(left.next());
+    } else {
+      rc.extend(right.next());
+    }
+  }
+  Ok(rc)
+}
+
+fn list_arg(id: &String, params: &Vec<Evaluation>) ->
+  Result<Vec<Evaluation>, Evaluation> {
+  match params[0] {
+    // nil being what cdr leaves at the end of a list
+    Evaluation::Nil => Ok(Vec::new()),
+    Evaluation::List(ref list) => Ok(list.iter().map(|i| i.clone()).collect()),
+    _ => Err(evaluator::exception(ExceptionType::TypeError, id,
+                                  "list expected for argument 1".to_string())),
+  }
+}
+
+fn sorted(result: Result<Vec<Evaluation>, Evaluation>) -> Evaluation {
+  match result {
+    Ok(items) => Evaluation::List(ListEval::from_items(items)),
+    Err(e) => e,
+  }
+}
+
+// A comparator's result, either a number compared to zero like compare gives
+fn comparison(id: &String, value: Evaluation) -> Result<Ordering, Evaluation> {
+  match value {
+    Evaluation::Integer(x) => Ok(x.cmp(&0)),
+    Evaluation::Float(x) if !x.is_nan() => Ok(float_order(x, 0.0)),
+    Evaluation::Exception(_) => Err(value),
+    _ => Err(evaluator::exception(ExceptionType::TypeError, id,
+                                  "comparison function must return a number".to_string())),
+  }
+}
+
+fn sort_by(id: &String, items: Vec<Evaluation>, func: &Function,
+           interp: &mut Interpreter) -> Evaluation {
+  let context = symbols::intern(id);
+  match func.params.len() {
+    // Key function: call it once per item, then sort by the keys
+    1 => {
+      let mut keyed = Vec::new();
+      for i in items {
+        let key = func.apply(vec![i.clone()], interp, context);
+        if let Evaluation::Exception(_) = key {
+          return key;
+        }
+        let mut pair = ListEval::new();
+        pair.push(key);
+        pair.push(i);
+        keyed.push(Evaluation::List(pair));
+      }
+      let result = merge_sort(keyed, &mut |a, b| {
+        match (a, b) {
+          (&Evaluation::List(ref x), &Evaluation::List(ref y)) => {
+            Ok(order(&x[0], &y[0]))
+          },
+          _ => Ok(Ordering::Equal),
+        }
+      });
+      sorted(result.map(|pairs| {
+        pairs.into_iter().map(|p| {
+          match p {
+            Evaluation::List(pair) => pair[1].clone(),
+            _ => p,
+          }
+        }).collect()
+      }))
+    },
+    // Comparison function, like compare
+    2 => {
+      sorted(merge_sort(items, &mut |a, b| {
+        let rc = func.apply(vec![a.clone(), b.clone()], interp, context);
+        comparison(id, rc)
+      }))
+    },
+    _ => evaluator::exception(ExceptionType::ArityError, id,
+                              "function must take 1 argument (key) or 2 (comparison)".to_string()),
+  }
+}
+
+pub fn ordering(op: &Primitive, id: &String, params: &Vec<Evaluation>,
+                interp: &mut Interpreter) ->
+  Evaluation {
+  let count = match op {
+    &Primitive::Sort => 1,
+    _ => 2,
+  };
+  if let Some(e) = expect_args(count, params, id) {
+    return e;
+  }
+  match op {
+    &Primitive::Compare => ordering_value(order(&params[0], &params[1])),
+    &Primitive::Sort => {
+      match list_arg(id, params) {
+        Ok(items) => sorted(merge_sort(items, &mut |a, b| Ok(order(a, b)))),
+        Err(e) => e,
+      }
+    },
+    &Primitive::SortBy => {
+      let items = match list_arg(id, params) {
+        Ok(items) => items,
+        Err(e) => { return e; },
+      };
+      match params[1] {
+        Evaluation::Function(ref func) => sort_by(id, items, func, interp),
+        _ => evaluator::exception(ExceptionType::TypeError, id,
+                                  "function expected for argument 2".to_string()),
+      }
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
@@ -0,0 +1,577 @@
+// String primitive functions.  Like substr and strlen, anything involving
+// positions or lengths counts characters, not bytes
+
+use std::char;
+use std::rc::Rc;
+
+use evaluator;
+use primitives::expect_args;
+use primitives::Primitive;
+use symbols;
+
+use regex::Regex;
+use regex::Captures;
+
+use encoding::Interpreter;
+use encoding::Evaluation;
+use encoding::ListEval;
+use encoding::ExceptionType;
+
+fn string_arg(id: &String, params: &Vec<Evaluation>, n: usize) ->
+  Result<String, Evaluation> {
+  match params[n] {
+    Evaluation::String(ref s) => Ok(s.to_string()),
+    _ => Err(evaluator::exception(ExceptionType::TypeError, id,
+                                  format!("string expected for argument {}",
+                                          n + 1))),
+  }
+}
+
+fn string_list(items: Vec<String>) -> Evaluation {
+  let mut list = ListEval::new();
+  for i in items {
+    list.push(Evaluation::String(Rc::from(i)));
+  }
+  Evaluation::List(list)
+}
+
+fn boolean(b: bool) -> Evaluation {
+  if b {
+    Evaluation::True
+  } else {
+    Evaluation::False
+  }
+}
+
+pub fn strings(op: &Primitive, id: &String, params: &Vec<Evaluation>,
+               interp: &mut Interpreter) ->
+  Evaluation {
+  let count = match op {
+    &Primitive::Replace => 3,
+    &Primitive::Split | &Primitive::Join | &Primitive::Find |
+    &Primitive::Contains | &Primitive::StartsWith | &Primitive::EndsWith |
+    &Primitive::Repeat => 2,
+    _ => 1,
+  };
+  if let Some(e) = expect_args(count, params, id) {
+    return e;
+  }
+
+  // The odd ones out that don't take a string first
+  match op {
+    &Primitive::Join => {
+      let sep = match string_arg(id, params, 1) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      let mut items = Vec::new();
+      match params[0] {
+        // nil being what cdr leaves at the end of a list
+        Evaluation::Nil => {},
+        Evaluation::List(ref list) => {
+          for i in list.iter() {
+            match i {
+              &Evaluation::String(ref s) => items.push(s.clone()),
+              _ => {
+                return evaluator::exception(ExceptionType::TypeError, id,
+                                            "list of strings expected for argument 1".to_string());
+              },
+            }
+          }
+        },
+        _ => {
+          return evaluator::exception(ExceptionType::TypeError, id,
+                                      "list of strings expected for argument 1".to_string());
+        },
+      }
+      // The result could be far bigger than the size limit, so check first
+      let size = items.iter().map(|s| s.len()).sum::<usize>() +
+        sep.len() * items.len().saturating_sub(1);
+      if let Some(e) = interp.reserve(size) {
+        return e;
+      }
+      return Evaluation::String(Rc::from(items.join(&sep)));
+    },
+    &Primitive::Chr => {
+      return match params[0] {
+        Evaluation::Integer(n) if n >= 0 && n <= u32::max_value() as i64 => {
+          match char::from_u32(n as u32) {
+            Some(c) => Evaluation::String(Rc::from(c.to_string())),
+            None => evaluator::exception(ExceptionType::RuntimeError, id,
+                                         format!("{} is not a valid character", n)),
+          }
+        },
+        Evaluation::Integer(n) => {
+          evaluator::exception(ExceptionType::RuntimeError, id,
+                               format!("{} is not a valid character", n))
+        },
+        _ => evaluator::exception(ExceptionType::TypeError, id,
+                                  "integer argument expected".to_string()),
+      };
+    },
+    _ => {},
+  }
+
+  let s = match string_arg(id, params, 0) {
+    Ok(s) => s,
+    Err(e) => { return e; },
+  };
+  match op {
+    &Primitive::Split => {
+      let sep = match string_arg(id, params, 1) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      if sep.is_empty() {
+        return evaluator::exception(ExceptionType::RuntimeError, id,
+                                    "cannot split on empty string (use chars)".to_string());
+      }
+      string_list(s.split(&*sep).map(|p| p.to_string()).collect())
+    },
+    &Primitive::Find => {
+      let sub = match string_arg(id, params, 1) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      match s.find(&*sub) {
+        Some(byte) => Evaluation::Integer(s[..byte].chars().count() as i64),
+        None => Evaluation::Nil,
+      }
+    },
+    &Primitive::Contains | &Primitive::StartsWith | &Primitive::EndsWith => {
+      let sub = match string_arg(id, params, 1) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      boolean(match op {
+        &Primitive::Contains => s.contains(&*sub),
+        &Primitive::StartsWith => s.starts_with(&*sub),
+        _ => s.ends_with(&*sub),
+      })
+    },
+    &Primitive::Replace => {
+      let from = match string_arg(id, params, 1) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      let to = match string_arg(id, params, 2) {
+        Ok(s) => s,
+        Err(e) => { return e; },
+      };
+      if from.is_empty() {
+        return evaluator::exception(ExceptionType::RuntimeError, id,
+                                    "cannot replace empty string".to_string());
+      }
+      // Every match could be replaced by something as long as the string, so
+      // check the size first
+      let count = s.matches(&*from).count();
+      let size = (s.len() - count * from.len()).saturating_add(
+        count.saturating_mul(to.len()));
+      if let Some(e) = interp.reserve(size) {
+        return e;
+      }
+      Evaluation::String(Rc::from(s.replace(&*from, &to)))
+    },
+    &Primitive::Trim => Evaluation::String(Rc::from(s.trim())),
+    &Primitive::Upper => Evaluation::String(Rc::from(s.to_uppercase())),
+    &Primitive::Lower => Evaluation::String(Rc::from(s.to_lowercase())),
+    &Primitive::Chars => string_list(s.chars().map(|c| c.to_string()).collect()),
+    &Primitive::Ord => {
+      let mut chars = s.chars();
+      match (chars.next(), chars.next()) {
+        (Some(c), None) => Evaluation::Integer(c as i64),
+        _ => evaluator::exception(ExceptionType::RuntimeError, id,
+                                  "string of exactly one character expected".to_string()),
+      }
+    },
+    &Primitive::Repeat => {
+      match params[1] {
+        Evaluation::Integer(n) if n < 0 => {
+          evaluator::exception(ExceptionType::RuntimeError, id,
+                               "repeat count cannot be negative".to_string())
+        },
+        Evaluation::Integer(n) => {
+          match s.len().checked_mul(n as usize) {
+            Some(size) => {
+              if let Some(e) = interp.reserve(size) {
+                return e;
+              }
+              Evaluation::String(Rc::from(s.repeat(n as usize)))
+            },
+            None => evaluator::exception(ExceptionType::RuntimeError, id,
+                                         "repeated string is too long".to_string()),
+          }
+        },
+        _ => evaluator::exception(ExceptionType::TypeError, id,
+                                  "integer expected for argument 2".to_string()),
+      }
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
+
+// A parsed {index:spec} placeholder for format
+struct Placeholder {
+  index: Option<usize>,
+  fill: char,
+  align: Option<char>,
+  zero: bool,
+  width: usize,
+  precision: Option<usize>
+}
+
+fn parse_placeholder(inner: &str) -> Option<Placeholder> {
+  let (index, spec) = match inner.find(':') {
+    Some(n) => (&inner[..n], &inner[n + 1..]),
+    None => (inner, ""),
+  };
+  let mut p = Placeholder { index: None, fill: ' ', align: None, zero: false,
+                            width: 0, precision: None };
+  if !index.is_empty() {
+    match index.parse::<usize>() {
+      Ok(n) => p.index = Some(n),
+      _ => { return None; },
+    }
+  }
+  let chars: Vec<char> = spec.chars().collect();
+  let mut i = 0;
+  let is_align = |c: char| c == '<' || c == '>' || c == '^';
+  if chars.len() >= 2 && is_align(chars[1]) {
+    p.fill = chars[0];
+    p.align = Some(chars[1]);
+    i = 2;
+  } else if chars.len() >= 1 && is_align(chars[0]) {
+    p.align = Some(chars[0]);
+    i = 1;
+  }
+  if p.align.is_none() && i < chars.len() && chars[i] == '0' {
+    p.zero = true;
+    i += 1;
+  }
+  let start = i;
+  while i < chars.len() && chars[i].is_digit(10) {
+    i += 1;
+  }
+  if i > start {
+    p.width = chars[start..i].iter().collect::<String>().parse().ok()?;
+  }
+  if i < chars.len() && chars[i] == '.' {
+    i += 1;
+    let start = i;
+    while i < chars.len() && chars[i].is_digit(10) {
+      i += 1;
+    }
+    if i == start {
+      return None;
+    }
+    p.precision = Some(chars[start..i].iter().collect::<String>().parse().ok()?);
+  }
+  if i < chars.len() {
+    return None;
+  }
+  Some(p)
+}
+
+// Add a value to out as string() shows it, a piece at a time so that the
+// size and time limits can stop it partway: lists share structure, so a small
+// one can show as something enormous
+pub fn show(value: &Evaluation, out: &mut String, interp: &mut Interpreter) ->
+  Result<(), Evaluation> {
+  match value {
+    &Evaluation::List(ref list) => {
+      out.push('[');
+      for (n, i) in list.iter().enumerate() {
+        if n > 0 {
+          out.push_str(", ");
+        }
+        show(i, out, interp)?;
+      }
+      out.push(']');
+    },
+    &Evaluation::Exception(ref e) => {
+      *out += &format!("[{}, ", e.flavor);
+      show(&e.payload, out, interp)?;
+      out.push_str(", ");
+      let stack: Vec<String> = e.stack.iter()
+        .map(|s| symbols::name(*s).to_string()).collect();
+      *out += &stack.join(", ");
+      out.push_str("]]");
+    },
+    _ => *out += &format!("{}", value),
+  }
+  if let Some(e) = interp.reserve(out.len()) {
+    return Err(e);
+  }
+  match interp.check_deadline() {
+    Some(e) => Err(e),
+    None => Ok(()),
+  }
+}
+
+// Values are rendered as by string(), except that strings go in as they are
+fn render(value: &Evaluation, p: &Placeholder, interp: &mut Interpreter) ->
+  Result<String, Evaluation> {
+  let text = match (value, p.precision) {
+    (&Evaluation::Float(x), Some(n)) => format!("{:.*}", n, x),
+    (&Evaluation::String(ref s), Some(n)) => s.chars().take(n).collect(),
+    (&Evaluation::String(ref s), None) => s.to_string(),
+    _ => {
+      let mut text = String::new();
+      show(value, &mut text, interp)?;
+      text
+    },
+  };
+  let len = text.chars().count();
+  if len >= p.width {
+    return Ok(text);
+  }
+  let pad = p.width - len;
+  let numeric = match value {
+    &Evaluation::Integer(_) | &Evaluation::Float(_) => true,
+    _ => false,
+  };
+  if p.zero && numeric {
+    // Zeros go after the sign
+    return Ok(if text.starts_with('-') {
+      format!("-{}{}", "0".repeat(pad), &text[1..])
+    } else {
+      format!("{}{}", "0".repeat(pad), text)
+    });
+  }
+  let fill = p.fill.to_string();
+  Ok(match p.align.unwrap_or(if numeric { '>' } else { '<' }) {
+    '>' => format!("{}{}", fill.repeat(pad), text),
+    '^' => format!("{}{}{}", fill.repeat(pad / 2), text,
+                   fill.repeat(pad - pad / 2)),
+    _ => format!("{}{}", text, fill.repeat(pad)),
+  })
+}
+
+pub fn format(id: &String, params: &Vec<Evaluation>,
+              interp: &mut Interpreter) -> Evaluation {
+  if params.len() < 1 {
+    return evaluator::exception(ExceptionType::ArityError, id,
+                                "expected at least 1 argument but got 0".to_string());
+  }
+  let template: Vec<char> = match string_arg(id, params, 0) {
+    Ok(s) => s.chars().collect(),
+    Err(e) => { return e; },
+  };
+  let args = &params[1..];
+  let mut rc = String::new();
+  let mut next = 0;
+  let mut i = 0;
+  while i < template.len() {
+    let c = template[i];
+    if (c == '{' || c == '}') && i + 1 < template.len() && template[i + 1] == c {
+      rc.push(c);
+      i += 2;
+    } else if c == '}' {
+      return evaluator::exception(ExceptionType::ParseError, id,
+                                  "unmatched } in format string".to_string());
+    } else if c == '{' {
+      let end = match template[i + 1..].iter().position(|&x| x == '}') {
+        Some(n) => i + 1 + n,
+        None => {
+          return evaluator::exception(ExceptionType::ParseError, id,
+                                      "unmatched { in format string".to_string());
+        },
+      };
+      let inner: String = template[i + 1..end].iter().collect();
+      let p = match parse_placeholder(&inner) {
+        Some(p) => p,
+        None => {
+          return evaluator::exception(ExceptionType::ParseError, id,
+                                      format!("bad placeholder: {{{}}}", inner));
+        },
+      };
+      let n = match p.index {
+        Some(n) => n,
+        None => {
+          next += 1;
+          next - 1
+        },
+      };
+      if n >= args.len() {
+        return evaluator::exception(ExceptionType::ArityError, id,
+                                    format!("no argument {} for format string", n));
+      }
+      // Padding, and the digits of a float's precision, could otherwise
+      // allocate far more than the size limit
+      let size = match (&args[n], p.precision) {
+        (&Evaluation::Float(_), Some(precision)) => p.width.max(precision),
+        _ => p.width,
+      };
+      if let Some(e) = interp.reserve(size) {
+        return e;
+      }
+      match render(&args[n], &p, interp) {
+        Ok(text) => rc += &text,
+        Err(e) => { return e; },
+      }
+      if let Some(e) = interp.reserve(rc.len()) {
+        return e;
+      }
+      i = end + 1;
+    } else {
+      rc.push(c);
+      i += 1;
+    }
+  }
+  Evaluation::String(Rc::from(rc))
+}
+
+// The pieces of an interpolated string joined up, each rendered as format's
+// {} would
+pub fn interpolate(params: &Vec<Evaluation>, interp: &mut Interpreter) ->
+  Evaluation {
+  let mut rc = String::new();
+  for p in params {
+    if let &Evaluation::String(ref s) = p {
+      rc += s;
+      if let Some(e) = interp.reserve(rc.len()) {
+        return e;
+      }
+    } else if let Err(e) = show(p, &mut rc, interp) {
+      return e;
+    }
+  }
+  Evaluation::String(Rc::from(rc))
+}
+
+// Captures as a list of the whole match followed by each group, with nil for
+// groups that didn't take part in the match
+fn captures_list(input: &[char], caps: &Captures) -> Evaluation {
+  let mut list = ListEval::new();
+  for c in caps {
+    match c {
+      &Some((a, b)) => {
+        list.push(Evaluation::String(Rc::from(input[a..b].iter().collect::<String>())));
+      },
+      &None => list.push(Evaluation::Nil),
+    }
+  }
+  Evaluation::List(list)
+}
+
+// Replacement text with $0-$9 filled in from the captures, $$ being a $,
+// added to rc, stopping early once rc is longer than max (since every $0
+// could be as long as the input)
+fn expand(replacement: &[char], input: &[char], caps: &Captures,
+          rc: &mut String, max: usize) {
+  let mut i = 0;
+  while i < replacement.len() && rc.len() <= max {
+    let c = replacement[i];
+    if c == '$' && i + 1 < replacement.len() {
+      let n = replacement[i + 1];
+      if n == '$' {
+        rc.push('$');
+        i += 2;
+        continue;
+      }
+      if let Some(g) = n.to_digit(10) {
+        if let Some(&Some((a, b))) = caps.get(g as usize) {
+          rc.extend(&input[a..b]);
+        }
+        i += 2;
+        continue;
+      }
+    }
+    rc.push(c);
+    i += 1;
+  }
+}
+
+// The re_* primitives check the time limit between matches, as there can be
+// as many of them as characters in the input
+pub fn regex(op: &Primitive, id: &String, params: &Vec<Evaluation>,
+             interp: &mut Interpreter) -> Evaluation {
+  let count = match op {
+    &Primitive::ReReplace => 3,
+    _ => 2,
+  };
+  if let Some(e) = expect_args(count, params, id) {
+    return e;
+  }
+  let re = match string_arg(id, params, 0) {
+    Ok(p) => {
+      match Regex::new(&p) {
+        Ok(re) => re,
+        Err(msg) => {
+          return evaluator::exception(ExceptionType::ParseError, id,
+                                      format!("bad pattern: {}", msg));
+        },
+      }
+    },
+    Err(e) => { return e; },
+  };
+  let input: Vec<char> = match string_arg(id, params, 1) {
+    Ok(s) => s.chars().collect(),
+    Err(e) => { return e; },
+  };
+
+  match op {
+    &Primitive::ReMatch => {
+      match re.find_at(&input, 0) {
+        Some(caps) => captures_list(&input, &caps),
+        None => Evaluation::Nil,
+      }
+    },
+    &Primitive::ReFindAll => {
+      let mut list = ListEval::new();
+      for caps in re.find_all(&input) {
+        if let Some(e) = interp.check_deadline() {
+          return e;
+        }
+        list.push(captures_list(&input, &caps));
+      }
+      Evaluation::List(list)
+    },
+    &Primitive::ReReplace => {
+      let replacement: Vec<char> = match string_arg(id, params, 2) {
+        Ok(s) => s.chars().collect(),
+        Err(e) => { return e; },
+      };
+      let max = interp.max_size.unwrap_or(usize::MAX);
+      let mut rc = String::new();
+      let mut last = 0;
+      for caps in re.find_all(&input) {
+        if let Some(e) = interp.check_deadline() {
+          return e;
+        }
+        if let Some((a, b)) = caps[0] {
+          rc.extend(&input[last..a]);
+          expand(&replacement, &input, &caps, &mut rc, max);
+          if let Some(e) = interp.reserve(rc.len()) {
+            return e;
+          }
+          last = b;
+        }
+      }
+      rc.extend(&input[last..]);
+      Evaluation::String(Rc::from(rc))
+    },
+    &Primitive::ReSplit => {
+      let mut pieces = Vec::new();
+      let mut last = 0;
+      for caps in re.find_all(&input) {
+        if let Some(e) = interp.check_deadline() {
+          return e;
+        }
+        if let Some((a, b)) = caps[0] {
+          // an empty match at either end doesn't split anything off
+          if b == 0 || a == input.len() {
+            continue;
+          }
+          pieces.push(input[last..a].iter().collect());
+          last = b;
+        }
+      }
+      pieces.push(input[last..].iter().collect());
+      string_list(pieces)
+    },
+    _ => evaluator::exception(ExceptionType::UndefError, id,
+                              "function is not defined in scope".to_string()),
+  }
+}
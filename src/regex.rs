@@ -0,0 +1,624 @@
+// Small regular expression engine for the re_* primitives.
+//
+// Patterns are parsed into a tree, compiled into instructions for a
+// backtracking machine, and run over the characters of a string.  The machine
+// never tries the same instruction at the same position twice, which keeps
+// matching time proportional to pattern size times string length (and stops
+// things like (a*)* from looping forever).
+//
+// Supported: literals, ., [...] and [^...] classes with ranges, \d \w \s (and
+// \D \W \S outside of classes), ^ and $ (start and end of the whole string),
+// \b and \B, | alternation, (...) capturing and (?:...) non-capturing groups,
+// and *, +, ?, {n}, {n,}, {n,m} quantifiers, which are lazy with a trailing ?
+
+// Keep counted repeats from blowing up the compiled program, groups and
+// repeats from nesting deeper than parsing and compiling can recurse, and the
+// whole program (repeats of repeats multiply) to a sensible size
+const MAX_REPEAT: usize = 1000;
+const MAX_NESTING: usize = 100;
+const MAX_PROGRAM: usize = 10000;
+
+enum Node {
+  Empty, Char(char), Any, Class(Vec<(char, char)>, bool), Start, End,
+  Boundary(bool), Group(Box<Node>, Option<usize>), Concat(Vec<Node>),
+  Alt(Vec<Node>), Repeat(Box<Node>, usize, Option<usize>, bool)
+}
+
+enum Inst {
+  Char(char), Any, Class(Vec<(char, char)>, bool), Start, End, Boundary(bool),
+  Split(usize, usize), Jmp(usize), Save(usize), Match
+}
+
+pub struct Regex {
+  prog: Vec<Inst>,
+  groups: usize
+}
+
+// Capture positions (in characters) for the whole match and each group, the
+// whole match always being present
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+struct Parser {
+  chars: Vec<char>,
+  index: usize,
+  groups: usize,
+  // Groups and repeats around what's being parsed
+  depth: usize
+}
+
+impl Parser {
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.index).cloned()
+  }
+
+  fn parse_alt(&mut self) -> Result<Node, String> {
+    let mut alts = vec![self.parse_concat()?];
+    while self.peek() == Some('|') {
+      self.index += 1;
+      alts.push(self.parse_concat()?);
+    }
+    if alts.len() == 1 {
+      Ok(alts.remove(0))
+    } else {
+      Ok(Node::Alt(alts))
+    }
+  }
+
+  fn parse_concat(&mut self) -> Result<Node, String> {
+    let mut nodes = Vec::new();
+    loop {
+      match self.peek() {
+        None | Some('|') | Some(')') => break,
+        _ => nodes.push(self.parse_repeat()?),
+      }
+    }
+    match nodes.len() {
+      0 => Ok(Node::Empty),
+      1 => Ok(nodes.remove(0)),
+      _ => Ok(Node::Concat(nodes)),
+    }
+  }
+
+  fn parse_repeat(&mut self) -> Result<Node, String> {
+    let mut node = self.parse_atom()?;
+    let depth = self.depth;
+    loop {
+      let (min, max) = match self.peek() {
+        Some('*') => { self.index += 1; (0, None) },
+        Some('+') => { self.index += 1; (1, None) },
+        Some('?') => { self.index += 1; (0, Some(1)) },
+        Some('{') => {
+          match self.parse_count() {
+            Some(range) => range,
+            // not a count after all, so it's a literal { for the next atom
+            None => break,
+          }
+        },
+        _ => break,
+      };
+      if let Some(m) = max {
+        if m < min {
+          return Err("repeat maximum is less than minimum".to_string());
+        }
+      }
+      if min > MAX_REPEAT || max.unwrap_or(0) > MAX_REPEAT {
+        return Err(format!("repeat count over {}", MAX_REPEAT));
+      }
+      let greedy = if self.peek() == Some('?') {
+        self.index += 1;
+        false
+      } else {
+        true
+      };
+      self.nest()?;
+      node = Node::Repeat(Box::new(node), min, max, greedy);
+    }
+    self.depth = depth;
+    Ok(node)
+  }
+
+  fn nest(&mut self) -> Result<(), String> {
+    self.depth += 1;
+    if self.depth > MAX_NESTING {
+      return Err(format!("nested over {} deep", MAX_NESTING));
+    }
+    Ok(())
+  }
+
+  // {n}, {n,} or {n,m}, leaving the index alone if it's none of those
+  fn parse_count(&mut self) -> Option<(usize, Option<usize>)> {
+    let start = self.index;
+    self.index += 1;
+    let min = self.parse_number();
+    let rc = match (min, self.peek()) {
+      (Some(n), Some('}')) => Some((n, Some(n))),
+      (Some(n), Some(',')) => {
+        self.index += 1;
+        let max = self.parse_number();
+        if self.peek() == Some('}') {
+          Some((n, max))
+        } else {
+          None
+        }
+      },
+      _ => None,
+    };
+    match rc {
+      Some(_) => self.index += 1,
+      None => self.index = start,
+    }
+    rc
+  }
+
+  fn parse_number(&mut self) -> Option<usize> {
+    let start = self.index;
+    while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+      self.index += 1;
+    }
+    if self.index == start {
+      return None;
+    }
+    self.chars[start..self.index].iter().collect::<String>().parse().ok()
+  }
+
+  fn parse_atom(&mut self) -> Result<Node, String> {
+    let c = match self.peek() {
+      Some(c) => c,
+      None => { return Err("unexpected end of pattern".to_string()); },
+    };
+    self.index += 1;
+    match c {
+      '(' => {
+        let group = if self.chars[self.index..].starts_with(&['?', ':']) {
+          self.index += 2;
+          None
+        } else {
+          self.groups += 1;
+          Some(self.groups)
+        };
+        self.nest()?;
+        let node = self.parse_alt()?;
+        if self.peek() != Some(')') {
+          return Err("missing )".to_string());
+        }
+        self.index += 1;
+        self.depth -= 1;
+        Ok(Node::Group(Box::new(node), group))
+      },
+      ')' => Err("unmatched )".to_string()),
+      '*' | '+' | '?' => Err(format!("nothing to repeat before {}", c)),
+      '[' => self.parse_class(),
+      '.' => Ok(Node::Any),
+      '^' => Ok(Node::Start),
+      '$' => Ok(Node::End),
+      '\\' => {
+        let e = match self.peek() {
+          Some(e) => e,
+          None => { return Err("pattern ends with \\".to_string()); },
+        };
+        self.index += 1;
+        match e {
+          'b' => Ok(Node::Boundary(true)),
+          'B' => Ok(Node::Boundary(false)),
+          'D' | 'W' | 'S' => {
+            let lower = e.to_lowercase().next().unwrap_or(e);
+            Ok(Node::Class(shorthand(lower).unwrap_or_default(), true))
+          },
+          _ => {
+            match shorthand(e) {
+              Some(ranges) => Ok(Node::Class(ranges, false)),
+              None => Ok(Node::Char(escaped(e))),
+            }
+          },
+        }
+      },
+      _ => Ok(Node::Char(c)),
+    }
+  }
+
+  fn parse_class(&mut self) -> Result<Node, String> {
+    let mut ranges = Vec::new();
+    let negated = if self.peek() == Some('^') {
+      self.index += 1;
+      true
+    } else {
+      false
+    };
+    let mut first = true;
+    loop {
+      let c = match self.peek() {
+        Some(c) => c,
+        None => { return Err("missing ]".to_string()); },
+      };
+      self.index += 1;
+      // ] right at the start is just a ]
+      if c == ']' && !first {
+        break;
+      }
+      first = false;
+      let lo = if c == '\\' {
+        let e = match self.peek() {
+          Some(e) => e,
+          None => { return Err("missing ]".to_string()); },
+        };
+        self.index += 1;
+        if let Some(mut r) = shorthand(e) {
+          ranges.append(&mut r);
+          continue;
+        }
+        if e == 'D' || e == 'W' || e == 'S' {
+          return Err(format!("\\{} is not supported inside []", e));
+        }
+        escaped(e)
+      } else {
+        c
+      };
+      // a - at either end is just a -
+      if self.peek() == Some('-') &&
+        self.chars.get(self.index + 1).is_some_and(|&n| n != ']') {
+        self.index += 1;
+        let mut hi = self.chars[self.index];
+        self.index += 1;
+        if hi == '\\' {
+          match self.peek() {
+            Some(e) => {
+              self.index += 1;
+              hi = escaped(e);
+            },
+            None => { return Err("missing ]".to_string()); },
+          }
+        }
+        if hi < lo {
+          return Err(format!("bad range {}-{}", lo, hi));
+        }
+        ranges.push((lo, hi));
+      } else {
+        ranges.push((lo, lo));
+      }
+    }
+    Ok(Node::Class(ranges, negated))
+  }
+}
+
+// Character ranges for \d, \w and \s
+fn shorthand(c: char) -> Option<Vec<(char, char)>> {
+  match c {
+    'd' => Some(vec![('0', '9')]),
+    'w' => Some(vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+    's' => Some(vec![(' ', ' '), ('\t', '\r')]),
+    _ => None,
+  }
+}
+
+fn escaped(c: char) -> char {
+  match c {
+    'n' => '\n',
+    't' => '\t',
+    'r' => '\r',
+    _ => c,
+  }
+}
+
+fn is_word(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+// Number of instructions compile gives for the node
+fn size(node: &Node) -> usize {
+  match node {
+    &Node::Empty => 0,
+    &Node::Group(ref n, group) => {
+      size(n).saturating_add(if group.is_some() { 2 } else { 0 })
+    },
+    &Node::Concat(ref nodes) => {
+      nodes.iter().fold(0usize, |total, n| total.saturating_add(size(n)))
+    },
+    // A split and a jump for every alternative but the last
+    &Node::Alt(ref alts) => {
+      let total = alts.iter().fold(0usize, |total, n| {
+        total.saturating_add(size(n))
+      });
+      total.saturating_add((alts.len() - 1) * 2)
+    },
+    &Node::Repeat(ref n, min, max, _) => {
+      let one = size(n);
+      let optional = match max {
+        Some(m) => one.saturating_add(1).saturating_mul(m - min),
+        None => one.saturating_add(2),
+      };
+      one.saturating_mul(min).saturating_add(optional)
+    },
+    _ => 1,
+  }
+}
+
+fn compile(node: &Node, prog: &mut Vec<Inst>) {
+  match node {
+    &Node::Empty => {},
+    &Node::Char(c) => prog.push(Inst::Char(c)),
+    &Node::Any => prog.push(Inst::Any),
+    &Node::Class(ref r, n) => prog.push(Inst::Class(r.clone(), n)),
+    &Node::Start => prog.push(Inst::Start),
+    &Node::End => prog.push(Inst::End),
+    &Node::Boundary(b) => prog.push(Inst::Boundary(b)),
+    &Node::Group(ref n, group) => {
+      if let Some(g) = group {
+        prog.push(Inst::Save(g * 2));
+      }
+      compile(n, prog);
+      if let Some(g) = group {
+        prog.push(Inst::Save(g * 2 + 1));
+      }
+    },
+    &Node::Concat(ref nodes) => {
+      for n in nodes {
+        compile(n, prog);
+      }
+    },
+    &Node::Alt(ref alts) => {
+      let mut jumps = Vec::new();
+      for (i, n) in alts.iter().enumerate() {
+        if i == alts.len() - 1 {
+          compile(n, prog);
+        } else {
+          let split = prog.len();
+          prog.push(Inst::Split(split + 1, 0));
+          compile(n, prog);
+          jumps.push(prog.len());
+          prog.push(Inst::Jmp(0));
+          let next = prog.len();
+          prog[split] = Inst::Split(split + 1, next);
+        }
+      }
+      let end = prog.len();
+      for j in jumps {
+        prog[j] = Inst::Jmp(end);
+      }
+    },
+    &Node::Repeat(ref n, min, max, greedy) => {
+      for _ in 0..min {
+        compile(n, prog);
+      }
+      match max {
+        None => {
+          let split = prog.len();
+          prog.push(Inst::Split(0, 0));
+          compile(n, prog);
+          prog.push(Inst::Jmp(split));
+          let exit = prog.len();
+          prog[split] = branch(split + 1, exit, greedy);
+        },
+        Some(m) => {
+          let mut splits = Vec::new();
+          for _ in min..m {
+            splits.push(prog.len());
+            prog.push(Inst::Split(0, 0));
+            compile(n, prog);
+          }
+          let exit = prog.len();
+          for s in splits {
+            prog[s] = branch(s + 1, exit, greedy);
+          }
+        },
+      }
+    },
+  }
+}
+
+// Greedy repeats try another round first, lazy ones try leaving first
+fn branch(more: usize, exit: usize, greedy: bool) -> Inst {
+  if greedy {
+    Inst::Split(more, exit)
+  } else {
+    Inst::Split(exit, more)
+  }
+}
+
+enum Job {
+  Thread(usize, usize),
+  Restore(usize, Option<usize>)
+}
+
+// Instruction and position pairs already tried, as bits, along with which
+// words have any set so that clearing up after a search costs no more than
+// the search did
+struct Visited {
+  bits: Vec<u64>,
+  used: Vec<usize>
+}
+
+impl Visited {
+  fn new(size: usize) -> Visited {
+    Visited { bits: vec![0u64; size.div_ceil(64)], used: Vec::new() }
+  }
+
+  // Mark a pair as tried, false if it already was
+  fn insert(&mut self, bit: usize) -> bool {
+    let word = bit / 64;
+    let mask = 1 << (bit % 64);
+    if self.bits[word] & mask != 0 {
+      return false;
+    }
+    if self.bits[word] == 0 {
+      self.used.push(word);
+    }
+    self.bits[word] |= mask;
+    true
+  }
+
+  fn clear(&mut self) {
+    for word in self.used.drain(..) {
+      self.bits[word] = 0;
+    }
+  }
+}
+
+// Every non-overlapping match, left to right, found one at a time so the
+// caller can stop partway (see Regex::find_all)
+pub struct Matches<'a> {
+  regex: &'a Regex,
+  input: &'a [char],
+  start: usize,
+  visited: Visited
+}
+
+impl<'a> Iterator for Matches<'a> {
+  type Item = Captures;
+
+  fn next(&mut self) -> Option<Captures> {
+    if self.start > self.input.len() {
+      return None;
+    }
+    let caps = self.regex.search(self.input, self.start, &mut self.visited)?;
+    let (a, b) = caps[0].unwrap_or((self.start, self.start));
+    // step past empty matches so we don't find them forever
+    self.start = if b > a { b } else { b + 1 };
+    Some(caps)
+  }
+}
+
+impl Regex {
+  pub fn new(pattern: &str) -> Result<Regex, String> {
+    let mut parser = Parser { chars: pattern.chars().collect(), index: 0,
+                              groups: 0, depth: 0 };
+    let node = parser.parse_alt()?;
+    if parser.index < parser.chars.len() {
+      // the only thing that stops parse_alt early
+      return Err("unmatched )".to_string());
+    }
+    if size(&node) > MAX_PROGRAM {
+      return Err(format!("pattern compiles to over {} instructions",
+                         MAX_PROGRAM));
+    }
+    let mut prog = Vec::new();
+    prog.push(Inst::Save(0));
+    compile(&node, &mut prog);
+    prog.push(Inst::Save(1));
+    prog.push(Inst::Match);
+    Ok(Regex { prog: prog, groups: parser.groups })
+  }
+
+  // Number of capture groups, not counting the whole match
+  pub fn groups(&self) -> usize {
+    self.groups
+  }
+
+  // Leftmost match starting at or after start
+  pub fn find_at(&self, input: &[char], start: usize) -> Option<Captures> {
+    let mut visited = Visited::new(self.prog.len() * (input.len() + 1));
+    self.search(input, start, &mut visited)
+  }
+
+  // Every non-overlapping match, left to right
+  pub fn find_all<'a>(&'a self, input: &'a [char]) -> Matches<'a> {
+    Matches { regex: self, input: input, start: 0,
+              visited: Visited::new(self.prog.len() * (input.len() + 1)) }
+  }
+
+  // What find_at does, with the visited set left clear for next time
+  fn search(&self, input: &[char], start: usize, visited: &mut Visited) ->
+    Option<Captures> {
+    let width = input.len() + 1;
+    let mut rc = None;
+    for s in start..width {
+      if let Some(slots) = self.run(input, s, visited) {
+        let mut caps = Vec::new();
+        for g in 0..self.groups + 1 {
+          match (slots[g * 2], slots[g * 2 + 1]) {
+            (Some(a), Some(b)) => caps.push(Some((a, b))),
+            _ => caps.push(None),
+          }
+        }
+        rc = Some(caps);
+        break;
+      }
+    }
+    visited.clear();
+    rc
+  }
+
+  fn run(&self, input: &[char], start: usize, visited: &mut Visited) ->
+    Option<Vec<Option<usize>>> {
+    let width = input.len() + 1;
+    let mut slots = vec![None; (self.groups + 1) * 2];
+    let mut jobs = vec![Job::Thread(0, start)];
+    while let Some(job) = jobs.pop() {
+      let (mut pc, mut pos) = match job {
+        Job::Restore(slot, old) => {
+          slots[slot] = old;
+          continue;
+        },
+        Job::Thread(pc, pos) => (pc, pos),
+      };
+      loop {
+        if !visited.insert(pc * width + pos) {
+          break;
+        }
+        let next = input.get(pos).cloned();
+        match self.prog[pc] {
+          Inst::Char(c) => {
+            if next != Some(c) {
+              break;
+            }
+            pc += 1;
+            pos += 1;
+          },
+          Inst::Any => {
+            match next {
+              Some(c) if c != '\n' => {
+                pc += 1;
+                pos += 1;
+              },
+              _ => break,
+            }
+          },
+          Inst::Class(ref ranges, negated) => {
+            match next {
+              Some(c) => {
+                let found = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                if found == negated {
+                  break;
+                }
+                pc += 1;
+                pos += 1;
+              },
+              None => break,
+            }
+          },
+          Inst::Start => {
+            if pos != 0 {
+              break;
+            }
+            pc += 1;
+          },
+          Inst::End => {
+            if pos != input.len() {
+              break;
+            }
+            pc += 1;
+          },
+          Inst::Boundary(b) => {
+            let before = pos > 0 && is_word(input[pos - 1]);
+            let after = next.is_some_and(is_word);
+            if (before != after) != b {
+              break;
+            }
+            pc += 1;
+          },
+          Inst::Split(x, y) => {
+            jobs.push(Job::Thread(y, pos));
+            pc = x;
+          },
+          Inst::Jmp(x) => pc = x,
+          Inst::Save(slot) => {
+            jobs.push(Job::Restore(slot, slots[slot]));
+            slots[slot] = Some(pos);
+            pc += 1;
+          },
+          Inst::Match => { return Some(slots); },
+        }
+      }
+    }
+    None
+  }
+}
@@ -0,0 +1,4004 @@
+// Runtime for doubtful programs compiled to C (see compiler.rs), which puts
+// this file ahead of the generated code.  Values, dynamic scoping, exceptions
+// and the primitives all work as they do in the interpreter, down to the
+// error messages, so that a compiled program behaves the same as it would
+// interpreted.
+//
+// Memory is never freed: values are immutable and shared freely, as they are
+// in the interpreter, and a compiled program is expected to finish before
+// that matters.  Upper and lower casing, and what \b counts as a word
+// character, know about the common alphabets rather than all of Unicode.
+
+#define _POSIX_C_SOURCE 200809L
+
+#include <dirent.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <inttypes.h>
+#include <math.h>
+#include <poll.h>
+#include <pthread.h>
+#include <signal.h>
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+extern char **environ;
+
+// Limit on nested function calls, as the interpreter's --max-depth
+#ifndef DBT_MAX_DEPTH
+#define DBT_MAX_DEPTH 10000
+#endif
+
+// Stack for the thread running the program, enough to reach the limit
+#define STACK_PER_CALL 16384
+
+// Names the runtime binds itself, the same slots as in symbols.rs
+#define SELF_SLOT 0
+#define ARGS_SLOT 1
+
+// Keep counted repeats in patterns from blowing up the compiled program,
+// groups and repeats from nesting too deep, and the whole compiled program
+// to a sensible size, as in regex.rs
+#define MAX_REPEAT 1000
+#define MAX_NESTING 100
+#define MAX_PROGRAM 10000
+
+// VALUES
+
+typedef struct Str {
+  size_t len;
+  const char *data;
+} Str;
+
+enum {
+  T_NIL, T_FALSE, T_TRUE, T_INTEGER, T_FLOAT, T_STRING, T_LIST, T_FUNCTION,
+  T_EXCEPTION
+};
+
+struct List;
+struct Fn;
+struct Exc;
+struct Memo;
+
+typedef struct V {
+  int type;
+  union {
+    int64_t i;
+    double f;
+    const Str *s;
+    const struct List *l;
+    const struct Fn *fn;
+    const struct Exc *e;
+  } as;
+} V;
+
+// Lists share a buffer of items, and adding to the end of the newest list
+// using a buffer doesn't copy anything, so building a list up one item at a
+// time doesn't take quadratic time
+typedef struct Items {
+  V *data;
+  size_t used, cap;
+} Items;
+
+typedef struct List {
+  Items *items;
+  size_t start, len;
+} List;
+
+typedef V (*Thunk)(void);
+typedef V (*Body)(const char *context);
+
+typedef struct Fn {
+  int nparams;
+  const char *const *params;
+  const int *slots;
+  Body body;
+  // The cache of results if it was made by memo()
+  struct Memo *memo;
+} Fn;
+
+typedef struct Def {
+  const char *id;
+  int slot;
+  const Fn *fn;
+} Def;
+
+typedef V (*Prim)(const char *id, V *args, int n);
+
+// A call in the program: what it calls, and its arguments to evaluate.  How
+// a name that isn't bound gets called is worked out the first time
+enum { SITE_NEW, SITE_PRIMITIVE, SITE_CONTROL, SITE_DOLLAR };
+
+typedef struct Site {
+  const char *id;
+  int slot;
+  int nargs;
+  const Thunk *args;
+  Prim prim;
+  int kind;
+} Site;
+
+// Calling context stack of an exception, newest (outermost) first
+typedef struct Context {
+  Str name;
+  const struct Context *next;
+} Context;
+
+enum {
+  E_RETURN, E_ERROR, E_ARITY, E_PARSE, E_TYPE, E_MISMATCH, E_DIV_BY_ZERO,
+  E_RUNTIME, E_UNDEF, E_REDEF, E_STACK_OVERFLOW, E_PERMISSION, E_IO,
+  E_OUT_OF_FUEL, E_OUT_OF_MEMORY, E_TIMEOUT, E_EXIT, E_USER
+};
+
+static const char *const flavor_names[] = {
+  "return", "error", "arity error", "parse error", "type error",
+  "type mismatch", "division by zero", "runtime error", "undefined function",
+  "redefinition error", "stack overflow", "permission error", "io error",
+  "out of fuel", "out of memory", "timeout", "exit"
+};
+
+typedef struct Exc {
+  int kind;
+  // Type name for E_USER
+  Str user;
+  V payload;
+  const Context *stack;
+  size_t depth;
+  const struct Exc *cause;
+} Exc;
+
+static void *dbt_realloc(void *p, size_t size) {
+  p = realloc(p, size ? size : 1);
+  if (p == NULL) {
+    fprintf(stderr, "memory allocation of %zu bytes failed\n", size);
+    abort();
+  }
+  return p;
+}
+
+static void *dbt_alloc(size_t size) {
+  return dbt_realloc(NULL, size);
+}
+
+static V dbt_nil(void) {
+  V v;
+  v.type = T_NIL;
+  v.as.i = 0;
+  return v;
+}
+
+static V dbt_true(void) {
+  V v = dbt_nil();
+  v.type = T_TRUE;
+  return v;
+}
+
+static V dbt_false(void) {
+  V v = dbt_nil();
+  v.type = T_FALSE;
+  return v;
+}
+
+static V dbt_bool(int b) {
+  return b ? dbt_true() : dbt_false();
+}
+
+static V dbt_int(int64_t x) {
+  V v;
+  v.type = T_INTEGER;
+  v.as.i = x;
+  return v;
+}
+
+static V dbt_float(double x) {
+  V v;
+  v.type = T_FLOAT;
+  v.as.f = x;
+  return v;
+}
+
+static V dbt_float_bits(uint64_t bits) {
+  double x;
+  memcpy(&x, &bits, sizeof x);
+  return dbt_float(x);
+}
+
+static V dbt_string(const Str *s) {
+  V v;
+  v.type = T_STRING;
+  v.as.s = s;
+  return v;
+}
+
+static V string_of(const char *data, size_t len) {
+  Str *s = dbt_alloc(sizeof *s);
+  char *copy = dbt_alloc(len);
+  memcpy(copy, data, len);
+  s->len = len;
+  s->data = copy;
+  return dbt_string(s);
+}
+
+static V cstring_value(const char *s) {
+  return string_of(s, strlen(s));
+}
+
+static V list_value(const List *l) {
+  V v;
+  v.type = T_LIST;
+  v.as.l = l;
+  return v;
+}
+
+static V function_value(const Fn *f) {
+  V v;
+  v.type = T_FUNCTION;
+  v.as.fn = f;
+  return v;
+}
+
+static V exception_value(const Exc *e) {
+  V v;
+  v.type = T_EXCEPTION;
+  v.as.e = e;
+  return v;
+}
+
+static Str str_of(const char *s) {
+  Str rc;
+  rc.len = strlen(s);
+  rc.data = s;
+  return rc;
+}
+
+static int str_equal(Str a, Str b) {
+  return a.len == b.len && memcmp(a.data, b.data, a.len) == 0;
+}
+
+static int str_order(Str a, Str b) {
+  size_t n = a.len < b.len ? a.len : b.len;
+  int c = n ? memcmp(a.data, b.data, n) : 0;
+  if (c != 0) {
+    return c < 0 ? -1 : 1;
+  }
+  return a.len < b.len ? -1 : (a.len > b.len ? 1 : 0);
+}
+
+// Strings with embedded NULs get cut short, for the few places (paths,
+// commands) where that's all C can do
+static const char *cstring(Str s) {
+  char *rc = dbt_alloc(s.len + 1);
+  memcpy(rc, s.data, s.len);
+  rc[s.len] = 0;
+  return rc;
+}
+
+// STRING BUILDING
+
+typedef struct Buf {
+  char *data;
+  size_t len, cap;
+} Buf;
+
+static void buf_add(Buf *b, const char *s, size_t n) {
+  if (b->len + n > b->cap) {
+    size_t cap = b->cap ? b->cap * 2 : 32;
+    while (cap < b->len + n) {
+      cap *= 2;
+    }
+    b->data = dbt_realloc(b->data, cap);
+    b->cap = cap;
+  }
+  if (n > 0) {
+    memcpy(b->data + b->len, s, n);
+  }
+  b->len += n;
+}
+
+static void buf_cstr(Buf *b, const char *s) {
+  buf_add(b, s, strlen(s));
+}
+
+static void buf_str(Buf *b, Str s) {
+  buf_add(b, s.data, s.len);
+}
+
+static void buf_printf(Buf *b, const char *fmt, ...) {
+  char small[64];
+  va_list ap;
+  va_start(ap, fmt);
+  int n = vsnprintf(small, sizeof small, fmt, ap);
+  va_end(ap);
+  if (n < (int) sizeof small) {
+    buf_add(b, small, n);
+    return;
+  }
+  char *big = dbt_alloc(n + 1);
+  va_start(ap, fmt);
+  vsnprintf(big, n + 1, fmt, ap);
+  va_end(ap);
+  buf_add(b, big, n);
+  free(big);
+}
+
+// Append a character as UTF-8
+static void buf_char(Buf *b, uint32_t c) {
+  char s[4];
+  if (c < 0x80) {
+    s[0] = c;
+    buf_add(b, s, 1);
+  } else if (c < 0x800) {
+    s[0] = 0xc0 | (c >> 6);
+    s[1] = 0x80 | (c & 0x3f);
+    buf_add(b, s, 2);
+  } else if (c < 0x10000) {
+    s[0] = 0xe0 | (c >> 12);
+    s[1] = 0x80 | ((c >> 6) & 0x3f);
+    s[2] = 0x80 | (c & 0x3f);
+    buf_add(b, s, 3);
+  } else {
+    s[0] = 0xf0 | (c >> 18);
+    s[1] = 0x80 | ((c >> 12) & 0x3f);
+    s[2] = 0x80 | ((c >> 6) & 0x3f);
+    s[3] = 0x80 | (c & 0x3f);
+    buf_add(b, s, 4);
+  }
+}
+
+static Str buf_contents(Buf *b) {
+  Str s;
+  s.len = b->len;
+  s.data = b->data ? b->data : "";
+  return s;
+}
+
+static V buf_value(Buf *b) {
+  Str *s = dbt_alloc(sizeof *s);
+  *s = buf_contents(b);
+  return dbt_string(s);
+}
+
+// UTF-8
+
+// Length of the valid sequence starting at s[i], 0 if there isn't one, and
+// in *bad how many bytes make up the invalid part (which is what gets
+// replaced by one U+FFFD when decoding lossily, as Rust does)
+static size_t utf8_sequence(const unsigned char *s, size_t len, size_t i,
+                            size_t *bad) {
+  unsigned char b = s[i];
+  size_t need;
+  unsigned char lo = 0x80, hi = 0xbf;
+  *bad = 1;
+  if (b < 0x80) {
+    return 1;
+  } else if (b >= 0xc2 && b <= 0xdf) {
+    need = 2;
+  } else if (b >= 0xe0 && b <= 0xef) {
+    need = 3;
+    if (b == 0xe0) {
+      lo = 0xa0;
+    } else if (b == 0xed) {
+      hi = 0x9f;
+    }
+  } else if (b >= 0xf0 && b <= 0xf4) {
+    need = 4;
+    if (b == 0xf0) {
+      lo = 0x90;
+    } else if (b == 0xf4) {
+      hi = 0x8f;
+    }
+  } else {
+    return 0;
+  }
+  for (size_t k = 1; k < need; k++) {
+    if (i + k >= len || s[i + k] < lo || s[i + k] > hi) {
+      *bad = k;
+      return 0;
+    }
+    lo = 0x80;
+    hi = 0xbf;
+  }
+  return need;
+}
+
+static int utf8_valid(const char *s, size_t len) {
+  size_t i = 0, bad;
+  while (i < len) {
+    size_t n = utf8_sequence((const unsigned char *) s, len, i, &bad);
+    if (n == 0) {
+      return 0;
+    }
+    i += n;
+  }
+  return 1;
+}
+
+static V utf8_lossy(const char *s, size_t len) {
+  Buf b = {0};
+  size_t i = 0, bad;
+  while (i < len) {
+    size_t n = utf8_sequence((const unsigned char *) s, len, i, &bad);
+    if (n == 0) {
+      buf_char(&b, 0xfffd);
+      i += bad;
+    } else {
+      buf_add(&b, s + i, n);
+      i += n;
+    }
+  }
+  return buf_value(&b);
+}
+
+// Decode one character of a string known to be valid
+static uint32_t utf8_next(const char *str, size_t *i) {
+  const unsigned char *s = (const unsigned char *) str;
+  uint32_t c = s[*i];
+  if (c < 0x80) {
+    *i += 1;
+    return c;
+  } else if (c < 0xe0) {
+    c = ((c & 0x1f) << 6) | (s[*i + 1] & 0x3f);
+    *i += 2;
+  } else if (c < 0xf0) {
+    c = ((c & 0x0f) << 12) | ((s[*i + 1] & 0x3f) << 6) | (s[*i + 2] & 0x3f);
+    *i += 3;
+  } else {
+    c = ((c & 0x07) << 18) | ((s[*i + 1] & 0x3f) << 12) |
+      ((s[*i + 2] & 0x3f) << 6) | (s[*i + 3] & 0x3f);
+    *i += 4;
+  }
+  return c;
+}
+
+static uint32_t *utf8_chars(Str s, size_t *n) {
+  uint32_t *rc = dbt_alloc((s.len + 1) * sizeof *rc);
+  size_t i = 0;
+  *n = 0;
+  while (i < s.len) {
+    rc[(*n)++] = utf8_next(s.data, &i);
+  }
+  return rc;
+}
+
+static size_t utf8_count(const char *s, size_t len) {
+  size_t n = 0;
+  for (size_t i = 0; i < len; i++) {
+    if ((s[i] & 0xc0) != 0x80) {
+      n++;
+    }
+  }
+  return n;
+}
+
+static V chars_value(const uint32_t *chars, size_t n) {
+  Buf b = {0};
+  for (size_t i = 0; i < n; i++) {
+    buf_char(&b, chars[i]);
+  }
+  return buf_value(&b);
+}
+
+// LISTS
+
+static const List empty_list = { NULL, 0, 0 };
+
+static const List *list_push(const List *l, V v) {
+  List *rc = dbt_alloc(sizeof *rc);
+  Items *items = l->items;
+  if (items != NULL && l->start + l->len == items->used &&
+      items->used < items->cap) {
+    items->data[items->used++] = v;
+    rc->items = items;
+    rc->start = l->start;
+    rc->len = l->len + 1;
+    return rc;
+  }
+  items = dbt_alloc(sizeof *items);
+  items->cap = (l->len + 1) * 2;
+  items->data = dbt_alloc(items->cap * sizeof(V));
+  if (l->len > 0) {
+    memcpy(items->data, l->items->data + l->start, l->len * sizeof(V));
+  }
+  items->data[l->len] = v;
+  items->used = l->len + 1;
+  rc->items = items;
+  rc->start = 0;
+  rc->len = l->len + 1;
+  return rc;
+}
+
+static V list_get(const List *l, size_t i) {
+  return l->items->data[l->start + i];
+}
+
+static const List *list_rest(const List *l) {
+  List *rc = dbt_alloc(sizeof *rc);
+  *rc = *l;
+  rc->start++;
+  rc->len--;
+  return rc;
+}
+
+static const List *list_concat(const List *a, const List *b) {
+  const List *rc = a;
+  for (size_t i = 0; i < b->len; i++) {
+    rc = list_push(rc, list_get(b, i));
+  }
+  return rc;
+}
+
+static V list_of_items(const V *items, size_t n) {
+  const List *l = &empty_list;
+  for (size_t i = 0; i < n; i++) {
+    l = list_push(l, items[i]);
+  }
+  return list_value(l);
+}
+
+// EXCEPTIONS
+
+static Str flavor(const Exc *e) {
+  return e->kind == E_USER ? e->user : str_of(flavor_names[e->kind]);
+}
+
+// Exceptions that end the program pass through catch untouched
+static int is_fatal(int kind) {
+  return kind == E_OUT_OF_FUEL || kind == E_OUT_OF_MEMORY ||
+    kind == E_TIMEOUT || kind == E_EXIT;
+}
+
+// Inverse of flavor, anything unrecognized is a user-defined type
+static int kind_from_name(Str name) {
+  for (int k = 0; k < E_USER; k++) {
+    if (str_equal(name, str_of(flavor_names[k]))) {
+      return k;
+    }
+  }
+  return E_USER;
+}
+
+// Names a user-defined type can't have, so a type string from catch always
+// says where the exception came from ("ok" being what catch gives for a value
+// that isn't one)
+static int is_reserved(Str name) {
+  return kind_from_name(name) != E_USER || str_equal(name, str_of("ok"));
+}
+
+static Exc *exc_new(int kind, Str user, V payload) {
+  Exc *e = dbt_alloc(sizeof *e);
+  e->kind = kind;
+  e->user = user;
+  e->payload = payload;
+  e->stack = NULL;
+  e->depth = 0;
+  e->cause = NULL;
+  return e;
+}
+
+static Exc *exc_copy(const Exc *e) {
+  Exc *rc = dbt_alloc(sizeof *rc);
+  *rc = *e;
+  return rc;
+}
+
+static Exc *exc_push(const Exc *e, Str context) {
+  Exc *rc = exc_copy(e);
+  Context *c = dbt_alloc(sizeof *c);
+  c->name = context;
+  c->next = e->stack;
+  rc->stack = c;
+  rc->depth = e->depth + 1;
+  return rc;
+}
+
+// The calling context in the order it was added, innermost first
+static Str *stack_array(const Exc *e) {
+  Str *rc = dbt_alloc((e->depth + 1) * sizeof *rc);
+  size_t i = e->depth;
+  for (const Context *c = e->stack; c != NULL; c = c->next) {
+    rc[--i] = c->name;
+  }
+  return rc;
+}
+
+static V exception_msg(int kind, const char *id, Str msg) {
+  Buf b = {0};
+  buf_cstr(&b, id);
+  buf_cstr(&b, " : ");
+  buf_str(&b, msg);
+  return exception_value(exc_new(kind, str_of(""), buf_value(&b)));
+}
+
+static V dbt_exception(int kind, const char *id, const char *fmt, ...) {
+  Buf b = {0};
+  char small[256];
+  va_list ap;
+  va_start(ap, fmt);
+  int n = vsnprintf(small, sizeof small, fmt, ap);
+  va_end(ap);
+  if (n < (int) sizeof small) {
+    buf_add(&b, small, n);
+  } else {
+    char *big = dbt_alloc(n + 1);
+    va_start(ap, fmt);
+    vsnprintf(big, n + 1, fmt, ap);
+    va_end(ap);
+    buf_add(&b, big, n);
+  }
+  return exception_msg(kind, id, buf_contents(&b));
+}
+
+static V error(int kind, const char *id, const char *msg) {
+  return exception_msg(kind, id, str_of(msg));
+}
+
+// Raising a user-defined exception named like a built-in one
+static V reserved(const char *id, Str name) {
+  Buf b = {0};
+  buf_str(&b, name);
+  buf_cstr(&b, " is a reserved exception type");
+  return exception_msg(E_RUNTIME, id, buf_contents(&b));
+}
+
+static V arity(const char *id, int count, int n) {
+  return dbt_exception(E_ARITY, id, "expected %d arguments but got %d", count,
+                       n);
+}
+
+#define EXPECT_ARGS(count) \
+  if (n != (count)) { \
+    return arity(id, count, n); \
+  }
+
+// What a block does with its value on the way out: a return stops there,
+// any other exception picks up the block's calling context
+static V dbt_leave_block(V value, const char *context) {
+  if (value.type != T_EXCEPTION) {
+    return value;
+  }
+  if (value.as.e->kind == E_RETURN) {
+    return value.as.e->payload;
+  }
+  return exception_value(exc_push(value.as.e, str_of(context)));
+}
+
+// CHARACTERS
+
+static uint32_t lower_char(uint32_t c) {
+  if (c < 0x80) {
+    return (c >= 'A' && c <= 'Z') ? c + 32 : c;
+  }
+  if ((c >= 0xc0 && c <= 0xde && c != 0xd7) || (c >= 0x391 && c <= 0x3ab &&
+                                                c != 0x3a2) ||
+      (c >= 0x410 && c <= 0x42f) || (c >= 0xff21 && c <= 0xff3a)) {
+    return c + 32;
+  }
+  if ((c >= 0x100 && c <= 0x12f) || (c >= 0x132 && c <= 0x137) ||
+      (c >= 0x14a && c <= 0x177) || (c >= 0x1de && c <= 0x1ef) ||
+      (c >= 0x1f8 && c <= 0x21f) || (c >= 0x222 && c <= 0x233) ||
+      (c >= 0x3d8 && c <= 0x3ef) || (c >= 0x460 && c <= 0x481) ||
+      (c >= 0x48a && c <= 0x4bf) || (c >= 0x4d0 && c <= 0x52f) ||
+      (c >= 0x1e00 && c <= 0x1e95) || (c >= 0x1ea0 && c <= 0x1eff)) {
+    return (c % 2 == 0) ? c + 1 : c;
+  }
+  if ((c >= 0x139 && c <= 0x148) || (c >= 0x179 && c <= 0x17e) ||
+      (c >= 0x1cd && c <= 0x1dc) || (c >= 0x4c1 && c <= 0x4ce)) {
+    return (c % 2 == 1) ? c + 1 : c;
+  }
+  if (c >= 0x400 && c <= 0x40f) {
+    return c + 80;
+  }
+  if (c >= 0x531 && c <= 0x556) {
+    return c + 48;
+  }
+  if (c >= 0x10a0 && c <= 0x10c5) {
+    return c + 7264;
+  }
+  if (c >= 0x2160 && c <= 0x216f) {
+    return c + 16;
+  }
+  if (c >= 0x24b6 && c <= 0x24cf) {
+    return c + 26;
+  }
+  if (c >= 0x10400 && c <= 0x10427) {
+    return c + 40;
+  }
+  switch (c) {
+  case 0x178: return 0xff;
+  case 0x1c4: case 0x1c7: case 0x1ca: case 0x1f1: return c + 2;
+  case 0x1c5: case 0x1c8: case 0x1cb: case 0x1f2: return c + 1;
+  case 0x386: return 0x3ac;
+  case 0x388: case 0x389: case 0x38a: return c + 37;
+  case 0x38c: return 0x3cc;
+  case 0x38e: case 0x38f: return c + 63;
+  case 0x4c0: return 0x4cf;
+  case 0x1e9e: return 0xdf;
+  default: return c;
+  }
+}
+
+static uint32_t upper_char(uint32_t c) {
+  if (c < 0x80) {
+    return (c >= 'a' && c <= 'z') ? c - 32 : c;
+  }
+  if ((c >= 0xe0 && c <= 0xfe && c != 0xf7) || (c >= 0x3b1 && c <= 0x3cb &&
+                                                c != 0x3c2) ||
+      (c >= 0x430 && c <= 0x44f) || (c >= 0xff41 && c <= 0xff5a)) {
+    return c - 32;
+  }
+  if ((c >= 0x101 && c <= 0x130) || (c >= 0x133 && c <= 0x137) ||
+      (c >= 0x14b && c <= 0x178) || (c >= 0x1df && c <= 0x1ef) ||
+      (c >= 0x1f9 && c <= 0x21f) || (c >= 0x223 && c <= 0x233) ||
+      (c >= 0x3d9 && c <= 0x3ef) || (c >= 0x461 && c <= 0x481) ||
+      (c >= 0x48b && c <= 0x4bf) || (c >= 0x4d1 && c <= 0x52f) ||
+      (c >= 0x1e01 && c <= 0x1e95) || (c >= 0x1ea1 && c <= 0x1eff)) {
+    return (c % 2 == 1) ? c - 1 : c;
+  }
+  if ((c >= 0x13a && c <= 0x148) || (c >= 0x17a && c <= 0x17e) ||
+      (c >= 0x1ce && c <= 0x1dc) || (c >= 0x4c2 && c <= 0x4ce)) {
+    return (c % 2 == 0) ? c - 1 : c;
+  }
+  if (c >= 0x450 && c <= 0x45f) {
+    return c - 80;
+  }
+  if (c >= 0x561 && c <= 0x586) {
+    return c - 48;
+  }
+  if (c >= 0x2d00 && c <= 0x2d25) {
+    return c - 7264;
+  }
+  if (c >= 0x2170 && c <= 0x217f) {
+    return c - 16;
+  }
+  if (c >= 0x24d0 && c <= 0x24e9) {
+    return c - 26;
+  }
+  if (c >= 0x10428 && c <= 0x1044f) {
+    return c - 40;
+  }
+  switch (c) {
+  case 0xb5: return 0x39c;
+  case 0xff: return 0x178;
+  case 0x1c5: case 0x1c8: case 0x1cb: case 0x1f2: return c - 1;
+  case 0x1c6: case 0x1c9: case 0x1cc: case 0x1f3: return c - 2;
+  case 0x131: return 'I';
+  case 0x17f: return 'S';
+  case 0x3ac: return 0x386;
+  case 0x3ad: case 0x3ae: case 0x3af: return c - 37;
+  case 0x3c2: return 0x3a3;
+  case 0x3cc: return 0x38c;
+  case 0x3cd: case 0x3ce: return c - 63;
+  case 0x4cf: return 0x4c0;
+  default: return c;
+  }
+}
+
+static int is_alphanumeric(uint32_t c) {
+  if (c < 0x80) {
+    return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') ||
+      (c >= '0' && c <= '9');
+  }
+  if (lower_char(c) != c || upper_char(c) != c) {
+    return 1;
+  }
+  return c == 0xaa || c == 0xb2 || c == 0xb3 || c == 0xb9 || c == 0xba ||
+    (c >= 0xbc && c <= 0xbe) || (c >= 0xdf && c <= 0x24f && c != 0xf7) ||
+    (c >= 0x250 && c <= 0x2c1) || (c >= 0x370 && c <= 0x3ff && c != 0x375 &&
+                                   c != 0x37e && c != 0x384 && c != 0x385 &&
+                                   c != 0x387) ||
+    (c >= 0x400 && c <= 0x52f && (c < 0x482 || c > 0x489)) ||
+    (c >= 0x531 && c <= 0x587) || (c >= 0x5d0 && c <= 0x5ea) ||
+    (c >= 0x620 && c <= 0x64a) || (c >= 0x660 && c <= 0x669) ||
+    (c >= 0x904 && c <= 0x939) || (c >= 0x966 && c <= 0x96f) ||
+    (c >= 0x10d0 && c <= 0x10ff) || (c >= 0x1e00 && c <= 0x1fbc) ||
+    (c >= 0x3041 && c <= 0x3096) || (c >= 0x30a1 && c <= 0x30fa) ||
+    (c >= 0x3400 && c <= 0x4dbf) || (c >= 0x4e00 && c <= 0x9fff) ||
+    (c >= 0xac00 && c <= 0xd7a3) || (c >= 0xff10 && c <= 0xff19) ||
+    (c >= 0xff41 && c <= 0xff5a) || (c >= 0xff66 && c <= 0xff9f) ||
+    (c >= 0x20000 && c <= 0x2fffd);
+}
+
+static int is_cased(uint32_t c) {
+  return lower_char(c) != c || upper_char(c) != c;
+}
+
+// Unicode White_Space, as trimmed by trim
+static int is_space(uint32_t c) {
+  return (c >= 0x09 && c <= 0x0d) || c == 0x20 || c == 0x85 || c == 0xa0 ||
+    c == 0x1680 || (c >= 0x2000 && c <= 0x200a) || c == 0x2028 ||
+    c == 0x2029 || c == 0x202f || c == 0x205f || c == 0x3000;
+}
+
+static V upper_string(Str s) {
+  Buf b = {0};
+  size_t i = 0;
+  while (i < s.len) {
+    uint32_t c = utf8_next(s.data, &i);
+    if (c == 0xdf) {
+      buf_cstr(&b, "SS");
+    } else if (c == 0x149) {
+      buf_char(&b, 0x2bc);
+      buf_char(&b, 'N');
+    } else {
+      buf_char(&b, upper_char(c));
+    }
+  }
+  return buf_value(&b);
+}
+
+static V lower_string(Str s) {
+  size_t n;
+  uint32_t *chars = utf8_chars(s, &n);
+  Buf b = {0};
+  for (size_t i = 0; i < n; i++) {
+    uint32_t c = chars[i];
+    if (c == 0x130) {
+      buf_char(&b, 'i');
+      buf_char(&b, 0x307);
+    } else if (c == 0x3a3) {
+      // Final sigma, at the end of a word
+      int after = i > 0 && is_cased(chars[i - 1]);
+      int before = i + 1 < n && is_cased(chars[i + 1]);
+      buf_char(&b, after && !before ? 0x3c2 : 0x3c3);
+    } else {
+      buf_char(&b, lower_char(c));
+    }
+  }
+  free(chars);
+  return buf_value(&b);
+}
+
+// DISPLAY
+
+// Floats as Rust displays them: the fewest digits that read back as the
+// same number, without an exponent
+static void show_float(Buf *b, double x) {
+  if (x != x) {
+    buf_cstr(b, "NaN");
+    return;
+  }
+  if (x > 1.7976931348623157e308 || x < -1.7976931348623157e308) {
+    buf_cstr(b, x > 0 ? "inf" : "-inf");
+    return;
+  }
+  if (x == 0) {
+    buf_cstr(b, signbit(x) ? "-0" : "0");
+    return;
+  }
+  char text[40];
+  for (int p = 1; p <= 17; p++) {
+    snprintf(text, sizeof text, "%.*e", p - 1, x);
+    if (strtod(text, NULL) == x) {
+      break;
+    }
+  }
+  char digits[40];
+  int ndigits = 0;
+  char *s = text;
+  if (*s == '-') {
+    buf_cstr(b, "-");
+    s++;
+  }
+  while (*s != 'e') {
+    if (*s != '.') {
+      digits[ndigits++] = *s;
+    }
+    s++;
+  }
+  int exponent = atoi(s + 1);
+  while (ndigits > 1 && digits[ndigits - 1] == '0') {
+    ndigits--;
+  }
+  // Digits before the decimal point
+  int point = exponent + 1;
+  if (point <= 0) {
+    buf_cstr(b, "0.");
+    for (int i = 0; i < -point; i++) {
+      buf_cstr(b, "0");
+    }
+    buf_add(b, digits, ndigits);
+  } else if (point >= ndigits) {
+    buf_add(b, digits, ndigits);
+    for (int i = ndigits; i < point; i++) {
+      buf_cstr(b, "0");
+    }
+  } else {
+    buf_add(b, digits, point);
+    buf_cstr(b, ".");
+    buf_add(b, digits + point, ndigits - point);
+  }
+}
+
+static void show_fixed(Buf *b, double x, size_t precision) {
+  if (x != x) {
+    buf_cstr(b, "NaN");
+    return;
+  }
+  if (x > 1.7976931348623157e308 || x < -1.7976931348623157e308) {
+    buf_cstr(b, x > 0 ? "inf" : "-inf");
+    return;
+  }
+  buf_printf(b, "%.*f", (int) precision, x);
+}
+
+// As string() shows a value
+static void show(Buf *b, V v) {
+  switch (v.type) {
+  case T_NIL:
+    buf_cstr(b, "nil");
+    break;
+  case T_TRUE:
+    buf_cstr(b, "true");
+    break;
+  case T_FALSE:
+    buf_cstr(b, "false");
+    break;
+  case T_INTEGER:
+    buf_printf(b, "%" PRId64, v.as.i);
+    break;
+  case T_FLOAT:
+    show_float(b, v.as.f);
+    break;
+  case T_STRING:
+    buf_cstr(b, "\"");
+    buf_str(b, *v.as.s);
+    buf_cstr(b, "\"");
+    break;
+  case T_LIST:
+    buf_cstr(b, "[");
+    for (size_t i = 0; i < v.as.l->len; i++) {
+      if (i > 0) {
+        buf_cstr(b, ", ");
+      }
+      show(b, list_get(v.as.l, i));
+    }
+    buf_cstr(b, "]");
+    break;
+  case T_EXCEPTION: {
+    const Exc *e = v.as.e;
+    Str *stack = stack_array(e);
+    buf_cstr(b, "[");
+    buf_str(b, flavor(e));
+    buf_cstr(b, ", ");
+    show(b, e->payload);
+    buf_cstr(b, ", ");
+    for (size_t i = 0; i < e->depth; i++) {
+      if (i > 0) {
+        buf_cstr(b, ", ");
+      }
+      buf_str(b, stack[i]);
+    }
+    buf_cstr(b, "]]");
+    break;
+  }
+  case T_FUNCTION:
+    if (v.as.fn->nparams > 0) {
+      buf_cstr(b, "(");
+      for (int i = 0; i < v.as.fn->nparams; i++) {
+        if (i > 0) {
+          buf_cstr(b, ", ");
+        }
+        buf_cstr(b, v.as.fn->params[i]);
+      }
+      buf_cstr(b, ")");
+    }
+    buf_cstr(b, ":<...>");
+    break;
+  }
+}
+
+// The report for an uncaught exception
+static void show_report(Buf *b, const Exc *e) {
+  Str *stack = stack_array(e);
+  V name = upper_string(flavor(e));
+  buf_cstr(b, "\nRUNTIME EXCEPTION: ");
+  buf_str(b, *name.as.s);
+  buf_cstr(b, "\n");
+  show(b, e->payload);
+  buf_cstr(b, ":\n\n  calling context:\n");
+  // Runs of the same function (i.e., recursion) are summarized as one line
+  size_t n = e->depth;
+  size_t x = 0;
+  while (x < e->depth) {
+    size_t repeats = 1;
+    while (x + repeats < e->depth && str_equal(stack[x + repeats], stack[x])) {
+      repeats++;
+    }
+    if (repeats > 2) {
+      buf_printf(b, "   -- called from functions %zu-%zu: ", n - 1,
+                 n - repeats);
+      buf_str(b, stack[x]);
+      buf_printf(b, " (repeated %zu times)\n", repeats);
+    } else {
+      for (size_t i = 0; i < repeats; i++) {
+        buf_printf(b, "   -- called from function %zu: ", n - 1 - i);
+        buf_str(b, stack[x]);
+        buf_cstr(b, "\n");
+      }
+    }
+    n -= repeats;
+    x += repeats;
+  }
+  if (e->cause != NULL) {
+    buf_cstr(b, "\n  caused by:");
+    show_report(b, e->cause);
+  }
+}
+
+// EQUALITY AND ORDER
+
+static int equal(V a, V b) {
+  switch (a.type) {
+  case T_NIL:
+  case T_TRUE:
+  case T_FALSE:
+    return b.type == a.type;
+  case T_INTEGER:
+    return b.type == T_INTEGER && a.as.i == b.as.i;
+  case T_FLOAT:
+    return b.type == T_FLOAT && a.as.f == b.as.f;
+  case T_STRING:
+    return b.type == T_STRING && str_equal(*a.as.s, *b.as.s);
+  case T_LIST:
+    if (b.type != T_LIST || a.as.l->len != b.as.l->len) {
+      return 0;
+    }
+    for (size_t i = 0; i < a.as.l->len; i++) {
+      if (!equal(list_get(a.as.l, i), list_get(b.as.l, i))) {
+        return 0;
+      }
+    }
+    return 1;
+  default:
+    return 0;
+  }
+}
+
+static int rank(V v) {
+  switch (v.type) {
+  case T_NIL: return 0;
+  case T_FALSE: return 1;
+  case T_TRUE: return 2;
+  case T_INTEGER: case T_FLOAT: return 3;
+  case T_STRING: return 4;
+  case T_LIST: return 5;
+  case T_FUNCTION: return 6;
+  default: return 7;
+  }
+}
+
+// NaN goes after every other number so that floats still have an order
+static int float_order(double x, double y) {
+  if (x < y) {
+    return -1;
+  } else if (x > y) {
+    return 1;
+  } else if (x == y) {
+    return 0;
+  }
+  return (x != x) - (y != y);
+}
+
+// An integer against a float by their exact values, since converting either
+// one to the other's type can round (integers above 2^53, say)
+static int mixed_order(int64_t x, double y) {
+  // 2^63, the first float past every integer
+  double limit = 9223372036854775808.0;
+  if (y != y || y >= limit) {
+    return -1;
+  } else if (y < -limit) {
+    return 1;
+  }
+  int64_t w = (int64_t) y;
+  double whole = (double) w;
+  if (x != w) {
+    return x < w ? -1 : 1;
+  }
+  return float_order(whole, y);
+}
+
+// Every pair of values has an order, as in primitives_order.rs
+static int order(V a, V b) {
+  if (a.type == T_INTEGER && b.type == T_INTEGER) {
+    return a.as.i < b.as.i ? -1 : (a.as.i > b.as.i ? 1 : 0);
+  }
+  if (a.type == T_FLOAT && b.type == T_FLOAT) {
+    return float_order(a.as.f, b.as.f);
+  }
+  // Equal integers and floats differ as far as = goes, integers go first
+  if (a.type == T_INTEGER && b.type == T_FLOAT) {
+    int o = mixed_order(a.as.i, b.as.f);
+    return o != 0 ? o : -1;
+  }
+  if (a.type == T_FLOAT && b.type == T_INTEGER) {
+    int o = -mixed_order(b.as.i, a.as.f);
+    return o != 0 ? o : 1;
+  }
+  if (a.type == T_STRING && b.type == T_STRING) {
+    return str_order(*a.as.s, *b.as.s);
+  }
+  if (a.type == T_LIST && b.type == T_LIST) {
+    const List *x = a.as.l, *y = b.as.l;
+    for (size_t i = 0; i < x->len && i < y->len; i++) {
+      int o = order(list_get(x, i), list_get(y, i));
+      if (o != 0) {
+        return o;
+      }
+    }
+    return x->len < y->len ? -1 : (x->len > y->len ? 1 : 0);
+  }
+  if (a.type == T_EXCEPTION && b.type == T_EXCEPTION) {
+    int o = str_order(flavor(a.as.e), flavor(b.as.e));
+    return o != 0 ? o : order(a.as.e->payload, b.as.e->payload);
+  }
+  int x = rank(a), y = rank(b);
+  return x < y ? -1 : (x > y ? 1 : 0);
+}
+
+// SCOPES
+
+typedef struct Binding {
+  // Index of the scope the binding was made in
+  size_t level;
+  int function;
+  V value;
+} Binding;
+
+typedef struct Bindings {
+  Binding *data;
+  size_t len, cap;
+} Bindings;
+
+typedef struct Scope {
+  int *slots;
+  size_t len, cap;
+} Scope;
+
+static Bindings *bindings;
+static size_t nbindings;
+static Scope *scopes;
+static size_t nscopes, scopes_cap;
+static size_t depth;
+
+static void dbt_push_scope(void) {
+  if (nscopes == scopes_cap) {
+    size_t cap = scopes_cap ? scopes_cap * 2 : 64;
+    scopes = dbt_realloc(scopes, cap * sizeof *scopes);
+    memset(scopes + scopes_cap, 0, (cap - scopes_cap) * sizeof *scopes);
+    scopes_cap = cap;
+  }
+  // The slots array is kept for the next scope at this level
+  scopes[nscopes++].len = 0;
+}
+
+static void dbt_pop_scope(void) {
+  Scope *s = &scopes[--nscopes];
+  for (size_t i = 0; i < s->len; i++) {
+    bindings[s->slots[i]].len--;
+  }
+}
+
+static void bind(int slot, int function, V value) {
+  if ((size_t) slot >= nbindings) {
+    size_t n = (slot + 1) * 2;
+    bindings = dbt_realloc(bindings, n * sizeof *bindings);
+    memset(bindings + nbindings, 0, (n - nbindings) * sizeof *bindings);
+    nbindings = n;
+  }
+  Bindings *b = &bindings[slot];
+  if (b->len == b->cap) {
+    b->cap = b->cap ? b->cap * 2 : 4;
+    b->data = dbt_realloc(b->data, b->cap * sizeof *b->data);
+  }
+  b->data[b->len].level = nscopes - 1;
+  b->data[b->len].function = function;
+  b->data[b->len].value = value;
+  b->len++;
+  Scope *s = &scopes[nscopes - 1];
+  if (s->len == s->cap) {
+    s->cap = s->cap ? s->cap * 2 : 4;
+    s->slots = dbt_realloc(s->slots, s->cap * sizeof *s->slots);
+  }
+  s->slots[s->len++] = slot;
+}
+
+static const Binding *lookup(int slot) {
+  if ((size_t) slot >= nbindings || bindings[slot].len == 0) {
+    return NULL;
+  }
+  return &bindings[slot].data[bindings[slot].len - 1];
+}
+
+// MEMOIZATION
+
+// Results of a function made by memo(), as in memo.rs: keyed by arguments
+// that are plain data, compared structurally with floats by their bits, and
+// the oldest dropped to make room
+#define DEFAULT_MEMO_SIZE 1000
+
+typedef struct Entry {
+  uint64_t hash;
+  V *args;
+  V value;
+  // Next in the same bucket, and the next one added
+  struct Entry *chain, *newer;
+} Entry;
+
+typedef struct Memo {
+  size_t capacity, count, nbuckets;
+  Entry **buckets;
+  Entry *oldest, *newest;
+} Memo;
+
+static uint64_t hash_bytes(uint64_t h, const void *data, size_t len) {
+  const unsigned char *p = data;
+  for (size_t i = 0; i < len; i++) {
+    h = (h ^ p[i]) * UINT64_C(0x100000001b3);
+  }
+  return h;
+}
+
+// Add a value to a hash, false if it can't be part of a key
+static int hash_key(V v, uint64_t *h) {
+  unsigned char type = v.type;
+  *h = hash_bytes(*h, &type, 1);
+  switch (v.type) {
+  case T_NIL:
+  case T_TRUE:
+  case T_FALSE:
+    return 1;
+  case T_INTEGER:
+    *h = hash_bytes(*h, &v.as.i, sizeof v.as.i);
+    return 1;
+  case T_FLOAT:
+    *h = hash_bytes(*h, &v.as.f, sizeof v.as.f);
+    return 1;
+  case T_STRING:
+    *h = hash_bytes(*h, &v.as.s->len, sizeof v.as.s->len);
+    *h = hash_bytes(*h, v.as.s->data, v.as.s->len);
+    return 1;
+  case T_LIST:
+    *h = hash_bytes(*h, &v.as.l->len, sizeof v.as.l->len);
+    for (size_t i = 0; i < v.as.l->len; i++) {
+      if (!hash_key(list_get(v.as.l, i), h)) {
+        return 0;
+      }
+    }
+    return 1;
+  default:
+    return 0;
+  }
+}
+
+static int same_key(V a, V b) {
+  if (a.type != b.type) {
+    return 0;
+  }
+  switch (a.type) {
+  case T_INTEGER:
+    return a.as.i == b.as.i;
+  case T_FLOAT:
+    return memcmp(&a.as.f, &b.as.f, sizeof a.as.f) == 0;
+  case T_STRING:
+    return str_equal(*a.as.s, *b.as.s);
+  case T_LIST:
+    if (a.as.l->len != b.as.l->len) {
+      return 0;
+    }
+    for (size_t i = 0; i < a.as.l->len; i++) {
+      if (!same_key(list_get(a.as.l, i), list_get(b.as.l, i))) {
+        return 0;
+      }
+    }
+    return 1;
+  default:
+    return 1;
+  }
+}
+
+static Memo *memo_new(size_t capacity) {
+  Memo *m = dbt_alloc(sizeof *m);
+  memset(m, 0, sizeof *m);
+  m->capacity = capacity;
+  m->nbuckets = 16;
+  m->buckets = dbt_alloc(m->nbuckets * sizeof *m->buckets);
+  memset(m->buckets, 0, m->nbuckets * sizeof *m->buckets);
+  return m;
+}
+
+static Entry *memo_find(const Memo *m, const V *args, int n, uint64_t hash) {
+  for (Entry *e = m->buckets[hash & (m->nbuckets - 1)]; e; e = e->chain) {
+    if (e->hash != hash) {
+      continue;
+    }
+    int i = 0;
+    while (i < n && same_key(e->args[i], args[i])) {
+      i++;
+    }
+    if (i == n) {
+      return e;
+    }
+  }
+  return NULL;
+}
+
+static void memo_drop_oldest(Memo *m) {
+  Entry *e = m->oldest;
+  Entry **p = &m->buckets[e->hash & (m->nbuckets - 1)];
+  while (*p != e) {
+    p = &(*p)->chain;
+  }
+  *p = e->chain;
+  m->oldest = e->newer;
+  if (m->oldest == NULL) {
+    m->newest = NULL;
+  }
+  m->count--;
+  free(e->args);
+  free(e);
+}
+
+static void memo_insert(Memo *m, const V *args, int n, uint64_t hash,
+                        V value) {
+  Entry *e = memo_find(m, args, n, hash);
+  if (e != NULL) {
+    e->value = value;
+    return;
+  }
+  if (m->count >= m->capacity) {
+    memo_drop_oldest(m);
+  }
+  if (m->count >= m->nbuckets) {
+    size_t nbuckets = m->nbuckets * 2;
+    Entry **buckets = dbt_alloc(nbuckets * sizeof *buckets);
+    memset(buckets, 0, nbuckets * sizeof *buckets);
+    for (Entry *x = m->oldest; x; x = x->newer) {
+      x->chain = buckets[x->hash & (nbuckets - 1)];
+      buckets[x->hash & (nbuckets - 1)] = x;
+    }
+    free(m->buckets);
+    m->buckets = buckets;
+    m->nbuckets = nbuckets;
+  }
+  e = dbt_alloc(sizeof *e);
+  e->hash = hash;
+  e->args = dbt_alloc((n + 1) * sizeof *e->args);
+  memcpy(e->args, args, n * sizeof *args);
+  e->value = value;
+  e->chain = m->buckets[hash & (m->nbuckets - 1)];
+  m->buckets[hash & (m->nbuckets - 1)] = e;
+  e->newer = NULL;
+  if (m->newest != NULL) {
+    m->newest->newer = e;
+  } else {
+    m->oldest = e;
+  }
+  m->newest = e;
+  m->count++;
+}
+
+static void memo_clear(Memo *m) {
+  while (m->oldest != NULL) {
+    memo_drop_oldest(m);
+  }
+}
+
+// CALLS
+
+// Call with arguments that have already been evaluated; a memoized function
+// with a cached result for them isn't called at all
+static V apply(const Fn *f, const V *args, const char *context) {
+  uint64_t hash = UINT64_C(0xcbf29ce484222325);
+  int cached = f->memo != NULL;
+  for (int i = 0; cached && i < f->nparams; i++) {
+    cached = hash_key(args[i], &hash);
+  }
+  if (cached) {
+    const Entry *e = memo_find(f->memo, args, f->nparams, hash);
+    if (e != NULL) {
+      return e->value;
+    }
+  }
+  if (depth >= DBT_MAX_DEPTH) {
+    return dbt_exception(E_STACK_OVERFLOW, context,
+                         "maximum call depth of %d exceeded", DBT_MAX_DEPTH);
+  }
+  dbt_push_scope();
+  bind(SELF_SLOT, 0, function_value(f));
+  for (int i = 0; i < f->nparams; i++) {
+    bind(f->slots[i], 0, args[i]);
+  }
+  depth++;
+  V rc = f->body(context);
+  depth--;
+  dbt_pop_scope();
+  if (cached && rc.type != T_EXCEPTION) {
+    memo_insert(f->memo, args, f->nparams, hash, rc);
+  }
+  return rc;
+}
+
+// Each argument is evaluated like a block of its own
+static V call_function(const Fn *f, const Thunk *thunks, int n,
+                       const char *context) {
+  V args[n + 1];
+  for (int i = 0; i < n; i++) {
+    dbt_push_scope();
+    args[i] = dbt_leave_block(thunks[i](), context);
+    dbt_pop_scope();
+  }
+  return apply(f, args, context);
+}
+
+static V dbt_define(const Def *def) {
+  const Binding *b = lookup(def->slot);
+  if (b != NULL && b->level + 1 == nscopes) {
+    return dbt_exception(E_REDEF, "", "attempt to redefine %s", def->id);
+  }
+  V f = function_value(def->fn);
+  bind(def->slot, 1, f);
+  return f;
+}
+
+static V dbt_list(size_t n, const Thunk *items) {
+  const List *l = &empty_list;
+  for (size_t i = 0; i < n; i++) {
+    l = list_push(l, items[i]());
+  }
+  return list_value(l);
+}
+
+static Prim find_primitive(const char *id);
+
+static V dbt_call(Site *site) {
+  const Binding *b = lookup(site->slot);
+  if (b != NULL) {
+    if (!b->function) {
+      // This value has already been evaluated, i.e., it's a passed param
+      return b->value;
+    }
+    const Fn *f = b->value.as.fn;
+    if (site->nargs != f->nparams) {
+      return arity(site->id, site->nargs, f->nparams);
+    }
+    return call_function(f, site->args, site->nargs, site->id);
+  }
+  if (site->kind == SITE_NEW) {
+    if (strcmp(site->id, "$") == 0) {
+      site->kind = SITE_DOLLAR;
+    } else {
+      const char *id = site->id;
+      site->prim = find_primitive(id);
+      site->kind = (strcmp(id, "?") == 0 || strcmp(id, "catch") == 0 ||
+                    strcmp(id, "ensure") == 0 || strcmp(id, "wrap") == 0) ?
+        SITE_CONTROL : SITE_PRIMITIVE;
+    }
+  }
+  if (site->kind == SITE_DOLLAR) {
+    if (site->nargs < 1) {
+      return error(E_ARITY, site->id, "expected at least 1 argument but got 0");
+    }
+    V f = site->args[0]();
+    if (f.type == T_EXCEPTION) {
+      return f;
+    }
+    if (f.type != T_FUNCTION) {
+      return error(E_TYPE, site->id, "function expected as first argument");
+    }
+    if (site->nargs - 1 != f.as.fn->nparams) {
+      return dbt_exception(E_ARITY, site->id,
+                           "called function expected %d arguments but got %d",
+                           site->nargs - 1, f.as.fn->nparams);
+    }
+    return call_function(f.as.fn, site->args + 1, site->nargs - 1, site->id);
+  }
+  int n = site->nargs;
+  V args[n + 1];
+  for (int i = 0; i < n; i++) {
+    args[i] = site->args[i]();
+  }
+  if (site->kind == SITE_PRIMITIVE) {
+    for (int i = 0; i < n; i++) {
+      if (args[i].type == T_EXCEPTION) {
+        return args[i];
+      }
+    }
+  }
+  if (site->prim == NULL) {
+    return error(E_UNDEF, site->id, "function is not defined in scope");
+  }
+  return site->prim(site->id, args, n);
+}
+
+// PRIMITIVES
+
+static int add_overflows(int64_t a, int64_t b) {
+  return (b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b);
+}
+
+static int sub_overflows(int64_t a, int64_t b) {
+  return (b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b);
+}
+
+static int mul_overflows(int64_t a, int64_t b) {
+  if (a == 0 || b == 0) {
+    return 0;
+  }
+  if (a > 0) {
+    return b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a;
+  }
+  return b > 0 ? a < INT64_MIN / b : b < INT64_MAX / a;
+}
+
+static V overflow(const char *id) {
+  return error(E_RUNTIME, id, "integer overflow");
+}
+
+static V div_by_zero(const char *id) {
+  return error(E_DIV_BY_ZERO, id, "integer division by zero");
+}
+
+static int is_digit(uint32_t c) {
+  return c >= '0' && c <= '9';
+}
+
+// Integers as Rust parses them: an optional sign, then digits only
+static int parse_int(Str s, int64_t *rc) {
+  size_t i = 0;
+  int negative = 0;
+  if (s.len > 0 && (s.data[0] == '+' || s.data[0] == '-')) {
+    negative = s.data[0] == '-';
+    i = 1;
+  }
+  if (i == s.len) {
+    return 0;
+  }
+  int64_t n = 0;
+  for (; i < s.len; i++) {
+    if (!is_digit((unsigned char) s.data[i])) {
+      return 0;
+    }
+    int d = s.data[i] - '0';
+    if (negative ? n < (INT64_MIN + d) / 10 : n > (INT64_MAX - d) / 10) {
+      return 0;
+    }
+    n = negative ? n * 10 - d : n * 10 + d;
+  }
+  *rc = n;
+  return 1;
+}
+
+// Sizes as Rust parses them, no sign other than +
+static int parse_size(const uint32_t *chars, size_t len, size_t *rc) {
+  size_t i = 0;
+  if (len > 0 && chars[0] == '+') {
+    i = 1;
+  }
+  if (i == len) {
+    return 0;
+  }
+  size_t n = 0;
+  for (; i < len; i++) {
+    if (!is_digit(chars[i])) {
+      return 0;
+    }
+    if (n > (SIZE_MAX - (chars[i] - '0')) / 10) {
+      return 0;
+    }
+    n = n * 10 + (chars[i] - '0');
+  }
+  *rc = n;
+  return 1;
+}
+
+static int match_word(Str s, size_t i, const char *word) {
+  size_t n = strlen(word);
+  if (s.len - i != n) {
+    return 0;
+  }
+  for (size_t k = 0; k < n; k++) {
+    char c = s.data[i + k];
+    if (c >= 'A' && c <= 'Z') {
+      c += 32;
+    }
+    if (c != word[k]) {
+      return 0;
+    }
+  }
+  return 1;
+}
+
+// Floats as Rust parses them, which is stricter than strtod: no spaces and
+// no hex, but inf, infinity and nan in any case
+static int parse_float(Str s, double *rc) {
+  size_t i = 0;
+  if (s.len > 0 && (s.data[0] == '+' || s.data[0] == '-')) {
+    i = 1;
+  }
+  if (match_word(s, i, "inf") || match_word(s, i, "infinity")) {
+    *rc = (s.data[0] == '-') ? -HUGE_VAL : HUGE_VAL;
+    return 1;
+  }
+  if (match_word(s, i, "nan")) {
+    *rc = NAN;
+    return 1;
+  }
+  size_t digits = 0;
+  while (i < s.len && is_digit((unsigned char) s.data[i])) {
+    i++;
+    digits++;
+  }
+  if (i < s.len && s.data[i] == '.') {
+    i++;
+    while (i < s.len && is_digit((unsigned char) s.data[i])) {
+      i++;
+      digits++;
+    }
+  }
+  if (digits == 0) {
+    return 0;
+  }
+  if (i < s.len && (s.data[i] == 'e' || s.data[i] == 'E')) {
+    i++;
+    if (i < s.len && (s.data[i] == '+' || s.data[i] == '-')) {
+      i++;
+    }
+    size_t start = i;
+    while (i < s.len && is_digit((unsigned char) s.data[i])) {
+      i++;
+    }
+    if (i == start) {
+      return 0;
+    }
+  }
+  if (i != s.len) {
+    return 0;
+  }
+  *rc = strtod(cstring(s), NULL);
+  return 1;
+}
+
+static V unparsable(const char *id, Str s) {
+  Buf b = {0};
+  buf_cstr(&b, "unable to parse string: ");
+  buf_str(&b, s);
+  return exception_msg(E_PARSE, id, buf_contents(&b));
+}
+
+static V p_int(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type == T_FLOAT) {
+    // Saturating, as Rust's as does it
+    double x = args[0].as.f;
+    if (x != x) {
+      return dbt_int(0);
+    } else if (x >= 9223372036854775807.0) {
+      return dbt_int(INT64_MAX);
+    } else if (x <= -9223372036854775808.0) {
+      return dbt_int(INT64_MIN);
+    }
+    return dbt_int((int64_t) x);
+  } else if (args[0].type == T_STRING) {
+    int64_t x;
+    if (parse_int(*args[0].as.s, &x)) {
+      return dbt_int(x);
+    }
+    return unparsable(id, *args[0].as.s);
+  }
+  return error(E_TYPE, id, "float or string argument expected");
+}
+
+static V p_float(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type == T_INTEGER) {
+    return dbt_float((double) args[0].as.i);
+  } else if (args[0].type == T_STRING) {
+    double x;
+    if (parse_float(*args[0].as.s, &x)) {
+      return dbt_float(x);
+    }
+    return unparsable(id, *args[0].as.s);
+  }
+  return error(E_TYPE, id, "int or string argument expected");
+}
+
+static V p_string(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  Buf b = {0};
+  show(&b, args[0]);
+  return buf_value(&b);
+}
+
+static V p_print(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type != T_STRING) {
+    return error(E_TYPE, id, "string argument expected");
+  }
+  fwrite(args[0].as.s->data, 1, args[0].as.s->len, stdout);
+  fputc('\n', stdout);
+  return dbt_nil();
+}
+
+static int is_number(V v) {
+  return v.type == T_INTEGER || v.type == T_FLOAT;
+}
+
+static double as_float(V v) {
+  return v.type == T_INTEGER ? (double) v.as.i : v.as.f;
+}
+
+static V p_add(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  V a = args[0], b = args[1];
+  switch (a.type) {
+  case T_INTEGER:
+  case T_FLOAT:
+    if (!is_number(b)) {
+      return error(E_MISMATCH, id, "mismatched argument types");
+    }
+    if (a.type == T_INTEGER && b.type == T_INTEGER) {
+      if (add_overflows(a.as.i, b.as.i)) {
+        return overflow(id);
+      }
+      return dbt_int(a.as.i + b.as.i);
+    }
+    return dbt_float(as_float(a) + as_float(b));
+  case T_STRING: {
+    if (b.type != T_STRING) {
+      return error(E_MISMATCH, id, "mismatched argument types");
+    }
+    Buf s = {0};
+    buf_str(&s, *a.as.s);
+    buf_str(&s, *b.as.s);
+    return buf_value(&s);
+  }
+  case T_LIST:
+    if (b.type == T_LIST) {
+      return list_value(list_concat(a.as.l, b.as.l));
+    }
+    return list_value(list_push(a.as.l, b));
+  default:
+    return error(E_TYPE, id, "numbers, strings, or list arguments expected");
+  }
+}
+
+// -, * and /, which only differ in the arithmetic
+static V arithmetic(const char *id, V *args, int n, char op) {
+  EXPECT_ARGS(2);
+  V a = args[0], b = args[1];
+  if (!is_number(a) || !is_number(b)) {
+    return error(E_TYPE, id, "numeric arguments expected");
+  }
+  if (a.type == T_INTEGER && b.type == T_INTEGER) {
+    int64_t x = a.as.i, y = b.as.i;
+    switch (op) {
+    case '-':
+      return sub_overflows(x, y) ? overflow(id) : dbt_int(x - y);
+    case '*':
+      return mul_overflows(x, y) ? overflow(id) : dbt_int(x * y);
+    default:
+      if (y == 0) {
+        return div_by_zero(id);
+      }
+      return (x == INT64_MIN && y == -1) ? overflow(id) : dbt_int(x / y);
+    }
+  }
+  double x = as_float(a), y = as_float(b);
+  switch (op) {
+  case '-':
+    return dbt_float(x - y);
+  case '*':
+    return dbt_float(x * y);
+  default:
+    return dbt_float(x / y);
+  }
+}
+
+static V p_subtract(const char *id, V *args, int n) {
+  return arithmetic(id, args, n, '-');
+}
+
+static V p_multiply(const char *id, V *args, int n) {
+  return arithmetic(id, args, n, '*');
+}
+
+static V p_divide(const char *id, V *args, int n) {
+  return arithmetic(id, args, n, '/');
+}
+
+static V p_remainder(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  if (args[0].type != T_INTEGER || args[1].type != T_INTEGER) {
+    return error(E_TYPE, id, "integer arguments expected");
+  }
+  int64_t x = args[0].as.i, y = args[1].as.i;
+  if (y == 0) {
+    return div_by_zero(id);
+  }
+  return (x == INT64_MIN && y == -1) ? overflow(id) : dbt_int(x % y);
+}
+
+static int is_boolean(V v) {
+  return v.type == T_TRUE || v.type == T_FALSE;
+}
+
+static V p_not(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (!is_boolean(args[0])) {
+    return error(E_TYPE, id, "boolean argument expected");
+  }
+  return dbt_bool(args[0].type == T_FALSE);
+}
+
+static V p_and(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  if (!is_boolean(args[0]) || !is_boolean(args[1])) {
+    return error(E_TYPE, id, "boolean arguments expected");
+  }
+  return dbt_bool(args[0].type == T_TRUE && args[1].type == T_TRUE);
+}
+
+static V p_or(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  if (!is_boolean(args[0]) || !is_boolean(args[1])) {
+    return error(E_TYPE, id, "boolean arguments expected");
+  }
+  return dbt_bool(args[0].type == T_TRUE || args[1].type == T_TRUE);
+}
+
+static V p_if(const char *id, V *args, int n) {
+  EXPECT_ARGS(3);
+  switch (args[0].type) {
+  case T_EXCEPTION:
+    return args[0];
+  case T_TRUE:
+    return args[1];
+  case T_FALSE:
+    return args[2];
+  default:
+    return error(E_TYPE, id, "expected boolean for first argument");
+  }
+}
+
+static V p_equal(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  return dbt_bool(equal(args[0], args[1]));
+}
+
+static V compare_numbers(const char *id, V *args, int n, int greater) {
+  EXPECT_ARGS(2);
+  V a = args[0], b = args[1];
+  if (!is_number(a) || !is_number(b)) {
+    return error(E_TYPE, id, "numeric arguments expected");
+  }
+  if (a.type == T_INTEGER && b.type == T_INTEGER) {
+    return dbt_bool(greater ? a.as.i > b.as.i : a.as.i < b.as.i);
+  }
+  double x = as_float(a), y = as_float(b);
+  return dbt_bool(greater ? x > y : x < y);
+}
+
+static V p_greater(const char *id, V *args, int n) {
+  return compare_numbers(id, args, n, 1);
+}
+
+static V p_less(const char *id, V *args, int n) {
+  return compare_numbers(id, args, n, 0);
+}
+
+// Byte offset of a character in a string, or its end if it's too short
+static size_t char_offset(Str s, size_t chars) {
+  size_t i = 0;
+  while (chars > 0 && i < s.len) {
+    utf8_next(s.data, &i);
+    chars--;
+  }
+  return i;
+}
+
+static V p_substr(const char *id, V *args, int n) {
+  EXPECT_ARGS(3);
+  if (args[0].type != T_STRING) {
+    return error(E_TYPE, id, "first argument must be string");
+  }
+  if (args[1].type != T_INTEGER) {
+    return error(E_TYPE, id, "second argument expects integer for start");
+  }
+  if (args[2].type != T_INTEGER) {
+    return error(E_TYPE, id, "third argument expects integer for length");
+  }
+  int64_t start = args[1].as.i, len = args[2].as.i;
+  if (start < 0 || len < 0) {
+    return error(E_RUNTIME, id, "start and length cannot be negative");
+  }
+  // Running off the end just truncates the substring
+  Str s = *args[0].as.s;
+  size_t a = char_offset(s, start);
+  Str rest = { s.len - a, s.data + a };
+  size_t b = char_offset(rest, len);
+  return string_of(rest.data, b);
+}
+
+static V p_strlen(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type != T_STRING) {
+    return error(E_TYPE, id, "string argument expected");
+  }
+  return dbt_int(utf8_count(args[0].as.s->data, args[0].as.s->len));
+}
+
+static V p_car(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type != T_LIST) {
+    return error(E_TYPE, id, "list argument expected");
+  }
+  if (args[0].as.l->len == 0) {
+    return error(E_RUNTIME, id, "attempt to get first item of empty list");
+  }
+  return list_get(args[0].as.l, 0);
+}
+
+static V p_cdr(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type != T_LIST) {
+    return error(E_TYPE, id, "list argument expected");
+  }
+  if (args[0].as.l->len == 0) {
+    return error(E_RUNTIME, id, "attempt to get rest of empty list");
+  }
+  if (args[0].as.l->len == 1) {
+    return dbt_nil();
+  }
+  return list_value(list_rest(args[0].as.l));
+}
+
+// The list catch turns an exception into: type, payload, stack, and (only if
+// there is one) the cause as another list of the same form
+static V exception_to_list(const Exc *e) {
+  Str *stack = stack_array(e);
+  Str name = flavor(e);
+  const List *l = list_push(&empty_list, string_of(name.data, name.len));
+  l = list_push(l, e->payload);
+  const List *s = &empty_list;
+  for (size_t i = 0; i < e->depth; i++) {
+    s = list_push(s, string_of(stack[i].data, stack[i].len));
+  }
+  l = list_push(l, list_value(s));
+  if (e->cause != NULL) {
+    l = list_push(l, exception_to_list(e->cause));
+  }
+  return list_value(l);
+}
+
+// Inverse of exception_to_list, for rethrow
+static const Exc *list_to_exception(const List *l) {
+  if (l->len != 3 && l->len != 4) {
+    return NULL;
+  }
+  V name = list_get(l, 0), stack = list_get(l, 2);
+  if (name.type != T_STRING || stack.type != T_LIST) {
+    return NULL;
+  }
+  // Catch never gives back a program-ending exception, or "ok"
+  int kind = kind_from_name(*name.as.s);
+  if (is_fatal(kind) || str_equal(*name.as.s, str_of("ok"))) {
+    return NULL;
+  }
+  Exc *e = exc_new(kind, *name.as.s, list_get(l, 1));
+  for (size_t i = 0; i < stack.as.l->len; i++) {
+    V s = list_get(stack.as.l, i);
+    if (s.type != T_STRING) {
+      return NULL;
+    }
+    e = exc_push(e, *s.as.s);
+  }
+  if (l->len == 4) {
+    V cause = list_get(l, 3);
+    if (cause.type != T_LIST) {
+      return NULL;
+    }
+    e->cause = list_to_exception(cause.as.l);
+    if (e->cause == NULL) {
+      return NULL;
+    }
+  }
+  return e;
+}
+
+static V p_catch(const char *id, V *args, int n) {
+  if (n != 1 && n != 2) {
+    return dbt_exception(E_ARITY, id, "expected 1 or 2 arguments but got %d",
+                         n);
+  }
+  if (n == 2) {
+    if (args[1].type == T_EXCEPTION) {
+      return args[1];
+    } else if (args[1].type != T_LIST) {
+      return error(E_TYPE, id,
+                   "second argument must be list of exception types");
+    }
+  }
+  if (args[0].type != T_EXCEPTION) {
+    const List *l = list_push(&empty_list, cstring_value("ok"));
+    return list_value(list_push(l, args[0]));
+  }
+  const Exc *e = args[0].as.e;
+  if (is_fatal(e->kind)) {
+    return args[0];
+  }
+  if (n == 2) {
+    int found = 0;
+    for (size_t i = 0; i < args[1].as.l->len; i++) {
+      V t = list_get(args[1].as.l, i);
+      if (t.type == T_STRING && str_equal(*t.as.s, flavor(e))) {
+        found = 1;
+        break;
+      }
+    }
+    if (!found) {
+      // Not one of the types we're catching, so let it keep going
+      return args[0];
+    }
+  }
+  return exception_to_list(e);
+}
+
+static V p_raise(const char *id, V *args, int n) {
+  if (n == 1) {
+    return exception_value(exc_new(E_ERROR, str_of(""), args[0]));
+  } else if (n == 2) {
+    if (args[0].type != T_STRING) {
+      return error(E_TYPE, id,
+                   "first argument must be string for exception type");
+    }
+    if (is_reserved(*args[0].as.s)) {
+      return reserved(id, *args[0].as.s);
+    }
+    return exception_value(exc_new(E_USER, *args[0].as.s, args[1]));
+  }
+  return dbt_exception(E_ARITY, id, "expected 1 or 2 arguments but got %d", n);
+}
+
+static V p_rethrow(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  const Exc *e = NULL;
+  if (args[0].type == T_LIST) {
+    e = list_to_exception(args[0].as.l);
+  }
+  if (e == NULL) {
+    return error(E_TYPE, id, "caught exception list expected");
+  }
+  return exception_value(e);
+}
+
+static V p_wrap(const char *id, V *args, int n) {
+  EXPECT_ARGS(3);
+  for (int i = 1; i < 3; i++) {
+    if (args[i].type == T_EXCEPTION) {
+      return args[i];
+    }
+  }
+  if (args[0].type != T_EXCEPTION || is_fatal(args[0].as.e->kind)) {
+    return args[0];
+  }
+  if (args[1].type != T_STRING) {
+    return error(E_TYPE, id,
+                 "second argument must be string for exception type");
+  }
+  if (is_reserved(*args[1].as.s)) {
+    return reserved(id, *args[1].as.s);
+  }
+  Exc *e = exc_new(E_USER, *args[1].as.s, args[2]);
+  e->cause = args[0].as.e;
+  return exception_value(e);
+}
+
+static V p_ensure(const char *id, V *args, int n) {
+  EXPECT_ARGS(2);
+  // Both arguments have been evaluated by now, in order, so the cleanup has
+  // already run whether or not the body failed
+  if (args[1].type != T_EXCEPTION) {
+    return args[0];
+  }
+  if (is_fatal(args[1].as.e->kind)) {
+    return args[1];
+  }
+  if (args[0].type == T_EXCEPTION && is_fatal(args[0].as.e->kind)) {
+    return args[0];
+  }
+  Exc *e = exc_copy(args[1].as.e);
+  if (args[0].type == T_EXCEPTION && e->cause == NULL) {
+    e->cause = args[0].as.e;
+  }
+  return exception_value(e);
+}
+
+static V p_exit(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  if (args[0].type != T_INTEGER) {
+    return error(E_TYPE, id, "integer argument expected");
+  }
+  if (args[0].as.i < 0 || args[0].as.i > 255) {
+    return dbt_exception(E_RUNTIME, id,
+                         "exit code %" PRId64 " is not between 0 and 255",
+                         args[0].as.i);
+  }
+  return exception_value(exc_new(E_EXIT, str_of(""), args[0]));
+}
+
+static V p_return(const char *id, V *args, int n) {
+  EXPECT_ARGS(1);
+  return exception_value(exc_new(E_RETURN, str_of(""), args[0]));
+}
+
+// STRINGS
+
+// Byte offset of the first occurrence of needle at or after from, or -1
+static ptrdiff_t str_find(Str s, Str needle, size_t from) {
+  if (needle.len > s.len) {
+    return -1;
+  }
+  for (size_t i = from; i + needle.len <= s.len; i++) {
+    if (memcmp(s.data + i, needle.data, needle.len) == 0) {
+      return i;
+    }
+  }
+  return -1;
+}
+
+static V string_arg(const char *id, V *args, int i) {
+  if (args[i].type != T_STRING) {
+    return dbt_exception(E_TYPE, id, "string expected for argument %d", i + 1);
+  }
+  return args[i];
+}
+
+static V p_strings(const char *id, V *args, int n) {
+  int count = 1;
+  if (strcmp(id, "replace") == 0) {
+    count = 3;
+  } else if (strcmp(id, "split") == 0 || strcmp(id, "join") == 0 ||
+             strcmp(id, "find") == 0 || strcmp(id, "contains?") == 0 ||
+             strcmp(id, "starts_with?") == 0 ||
+             strcmp(id, "ends_with?") == 0 || strcmp(id, "repeat") == 0) {
+    count = 2;
+  }
+  EXPECT_ARGS(count);
+
+  // The odd ones out that don't take a string first
+  if (strcmp(id, "join") == 0) {
+    V sep = string_arg(id, args, 1);
+    if (sep.type == T_EXCEPTION) {
+      return sep;
+    }
+    Buf b = {0};
+    // nil being what cdr leaves at the end of a list
+    if (args[0].type == T_NIL) {
+      return buf_value(&b);
+    }
+    if (args[0].type != T_LIST) {
+      return error(E_TYPE, id, "list of strings expected for argument 1");
+    }
+    for (size_t i = 0; i < args[0].as.l->len; i++) {
+      V item = list_get(args[0].as.l, i);
+      if (item.type != T_STRING) {
+        return error(E_TYPE, id, "list of strings expected for argument 1");
+      }
+      if (i > 0) {
+        buf_str(&b, *sep.as.s);
+      }
+      buf_str(&b, *item.as.s);
+    }
+    return buf_value(&b);
+  }
+  if (strcmp(id, "chr") == 0) {
+    if (args[0].type != T_INTEGER) {
+      return error(E_TYPE, id, "integer argument expected");
+    }
+    int64_t c = args[0].as.i;
+    if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c <= 0xdfff)) {
+      return dbt_exception(E_RUNTIME, id, "%" PRId64 " is not a valid character",
+                           c);
+    }
+    Buf b = {0};
+    buf_char(&b, c);
+    return buf_value(&b);
+  }
+
+  V sv = string_arg(id, args, 0);
+  if (sv.type == T_EXCEPTION) {
+    return sv;
+  }
+  Str s = *sv.as.s;
+  if (strcmp(id, "split") == 0) {
+    V sep = string_arg(id, args, 1);
+    if (sep.type == T_EXCEPTION) {
+      return sep;
+    }
+    if (sep.as.s->len == 0) {
+      return error(E_RUNTIME, id, "cannot split on empty string (use chars)");
+    }
+    const List *l = &empty_list;
+    size_t last = 0;
+    ptrdiff_t at;
+    while ((at = str_find(s, *sep.as.s, last)) >= 0) {
+      l = list_push(l, string_of(s.data + last, at - last));
+      last = at + sep.as.s->len;
+    }
+    l = list_push(l, string_of(s.data + last, s.len - last));
+    return list_value(l);
+  }
+  if (strcmp(id, "find") == 0) {
+    V sub = string_arg(id, args, 1);
+    if (sub.type == T_EXCEPTION) {
+      return sub;
+    }
+    ptrdiff_t at = str_find(s, *sub.as.s, 0);
+    if (at < 0) {
+      return dbt_nil();
+    }
+    return dbt_int(utf8_count(s.data, at));
+  }
+  if (strcmp(id, "contains?") == 0 || strcmp(id, "starts_with?") == 0 ||
+      strcmp(id, "ends_with?") == 0) {
+    V subv = string_arg(id, args, 1);
+    if (subv.type == T_EXCEPTION) {
+      return subv;
+    }
+    Str sub = *subv.as.s;
+    if (strcmp(id, "contains?") == 0) {
+      return dbt_bool(str_find(s, sub, 0) >= 0);
+    } else if (sub.len > s.len) {
+      return dbt_false();
+    } else if (strcmp(id, "starts_with?") == 0) {
+      return dbt_bool(memcmp(s.data, sub.data, sub.len) == 0);
+    }
+    return dbt_bool(memcmp(s.data + s.len - sub.len, sub.data, sub.len) == 0);
+  }
+  if (strcmp(id, "replace") == 0) {
+    V from = string_arg(id, args, 1);
+    if (from.type == T_EXCEPTION) {
+      return from;
+    }
+    V to = string_arg(id, args, 2);
+    if (to.type == T_EXCEPTION) {
+      return to;
+    }
+    if (from.as.s->len == 0) {
+      return error(E_RUNTIME, id, "cannot replace empty string");
+    }
+    Buf b = {0};
+    size_t last = 0;
+    ptrdiff_t at;
+    while ((at = str_find(s, *from.as.s, last)) >= 0) {
+      buf_add(&b, s.data + last, at - last);
+      buf_str(&b, *to.as.s);
+      last = at + from.as.s->len;
+    }
+    buf_add(&b, s.data + last, s.len - last);
+    return buf_value(&b);
+  }
+  if (strcmp(id, "trim") == 0) {
+    size_t nchars;
+    uint32_t *chars = utf8_chars(s, &nchars);
+    size_t a = 0, b = nchars;
+    while (a < b && is_space(chars[a])) {
+      a++;
+    }
+    while (b > a && is_space(chars[b - 1])) {
+      b--;
+    }
+    return chars_value(chars + a, b - a);
+  }
+  if (strcmp(id, "upper") == 0) {
+    return upper_string(s);
+  }
+  if (strcmp(id, "lower") == 0) {
+    return lower_string(s);
+  }
+  if (strcmp(id, "chars") == 0) {
+    const List *l = &empty_list;
+    size_t i = 0;
+    while (i < s.len) {
+      size_t start = i;
+      utf8_next(s.data, &i);
+      l = list_push(l, string_of(s.data + start, i - start));
+    }
+    return list_value(l);
+  }
+  if (strcmp(id, "ord") == 0) {
+    size_t i = 0;
+    if (s.len > 0) {
+      uint32_t c = utf8_next(s.data, &i);
+      if (i == s.len) {
+        return dbt_int(c);
+      }
+    }
+    return error(E_RUNTIME, id, "string of exactly one character expected");
+  }
+  if (strcmp(id, "repeat") == 0) {
+    if (args[1].type != T_INTEGER) {
+      return error(E_TYPE, id, "integer expected for argument 2");
+    }
+    int64_t count = args[1].as.i;
+    if (count < 0) {
+      return error(E_RUNTIME, id, "repeat count cannot be negative");
+    }
+    if (s.len > 0 && (uint64_t) count > SIZE_MAX / s.len) {
+      return error(E_RUNTIME, id, "repeated string is too long");
+    }
+    Buf b = {0};
+    for (int64_t i = 0; i < count && s.len > 0; i++) {
+      buf_str(&b, s);
+    }
+    return buf_value(&b);
+  }
+  return error(E_UNDEF, id, "function is not defined in scope");
+}
+
+// A parsed {index:spec} placeholder for format
+typedef struct Placeholder {
+  int has_index;
+  size_t index;
+  uint32_t fill;
+  uint32_t align;
+  int zero;
+  size_t width;
+  int has_precision;
+  size_t precision;
+} Placeholder;
+
+static int is_align(uint32_t c) {
+  return c == '<' || c == '>' || c == '^';
+}
+
+static int parse_placeholder(const uint32_t *inner, size_t len,
+                             Placeholder *p) {
+  size_t colon = 0;
+  while (colon < len && inner[colon] != ':') {
+    colon++;
+  }
+  const uint32_t *spec = colon < len ? inner + colon + 1 : inner + len;
+  size_t nspec = colon < len ? len - colon - 1 : 0;
+  memset(p, 0, sizeof *p);
+  p->fill = ' ';
+  if (colon > 0) {
+    if (!parse_size(inner, colon, &p->index)) {
+      return 0;
+    }
+    p->has_index = 1;
+  }
+  size_t i = 0;
+  if (nspec >= 2 && is_align(spec[1])) {
+    p->fill = spec[0];
+    p->align = spec[1];
+    i = 2;
+  } else if (nspec >= 1 && is_align(spec[0])) {
+    p->align = spec[0];
+    i = 1;
+  }
+  if (!p->align && i < nspec && spec[i] == '0') {
+    p->zero = 1;
+    i++;
+  }
+  size_t start = i;
+  while (i < nspec && is_digit(spec[i])) {
+    i++;
+  }
+  if (i > start && !parse_size(spec + start, i - start, &p->width)) {
+    return 0;
+  }
+  if (i < nspec && spec[i] == '.') {
+    i++;
+    start = i;
+    while (i < nspec && is_digit(spec[i])) {
+      i++;
+    }
+    if (i == start) {
+      return 0;
+    }
+    if (!parse_size(spec + start, i - start, &p->precision)) {
+      return 0;
+    }
+    p->has_precision = 1;
+  }
+  return i == nspec;
+}
+
+// Values are rendered as by string(), except that strings go in as they are
+static void render(Buf *out, V value, const Placeholder *p) {
+  Buf text = {0};
+  if (value.type == T_FLOAT && p->has_precision) {
+    show_fixed(&text, value.as.f, p->precision);
+  } else if (value.type == T_STRING && p->has_precision) {
+    Str s = *value.as.s;
+    buf_add(&text, s.data, char_offset(s, p->precision));
+  } else if (value.type == T_STRING) {
+    buf_str(&text, *value.as.s);
+  } else {
+    show(&text, value);
+  }
+  size_t len = utf8_count(text.data, text.len);
+  if (len >= p->width) {
+    buf_add(out, text.data, text.len);
+    return;
+  }
+  size_t pad = p->width - len;
+  int numeric = is_number(value);
+  if (p->zero && numeric) {
+    // Zeros go after the sign
+    size_t sign = (text.len > 0 && text.data[0] == '-') ? 1 : 0;
+    buf_add(out, text.data, sign);
+    for (size_t i = 0; i < pad; i++) {
+      buf_cstr(out, "0");
+    }
+    buf_add(out, text.data + sign, text.len - sign);
+    return;
+  }
+  uint32_t align = p->align ? p->align : (numeric ? '>' : '<');
+  size_t before = align == '>' ? pad : (align == '^' ? pad / 2 : 0);
+  for (size_t i = 0; i < before; i++) {
+    buf_char(out, p->fill);
+  }
+  buf_add(out, text.data, text.len);
+  for (size_t i = before; i < pad; i++) {
+    buf_char(out, p->fill);
+  }
+}
+
+static V p_format(const char *id, V *args, int n) {
+  if (n < 1) {
+    return error(E_ARITY, id, "expected at least 1 argument but got 0");
+  }
+  V tv = string_arg(id, args, 0);
+  if (tv.type == T_EXCEPTION) {
+    return tv;
+  }
+  size_t len;
+  uint32_t *template = utf8_chars(*tv.as.s, &len);
+  V *rest = args + 1;
+  size_t nrest = n - 1;
+  Buf rc = {0};
+  size_t next = 0;
+  size_t i = 0;
+  while (i < len) {
+    uint32_t c = template[i];
+    if ((c == '{' || c == '}') && i + 1 < len && template[i + 1] == c) {
+      buf_char(&rc, c);
+      i += 2;
+    } else if (c == '}') {
+      return error(E_PARSE, id, "unmatched } in format string");
+    } else if (c == '{') {
+      size_t end = i + 1;
+      while (end < len && template[end] != '}') {
+        end++;
+      }
+      if (end == len) {
+        return error(E_PARSE, id, "unmatched { in format string");
+      }
+      Placeholder p;
+      if (!parse_placeholder(template + i + 1, end - i - 1, &p)) {
+        Buf msg = {0};
+        buf_cstr(&msg, "bad placeholder: {");
+        for (size_t k = i + 1; k < end; k++) {
+          buf_char(&msg, template[k]);
+        }
+        buf_cstr(&msg, "}");
+        return exception_msg(E_PARSE, id, buf_contents(&msg));
+      }
+      size_t index = p.has_index ? p.index : next++;
+      if (index >= nrest) {
+        return dbt_exception(E_ARITY, id, "no argument %zu for format string",
+                             index);
+      }
+      render(&rc, rest[index], &p);
+      i = end + 1;
+    } else {
+      buf_char(&rc, c);
+      i++;
+    }
+  }
+  return buf_value(&rc);
+}
+
+// The pieces of an interpolated string joined up, each rendered as format's
+// {} would
+static V p_interpolate(const char *id, V *args, int n) {
+  Buf rc = {0};
+  for (int i = 0; i < n; i++) {
+    if (args[i].type == T_STRING) {
+      buf_str(&rc, *args[i].as.s);
+    } else {
+      show(&rc, args[i]);
+    }
+  }
+  return buf_value(&rc);
+}
+
+// REGULAR EXPRESSIONS, as in regex.rs
+
+enum {
+  N_EMPTY, N_CHAR, N_ANY, N_CLASS, N_START, N_END, N_BOUNDARY, N_GROUP,
+  N_CONCAT, N_ALT, N_REPEAT
+};
+
+typedef struct Range {
+  uint32_t lo, hi;
+} Range;
+
+// No maximum for a repeat
+#define UNBOUNDED SIZE_MAX
+
+typedef struct Node {
+  int type;
+  uint32_t c;
+  Range *ranges;
+  size_t nranges;
+  // Negated class, \b rather than \B, or greedy repeat
+  int flag;
+  struct Node **nodes;
+  size_t nnodes;
+  // Capture group number, 0 for (?:...)
+  size_t group;
+  size_t min, max;
+} Node;
+
+typedef struct RegexParser {
+  uint32_t *chars;
+  size_t len, index, groups;
+  // Groups and repeats around what's being parsed
+  size_t depth;
+  Buf error;
+} RegexParser;
+
+static Node *node_new(int type) {
+  Node *n = dbt_alloc(sizeof *n);
+  memset(n, 0, sizeof *n);
+  n->type = type;
+  return n;
+}
+
+static void node_add(Node *parent, Node *child) {
+  parent->nodes = dbt_realloc(parent->nodes,
+                              (parent->nnodes + 1) * sizeof *parent->nodes);
+  parent->nodes[parent->nnodes++] = child;
+}
+
+static void range_add(Range **ranges, size_t *n, uint32_t lo, uint32_t hi) {
+  *ranges = dbt_realloc(*ranges, (*n + 1) * sizeof **ranges);
+  (*ranges)[*n].lo = lo;
+  (*ranges)[*n].hi = hi;
+  (*n)++;
+}
+
+static Node *regex_fail(RegexParser *p, const char *msg) {
+  buf_cstr(&p->error, msg);
+  return NULL;
+}
+
+// The next character, or -1 at the end
+static int64_t re_peek(RegexParser *p) {
+  return p->index < p->len ? (int64_t) p->chars[p->index] : -1;
+}
+
+// Character ranges for \d, \w and \s
+static int shorthand(uint32_t c, Range **ranges, size_t *n) {
+  switch (c) {
+  case 'd':
+    range_add(ranges, n, '0', '9');
+    return 1;
+  case 'w':
+    range_add(ranges, n, 'a', 'z');
+    range_add(ranges, n, 'A', 'Z');
+    range_add(ranges, n, '0', '9');
+    range_add(ranges, n, '_', '_');
+    return 1;
+  case 's':
+    range_add(ranges, n, ' ', ' ');
+    range_add(ranges, n, '\t', '\r');
+    return 1;
+  default:
+    return 0;
+  }
+}
+
+static uint32_t escaped(uint32_t c) {
+  switch (c) {
+  case 'n': return '\n';
+  case 't': return '\t';
+  case 'r': return '\r';
+  default: return c;
+  }
+}
+
+static int is_word(uint32_t c) {
+  return is_alphanumeric(c) || c == '_';
+}
+
+static Node *parse_alt(RegexParser *p);
+
+static int parse_number(RegexParser *p, size_t *rc) {
+  size_t start = p->index;
+  while (re_peek(p) >= '0' && re_peek(p) <= '9') {
+    p->index++;
+  }
+  if (p->index == start) {
+    return 0;
+  }
+  return parse_size(p->chars + start, p->index - start, rc);
+}
+
+// {n}, {n,} or {n,m}, leaving the index alone if it's none of those
+static int parse_count(RegexParser *p, size_t *min, size_t *max) {
+  size_t start = p->index;
+  int ok = 0;
+  p->index++;
+  if (parse_number(p, min)) {
+    if (re_peek(p) == '}') {
+      *max = *min;
+      ok = 1;
+    } else if (re_peek(p) == ',') {
+      p->index++;
+      if (!parse_number(p, max)) {
+        *max = UNBOUNDED;
+      }
+      ok = re_peek(p) == '}';
+    }
+  }
+  if (ok) {
+    p->index++;
+  } else {
+    p->index = start;
+  }
+  return ok;
+}
+
+static Node *parse_class(RegexParser *p) {
+  Node *node = node_new(N_CLASS);
+  if (re_peek(p) == '^') {
+    p->index++;
+    node->flag = 1;
+  }
+  int first = 1;
+  for (;;) {
+    if (re_peek(p) < 0) {
+      return regex_fail(p, "missing ]");
+    }
+    uint32_t c = p->chars[p->index++];
+    // ] right at the start is just a ]
+    if (c == ']' && !first) {
+      break;
+    }
+    first = 0;
+    uint32_t lo = c;
+    if (c == '\\') {
+      if (re_peek(p) < 0) {
+        return regex_fail(p, "missing ]");
+      }
+      uint32_t e = p->chars[p->index++];
+      if (shorthand(e, &node->ranges, &node->nranges)) {
+        continue;
+      }
+      if (e == 'D' || e == 'W' || e == 'S') {
+        buf_cstr(&p->error, "\\");
+        buf_char(&p->error, e);
+        return regex_fail(p, " is not supported inside []");
+      }
+      lo = escaped(e);
+    }
+    // a - at either end is just a -
+    if (re_peek(p) == '-' && p->index + 1 < p->len &&
+        p->chars[p->index + 1] != ']') {
+      p->index++;
+      uint32_t hi = p->chars[p->index++];
+      if (hi == '\\') {
+        if (re_peek(p) < 0) {
+          return regex_fail(p, "missing ]");
+        }
+        hi = escaped(p->chars[p->index++]);
+      }
+      if (hi < lo) {
+        buf_cstr(&p->error, "bad range ");
+        buf_char(&p->error, lo);
+        buf_cstr(&p->error, "-");
+        buf_char(&p->error, hi);
+        return NULL;
+      }
+      range_add(&node->ranges, &node->nranges, lo, hi);
+    } else {
+      range_add(&node->ranges, &node->nranges, lo, lo);
+    }
+  }
+  return node;
+}
+
+static int nest(RegexParser *p) {
+  if (++p->depth > MAX_NESTING) {
+    buf_printf(&p->error, "nested over %d deep", MAX_NESTING);
+    return 0;
+  }
+  return 1;
+}
+
+static Node *parse_atom(RegexParser *p) {
+  if (re_peek(p) < 0) {
+    return regex_fail(p, "unexpected end of pattern");
+  }
+  uint32_t c = p->chars[p->index++];
+  switch (c) {
+  case '(': {
+    Node *node = node_new(N_GROUP);
+    if (p->index + 1 < p->len && p->chars[p->index] == '?' &&
+        p->chars[p->index + 1] == ':') {
+      p->index += 2;
+    } else {
+      node->group = ++p->groups;
+    }
+    if (!nest(p)) {
+      return NULL;
+    }
+    Node *inner = parse_alt(p);
+    if (inner == NULL) {
+      return NULL;
+    }
+    if (re_peek(p) != ')') {
+      return regex_fail(p, "missing )");
+    }
+    p->index++;
+    p->depth--;
+    node_add(node, inner);
+    return node;
+  }
+  case ')':
+    return regex_fail(p, "unmatched )");
+  case '*':
+  case '+':
+  case '?':
+    buf_cstr(&p->error, "nothing to repeat before ");
+    buf_char(&p->error, c);
+    return NULL;
+  case '[':
+    return parse_class(p);
+  case '.':
+    return node_new(N_ANY);
+  case '^':
+    return node_new(N_START);
+  case '$':
+    return node_new(N_END);
+  case '\\': {
+    if (re_peek(p) < 0) {
+      return regex_fail(p, "pattern ends with \\");
+    }
+    uint32_t e = p->chars[p->index++];
+    if (e == 'b' || e == 'B') {
+      Node *node = node_new(N_BOUNDARY);
+      node->flag = e == 'b';
+      return node;
+    }
+    Node *node = node_new(N_CLASS);
+    if (e == 'D' || e == 'W' || e == 'S') {
+      shorthand(e + 32, &node->ranges, &node->nranges);
+      node->flag = 1;
+      return node;
+    }
+    if (shorthand(e, &node->ranges, &node->nranges)) {
+      return node;
+    }
+    node->type = N_CHAR;
+    node->c = escaped(e);
+    return node;
+  }
+  default: {
+    Node *node = node_new(N_CHAR);
+    node->c = c;
+    return node;
+  }
+  }
+}
+
+static Node *parse_repeat(RegexParser *p) {
+  Node *node = parse_atom(p);
+  if (node == NULL) {
+    return NULL;
+  }
+  size_t depth = p->depth;
+  for (;;) {
+    size_t min, max;
+    int64_t c = re_peek(p);
+    if (c == '*') {
+      p->index++;
+      min = 0;
+      max = UNBOUNDED;
+    } else if (c == '+') {
+      p->index++;
+      min = 1;
+      max = UNBOUNDED;
+    } else if (c == '?') {
+      p->index++;
+      min = 0;
+      max = 1;
+    } else if (c == '{') {
+      // not a count after all, so it's a literal { for the next atom
+      if (!parse_count(p, &min, &max)) {
+        break;
+      }
+    } else {
+      break;
+    }
+    if (max != UNBOUNDED && max < min) {
+      return regex_fail(p, "repeat maximum is less than minimum");
+    }
+    if (min > MAX_REPEAT || (max != UNBOUNDED && max > MAX_REPEAT)) {
+      buf_printf(&p->error, "repeat count over %d", MAX_REPEAT);
+      return NULL;
+    }
+    Node *repeat = node_new(N_REPEAT);
+    repeat->min = min;
+    repeat->max = max;
+    repeat->flag = 1;
+    if (re_peek(p) == '?') {
+      p->index++;
+      repeat->flag = 0;
+    }
+    if (!nest(p)) {
+      return NULL;
+    }
+    node_add(repeat, node);
+    node = repeat;
+  }
+  p->depth = depth;
+  return node;
+}
+
+static Node *parse_concat(RegexParser *p) {
+  Node *node = node_new(N_CONCAT);
+  for (;;) {
+    int64_t c = re_peek(p);
+    if (c < 0 || c == '|' || c == ')') {
+      break;
+    }
+    Node *n = parse_repeat(p);
+    if (n == NULL) {
+      return NULL;
+    }
+    node_add(node, n);
+  }
+  if (node->nnodes == 0) {
+    node->type = N_EMPTY;
+  } else if (node->nnodes == 1) {
+    return node->nodes[0];
+  }
+  return node;
+}
+
+static Node *parse_alt(RegexParser *p) {
+  Node *node = node_new(N_ALT);
+  Node *first = parse_concat(p);
+  if (first == NULL) {
+    return NULL;
+  }
+  node_add(node, first);
+  while (re_peek(p) == '|') {
+    p->index++;
+    Node *n = parse_concat(p);
+    if (n == NULL) {
+      return NULL;
+    }
+    node_add(node, n);
+  }
+  return node->nnodes == 1 ? node->nodes[0] : node;
+}
+
+enum {
+  I_CHAR, I_ANY, I_CLASS, I_START, I_END, I_BOUNDARY, I_SPLIT, I_JMP, I_SAVE,
+  I_MATCH
+};
+
+typedef struct Inst {
+  int op;
+  uint32_t c;
+  const Range *ranges;
+  size_t nranges;
+  int flag;
+  size_t x, y;
+} Inst;
+
+typedef struct Regex {
+  Inst *prog;
+  size_t len, cap;
+  size_t groups;
+} Regex;
+
+static size_t emit(Regex *re, int op) {
+  if (re->len == re->cap) {
+    re->cap = re->cap ? re->cap * 2 : 16;
+    re->prog = dbt_realloc(re->prog, re->cap * sizeof *re->prog);
+  }
+  memset(&re->prog[re->len], 0, sizeof *re->prog);
+  re->prog[re->len].op = op;
+  return re->len++;
+}
+
+static void set_split(Regex *re, size_t at, size_t x, size_t y) {
+  re->prog[at].op = I_SPLIT;
+  re->prog[at].x = x;
+  re->prog[at].y = y;
+}
+
+// Greedy repeats try another round first, lazy ones try leaving first
+static void branch(Regex *re, size_t at, size_t more, size_t exit,
+                   int greedy) {
+  if (greedy) {
+    set_split(re, at, more, exit);
+  } else {
+    set_split(re, at, exit, more);
+  }
+}
+
+static size_t add_sizes(size_t a, size_t b) {
+  return a > SIZE_MAX - b ? SIZE_MAX : a + b;
+}
+
+static size_t mul_sizes(size_t a, size_t b) {
+  return b != 0 && a > SIZE_MAX / b ? SIZE_MAX : a * b;
+}
+
+// Number of instructions compile_node gives for the node
+static size_t node_size(const Node *node) {
+  size_t total = 0, one, optional;
+  switch (node->type) {
+  case N_EMPTY:
+    return 0;
+  case N_GROUP:
+    return add_sizes(node_size(node->nodes[0]), node->group ? 2 : 0);
+  case N_CONCAT:
+    for (size_t i = 0; i < node->nnodes; i++) {
+      total = add_sizes(total, node_size(node->nodes[i]));
+    }
+    return total;
+  case N_ALT:
+    // A split and a jump for every alternative but the last
+    for (size_t i = 0; i < node->nnodes; i++) {
+      total = add_sizes(total, node_size(node->nodes[i]));
+    }
+    return add_sizes(total, (node->nnodes - 1) * 2);
+  case N_REPEAT:
+    one = node_size(node->nodes[0]);
+    if (node->max == UNBOUNDED) {
+      optional = add_sizes(one, 2);
+    } else {
+      optional = mul_sizes(add_sizes(one, 1), node->max - node->min);
+    }
+    return add_sizes(mul_sizes(one, node->min), optional);
+  default:
+    return 1;
+  }
+}
+
+static void compile_node(Regex *re, const Node *node) {
+  size_t at;
+  switch (node->type) {
+  case N_EMPTY:
+    break;
+  case N_CHAR:
+    at = emit(re, I_CHAR);
+    re->prog[at].c = node->c;
+    break;
+  case N_ANY:
+    emit(re, I_ANY);
+    break;
+  case N_CLASS:
+    at = emit(re, I_CLASS);
+    re->prog[at].ranges = node->ranges;
+    re->prog[at].nranges = node->nranges;
+    re->prog[at].flag = node->flag;
+    break;
+  case N_START:
+    emit(re, I_START);
+    break;
+  case N_END:
+    emit(re, I_END);
+    break;
+  case N_BOUNDARY:
+    at = emit(re, I_BOUNDARY);
+    re->prog[at].flag = node->flag;
+    break;
+  case N_GROUP:
+    if (node->group) {
+      at = emit(re, I_SAVE);
+      re->prog[at].x = node->group * 2;
+    }
+    compile_node(re, node->nodes[0]);
+    if (node->group) {
+      at = emit(re, I_SAVE);
+      re->prog[at].x = node->group * 2 + 1;
+    }
+    break;
+  case N_CONCAT:
+    for (size_t i = 0; i < node->nnodes; i++) {
+      compile_node(re, node->nodes[i]);
+    }
+    break;
+  case N_ALT: {
+    size_t *jumps = dbt_alloc(node->nnodes * sizeof *jumps);
+    size_t njumps = 0;
+    for (size_t i = 0; i < node->nnodes; i++) {
+      if (i == node->nnodes - 1) {
+        compile_node(re, node->nodes[i]);
+      } else {
+        size_t split = emit(re, I_SPLIT);
+        compile_node(re, node->nodes[i]);
+        jumps[njumps++] = emit(re, I_JMP);
+        set_split(re, split, split + 1, re->len);
+      }
+    }
+    for (size_t i = 0; i < njumps; i++) {
+      re->prog[jumps[i]].x = re->len;
+    }
+    free(jumps);
+    break;
+  }
+  case N_REPEAT:
+    for (size_t i = 0; i < node->min; i++) {
+      compile_node(re, node->nodes[0]);
+    }
+    if (node->max == UNBOUNDED) {
+      size_t split = emit(re, I_SPLIT);
+      compile_node(re, node->nodes[0]);
+      at = emit(re, I_JMP);
+      re->prog[at].x = split;
+      branch(re, split, split + 1, re->len, node->flag);
+    } else {
+      size_t count = node->max - node->min;
+      size_t *splits = dbt_alloc((count + 1) * sizeof *splits);
+      for (size_t i = 0; i < count; i++) {
+        splits[i] = emit(re, I_SPLIT);
+        compile_node(re, node->nodes[0]);
+      }
+      for (size_t i = 0; i < count; i++) {
+        branch(re, splits[i], splits[i] + 1, re->len, node->flag);
+      }
+      free(splits);
+    }
+    break;
+  }
+}
+
+// NULL with the message in *error if the pattern is no good
+static Regex *regex_new(Str pattern, Buf *error) {
+  RegexParser p;
+  memset(&p, 0, sizeof p);
+  p.chars = utf8_chars(pattern, &p.len);
+  Node *node = parse_alt(&p);
+  if (node != NULL && p.index < p.len) {
+    // the only thing that stops parse_alt early
+    node = regex_fail(&p, "unmatched )");
+  }
+  if (node != NULL && node_size(node) > MAX_PROGRAM) {
+    buf_printf(&p.error, "pattern compiles to over %d instructions",
+               MAX_PROGRAM);
+    node = NULL;
+  }
+  if (node == NULL) {
+    *error = p.error;
+    return NULL;
+  }
+  Regex *re = dbt_alloc(sizeof *re);
+  memset(re, 0, sizeof *re);
+  re->groups = p.groups;
+  emit(re, I_SAVE);
+  compile_node(re, node);
+  size_t at = emit(re, I_SAVE);
+  re->prog[at].x = 1;
+  emit(re, I_MATCH);
+  return re;
+}
+
+typedef struct Job {
+  int restore;
+  size_t a;
+  int64_t b;
+} Job;
+
+// Match starting at exactly start, filling in slots (-1 for unset)
+static int regex_run(const Regex *re, const uint32_t *input, size_t len,
+                     size_t start, uint64_t *visited, int64_t *slots) {
+  size_t width = len + 1;
+  size_t nslots = (re->groups + 1) * 2;
+  for (size_t i = 0; i < nslots; i++) {
+    slots[i] = -1;
+  }
+  size_t njobs = 1, cap = 16;
+  Job *jobs = dbt_alloc(cap * sizeof *jobs);
+  jobs[0].restore = 0;
+  jobs[0].a = 0;
+  jobs[0].b = start;
+  while (njobs > 0) {
+    Job job = jobs[--njobs];
+    if (job.restore) {
+      slots[job.a] = job.b;
+      continue;
+    }
+    size_t pc = job.a, pos = job.b;
+    for (;;) {
+      size_t bit = pc * width + pos;
+      if (visited[bit / 64] & ((uint64_t) 1 << (bit % 64))) {
+        break;
+      }
+      visited[bit / 64] |= (uint64_t) 1 << (bit % 64);
+      int64_t next = pos < len ? (int64_t) input[pos] : -1;
+      const Inst *inst = &re->prog[pc];
+      int failed = 0;
+      switch (inst->op) {
+      case I_CHAR:
+        if (next != (int64_t) inst->c) {
+          failed = 1;
+        } else {
+          pc++;
+          pos++;
+        }
+        break;
+      case I_ANY:
+        if (next < 0 || next == '\n') {
+          failed = 1;
+        } else {
+          pc++;
+          pos++;
+        }
+        break;
+      case I_CLASS: {
+        if (next < 0) {
+          failed = 1;
+          break;
+        }
+        int found = 0;
+        for (size_t i = 0; i < inst->nranges; i++) {
+          if (inst->ranges[i].lo <= next && next <= inst->ranges[i].hi) {
+            found = 1;
+            break;
+          }
+        }
+        if (found == inst->flag) {
+          failed = 1;
+        } else {
+          pc++;
+          pos++;
+        }
+        break;
+      }
+      case I_START:
+        if (pos != 0) {
+          failed = 1;
+        } else {
+          pc++;
+        }
+        break;
+      case I_END:
+        if (pos != len) {
+          failed = 1;
+        } else {
+          pc++;
+        }
+        break;
+      case I_BOUNDARY: {
+        int before = pos > 0 && is_word(input[pos - 1]);
+        int after = next >= 0 && is_word(next);
+        if ((before != after) != inst->flag) {
+          failed = 1;
+        } else {
+          pc++;
+        }
+        break;
+      }
+      case I_SPLIT:
+      case I_SAVE:
+        if (njobs == cap) {
+          cap *= 2;
+          jobs = dbt_realloc(jobs, cap * sizeof *jobs);
+        }
+        if (inst->op == I_SPLIT) {
+          jobs[njobs].restore = 0;
+          jobs[njobs].a = inst->y;
+          jobs[njobs].b = pos;
+          njobs++;
+          pc = inst->x;
+        } else {
+          jobs[njobs].restore = 1;
+          jobs[njobs].a = inst->x;
+          jobs[njobs].b = slots[inst->x];
+          njobs++;
+          slots[inst->x] = pos;
+          pc++;
+        }
+        break;
+      case I_JMP:
+        pc = inst->x;
+        break;
+      case I_MATCH:
+        free(jobs);
+        return 1;
+      }
+      if (failed) {
+        break;
+      }
+    }
+  }
+  free(jobs);
+  return 0;
+}
+
+// Leftmost match starting at or after start, with captures as pairs of
+// positions in caps, -1 for groups that didn't take part
+static int regex_find_at(const Regex *re, const uint32_t *input, size_t len,
+                         size_t start, int64_t *caps) {
+  size_t width = len + 1;
+  size_t bits = re->len * width;
+  uint64_t *visited = calloc((bits + 63) / 64 + 1, sizeof *visited);
+  if (visited == NULL) {
+    visited = dbt_alloc(0);
+  }
+  int found = 0;
+  for (size_t s = start; s < width; s++) {
+    if (regex_run(re, input, len, s, visited, caps)) {
+      found = 1;
+      break;
+    }
+  }
+  free(visited);
+  if (found) {
+    for (size_t g = 0; g <= re->groups; g++) {
+      if (caps[g * 2] < 0 || caps[g * 2 + 1] < 0) {
+        caps[g * 2] = caps[g * 2 + 1] = -1;
+      }
+    }
+  }
+  return found;
+}
+
+// Every non-overlapping match, left to right, as an array of captures
+static int64_t **regex_find_all(const Regex *re, const uint32_t *input,
+                                size_t len, size_t *count) {
+  int64_t **rc = NULL;
+  size_t start = 0;
+  *count = 0;
+  while (start <= len) {
+    int64_t *caps = dbt_alloc((re->groups + 1) * 2 * sizeof *caps);
+    if (!regex_find_at(re, input, len, start, caps)) {
+      free(caps);
+      break;
+    }
+    size_t a = caps[0], b = caps[1];
+    // step past empty matches so we don't find them forever
+    start = b > a ? b : b + 1;
+    rc = dbt_realloc(rc, (*count + 1) * sizeof *rc);
+    rc[(*count)++] = caps;
+  }
+  return rc;
+}
+
+// Captures as a list of the whole match followed by each group, with nil for
+// groups that didn't take part in the match
+static V captures_list(const Regex *re, const uint32_t *input,
+                       const int64_t *caps) {
+  const List *l = &empty_list;
+  for (size_t g = 0; g <= re->groups; g++) {
+    if (caps[g * 2] < 0) {
+      l = list_push(l, dbt_nil());
+    } else {
+      l = list_push(l, chars_value(input + caps[g * 2],
+                                   caps[g * 2 + 1] - caps[g * 2]));
+    }
+  }
+  return list_value(l);
+}
+
+static void add_chars(Buf *b, const uint32_t *chars, size_t n) {
+  for (size_t i = 0; i < n; i++) {
+    buf_char(b, chars[i]);
+  }
+}
+
+// Replacement text with $0-$9 filled in from the captures, $$ being a $
+static void expand(Buf *b, const Regex *re, const uint32_t *replacement,
+                   size_t len, const uint32_t *input, const int64_t *caps) {
+  size_t i = 0;
+  while (i < len) {
+    uint32_t c = replacement[i];
+    if (c == '$' && i + 1 < len) {
+      uint32_t n = replacement[i + 1];
+      if (n == '$') {
+        buf_char(b, '$');
+        i += 2;
+        continue;
+      }
+      if (is_digit(n)) {
+        size_t g = n - '0';
+        if (g <= re->groups && caps[g * 2] >= 0) {
+          add_chars(b, input + caps[g * 2], caps[g * 2 + 1] - caps[g * 2]);
+        }
+        i += 2;
+        continue;
+      }
+    }
+    buf_char(b, c);
+    i++;
+  }
+}
+
+static V p_regex(const char *id, V *args, int n) {
+  EXPECT_ARGS(strcmp(id, "re_replace") == 0 ? 3 : 2);
+  V pattern = string_arg(id, args, 0);
+  if (pattern.type == T_EXCEPTION) {
+    return pattern;
+  }
+  Buf msg = {0};
+  Regex *re = regex_new(*pattern.as.s, &msg);
+  if (re == NULL) {
+    Buf full = {0};
+    buf_cstr(&full, "bad pattern: ");
+    buf_str(&full, buf_contents(&msg));
+    return exception_msg(E_PARSE, id, buf_contents(&full));
+  }
+  V text = string_arg(id, args, 1);
+  if (text.type == T_EXCEPTION) {
+    return text;
+  }
+  size_t len;
+  uint32_t *input = utf8_chars(*text.as.s, &len);
+
+  if (strcmp(id, "re_match") == 0) {
+    int64_t *caps = dbt_alloc((re->groups + 1) * 2 * sizeof *caps);
+    if (regex_find_at(re, input, len, 0, caps)) {
+      return captures_list(re, input, caps);
+    }
+    return dbt_nil();
+  }
+  size_t count;
+  int64_t **all;
+  if (strcmp(id, "re_find_all") == 0) {
+    all = regex_find_all(re, input, len, &count);
+    const List *l = &empty_list;
+    for (size_t i = 0; i < count; i++) {
+      l = list_push(l, captures_list(re, input, all[i]));
+    }
+    return list_value(l);
+  }
+  if (strcmp(id, "re_replace") == 0) {
+    V rv = string_arg(id, args, 2);
+    if (rv.type == T_EXCEPTION) {
+      return rv;
+    }
+    size_t rlen;
+    uint32_t *replacement = utf8_chars(*rv.as.s, &rlen);
+    all = regex_find_all(re, input, len, &count);
+    Buf b = {0};
+    size_t last = 0;
+    for (size_t i = 0; i < count; i++) {
+      size_t a = all[i][0];
+      add_chars(&b, input + last, a - last);
+      expand(&b, re, replacement, rlen, input, all[i]);
+      last = all[i][1];
+    }
+    add_chars(&b, input + last, len - last);
+    return buf_value(&b);
+  }
+  if (strcmp(id, "re_split") == 0) {
+    all = regex_find_all(re, input, len, &count);
+    const List *l = &empty_list;
+    size_t last = 0;
+    for (size_t i = 0; i < count; i++) {
+      size_t a = all[i][0], b = all[i][1];
+      // an empty match at either end doesn't split anything off
+      if (b == 0 || a == len) {
+        continue;
+      }
+      l = list_push(l, chars_value(input + last, a - last));
+      last = b;
+    }
+    l = list_push(l, chars_value(input + last, len - last));
+    return list_value(l);
+  }
+  return error(E_UNDEF, id, "function is not defined in scope");
+}
+
+// ORDERING
+
+// A comparison that can fail, putting the exception in *failed
+typedef int (*Comparison)(V a, V b, void *data, V *failed);
+
+// Stable merge sort, as in primitives_order.rs, which also doesn't care
+// whether the comparison is consistent.  Returns 0 if the comparison failed
+static int merge_sort(V *items, size_t n, Comparison cmp, void *data,
+                      V *failed) {
+  if (n < 2) {
+    return 1;
+  }
+  size_t half = n / 2;
+  if (!merge_sort(items, half, cmp, data, failed) ||
+      !merge_sort(items + half, n - half, cmp, data, failed)) {
+    return 0;
+  }
+  V *merged = dbt_alloc(n * sizeof *merged);
+  size_t i = 0, j = half, k = 0;
+  while (i < half || j < n) {
+    int take_left;
+    if (i < half && j < n) {
+      int o = cmp(items[i], items[j], data, failed);
+      if (o == 2) {
+        free(merged);
+        return 0;
+      }
+      take_left = o != 1;
+    } else {
+      take_left = i < half;
+    }
+    merged[k++] = take_left ? items[i++] : items[j++];
+  }
+  memcpy(items, merged, n * sizeof *merged);
+  free(merged);
+  return 1;
+}
+
+static int by_order(V a, V b, void *data, V *failed) {
+  (void) data;
+  (void) failed;
+  return order(a, b);
+}
+
+// Key function results come paired with their items
+static int by_key(V a, V b, void *data, V *failed) {
+  (void) data;
+  (void) failed;
+  return order(list_get(a.as.l, 0), list_get(b.as.l, 0));
+}
+
+typedef struct Comparator {
+  const char *id;
+  const Fn *fn;
+} Comparator;
+
+// A comparator's result, either a number compared to zero like compare gives
+static int by_function(V a, V b, void *data, V *failed) {
+  const Comparator *c = data;
+  V args[2];
+  args[0] = a;
+  args[1] = b;
+  V rc = apply(c->fn, args, c->id);
+  if (rc.type == T_INTEGER) {
+    return rc.as.i < 0 ? -1 : (rc.as.i > 0 ? 1 : 0);
+  } else if (rc.type == T_FLOAT && rc.as.f == rc.as.f) {
+    return float_order(rc.as.f, 0.0);
+  } else if (rc.type == T_EXCEPTION) {
+    *failed = rc;
+  } else {
+    *failed = error(E_TYPE, c->id, "comparison function must return a number");
+  }
+  return 2;
+}
+
+static V p_ordering(const char *id, V *args, int n) {
+  EXPECT_ARGS(strcmp(id, "sort") == 0 ? 1 : 2);
+  if (strcmp(id, "compare") == 0) {
+    return dbt_int(order(args[0], args[1]));
+  }
+  size_t len = 0;
+  V *items;
+  // nil being what cdr leaves at the end of a list
+  if (args[0].type == T_LIST) {
+    len = args[0].as.l->len;
+  } else if (args[0].type != T_NIL) {
+    return error(E_TYPE, id, "list expected for argument 1");
+  }
+  items = dbt_alloc((len + 1) * sizeof *items);
+  for (size_t i = 0; i < len; i++) {
+    items[i] = list_get(args[0].as.l, i);
+  }
+  V failed = dbt_nil();
+  if (strcmp(id, "sort") == 0) {
+    merge_sort(items, len, by_order, NULL, &failed);
+    return list_of_items(items, len);
+  }
+  if (args[1].type != T_FUNCTION) {
+    return error(E_TYPE, id, "function expected for argument 2");
+  }
+  const Fn *f = args[1].as.fn;
+  if (f->nparams == 1) {
+    // Key function: call it once per item, then sort by the keys
+    for (size_t i = 0; i < len; i++) {
+      V key = apply(f, &items[i], id);
+      if (key.type == T_EXCEPTION) {
+        return key;
+      }
+      V pair[2];
+      pair[0] = key;
+      pair[1] = items[i];
+      items[i] = list_of_items(pair, 2);
+    }
+    merge_sort(items, len, by_key, NULL, &failed);
+    for (size_t i = 0; i < len; i++) {
+      items[i] = list_get(items[i].as.l, 1);
+    }
+    return list_of_items(items, len);
+  } else if (f->nparams == 2) {
+    Comparator c;
+    c.id = id;
+    c.fn = f;
+    if (!merge_sort(items, len, by_function, &c, &failed)) {
+      return failed;
+    }
+    return list_of_items(items, len);
+  }
+  return error(E_ARITY, id,
+               "function must take 1 argument (key) or 2 (comparison)");
+}
+
+// MEMOIZATION PRIMITIVES
+
+static V p_memo(const char *id, V *args, int n) {
+  if (strcmp(id, "memo_clear") == 0) {
+    EXPECT_ARGS(1);
+    if (args[0].type != T_FUNCTION || args[0].as.fn->memo == NULL) {
+      return error(E_TYPE, id, "memoized function expected");
+    }
+    memo_clear(args[0].as.fn->memo);
+    return dbt_nil();
+  }
+  if (n != 1 && n != 2) {
+    return dbt_exception(E_ARITY, id, "expected 1 or 2 arguments but got %d",
+                         n);
+  }
+  if (args[0].type != T_FUNCTION) {
+    return error(E_TYPE, id, "function expected for argument 1");
+  }
+  size_t capacity = DEFAULT_MEMO_SIZE;
+  if (n == 2) {
+    if (args[1].type != T_INTEGER) {
+      return error(E_TYPE, id, "integer expected for argument 2");
+    } else if (args[1].as.i < 1) {
+      return error(E_RUNTIME, id, "cache size must be at least 1");
+    }
+    capacity = (size_t) args[1].as.i;
+  }
+  Fn *f = dbt_alloc(sizeof *f);
+  *f = *args[0].as.fn;
+  f->memo = memo_new(capacity);
+  return function_value(f);
+}
+
+// FILES, ENVIRONMENT AND PROCESSES
+
+// As Rust shows an OS error
+static V io_error(const char *id, Str path, int err) {
+  Buf b = {0};
+  buf_str(&b, path);
+  buf_printf(&b, ": %s (os error %d)", strerror(err), err);
+  return exception_msg(E_IO, id, buf_contents(&b));
+}
+
+static int write_all(int fd, const char *data, size_t len) {
+  while (len > 0) {
+    ssize_t n = write(fd, data, len);
+    if (n < 0) {
+      if (errno == EINTR) {
+        continue;
+      }
+      return 0;
+    }
+    data += n;
+    len -= n;
+  }
+  return 1;
+}
+
+static int compare_names(const void *a, const void *b) {
+  return str_order(*((const V *) a)->as.s, *((const V *) b)->as.s);
+}
+
+static V p_files(const char *id, V *args, int n) {
+  EXPECT_ARGS((strcmp(id, "write_file") == 0 ||
+               strcmp(id, "append_file") == 0) ? 2 : 1);
+  if (args[0].type != T_STRING) {
+    return error(E_TYPE, id, "first argument must be string for path");
+  }
+  Str path = *args[0].as.s;
+  const char *cpath = cstring(path);
+  if (strcmp(id, "read_file") == 0) {
+    int fd = open(cpath, O_RDONLY);
+    if (fd < 0) {
+      return io_error(id, path, errno);
+    }
+    Buf b = {0};
+    char chunk[65536];
+    for (;;) {
+      ssize_t got = read(fd, chunk, sizeof chunk);
+      if (got < 0) {
+        if (errno == EINTR) {
+          continue;
+        }
+        int err = errno;
+        close(fd);
+        return io_error(id, path, err);
+      }
+      if (got == 0) {
+        break;
+      }
+      buf_add(&b, chunk, got);
+    }
+    close(fd);
+    if (!utf8_valid(b.data, b.len)) {
+      Buf msg = {0};
+      buf_str(&msg, path);
+      buf_cstr(&msg, ": stream did not contain valid UTF-8");
+      return exception_msg(E_IO, id, buf_contents(&msg));
+    }
+    return buf_value(&b);
+  }
+  if (strcmp(id, "write_file") == 0 || strcmp(id, "append_file") == 0) {
+    if (args[1].type != T_STRING) {
+      return error(E_TYPE, id, "second argument must be string");
+    }
+    int flags = O_WRONLY | O_CREAT |
+      (strcmp(id, "write_file") == 0 ? O_TRUNC : O_APPEND);
+    int fd = open(cpath, flags, 0666);
+    if (fd < 0) {
+      return io_error(id, path, errno);
+    }
+    if (!write_all(fd, args[1].as.s->data, args[1].as.s->len)) {
+      int err = errno;
+      close(fd);
+      return io_error(id, path, err);
+    }
+    close(fd);
+    return dbt_nil();
+  }
+  if (strcmp(id, "exists?") == 0) {
+    struct stat st;
+    return dbt_bool(stat(cpath, &st) == 0);
+  }
+  if (strcmp(id, "delete_file") == 0) {
+    if (unlink(cpath) != 0) {
+      return io_error(id, path, errno);
+    }
+    return dbt_nil();
+  }
+  if (strcmp(id, "list_dir") == 0) {
+    DIR *dir = opendir(cpath);
+    if (dir == NULL) {
+      return io_error(id, path, errno);
+    }
+    V *names = NULL;
+    size_t count = 0;
+    struct dirent *entry;
+    while ((entry = readdir(dir)) != NULL) {
+      if (strcmp(entry->d_name, ".") == 0 || strcmp(entry->d_name, "..") == 0) {
+        continue;
+      }
+      names = dbt_realloc(names, (count + 1) * sizeof *names);
+      names[count++] = utf8_lossy(entry->d_name, strlen(entry->d_name));
+    }
+    closedir(dir);
+    // Directory order is up to the OS, so make it predictable
+    if (count > 0) {
+      qsort(names, count, sizeof *names, compare_names);
+    }
+    return list_of_items(names, count);
+  }
+  return error(E_UNDEF, id, "function is not defined in scope");
+}
+
+static int compare_vars(const void *a, const void *b) {
+  const List *x = ((const V *) a)->as.l, *y = ((const V *) b)->as.l;
+  int o = str_order(*list_get(x, 0).as.s, *list_get(y, 0).as.s);
+  return o != 0 ? o : str_order(*list_get(x, 1).as.s, *list_get(y, 1).as.s);
+}
+
+static V p_environment(const char *id, V *args, int n) {
+  if (strcmp(id, "getenv") == 0) {
+    EXPECT_ARGS(1);
+    if (args[0].type != T_STRING) {
+      return error(E_TYPE, id, "string argument expected");
+    }
+    Str name = *args[0].as.s;
+    if (memchr(name.data, 0, name.len) != NULL) {
+      return dbt_nil();
+    }
+    const char *value = getenv(cstring(name));
+    if (value == NULL || !utf8_valid(value, strlen(value))) {
+      return dbt_nil();
+    }
+    return cstring_value(value);
+  }
+  EXPECT_ARGS(0);
+  V *vars = NULL;
+  size_t count = 0;
+  for (char **e = environ; *e != NULL; e++) {
+    // A leading = is part of the name
+    const char *eq = **e ? strchr(*e + 1, '=') : NULL;
+    if (eq == NULL) {
+      continue;
+    }
+    V pair[2];
+    pair[0] = utf8_lossy(*e, eq - *e);
+    pair[1] = utf8_lossy(eq + 1, strlen(eq + 1));
+    vars = dbt_realloc(vars, (count + 1) * sizeof *vars);
+    vars[count++] = list_of_items(pair, 2);
+  }
+  if (count > 0) {
+    qsort(vars, count, sizeof *vars, compare_vars);
+  }
+  return list_of_items(vars, count);
+}
+
+static int pipe_cloexec(int fds[2]) {
+  if (pipe(fds) != 0) {
+    return 0;
+  }
+  fcntl(fds[0], F_SETFD, FD_CLOEXEC);
+  fcntl(fds[1], F_SETFD, FD_CLOEXEC);
+  return 1;
+}
+
+static V p_process(const char *id, V *args, int n) {
+  EXPECT_ARGS(3);
+  if (args[0].type != T_STRING) {
+    return error(E_TYPE, id, "first argument must be string for command");
+  }
+  Str cmd = *args[0].as.s;
+  size_t nargs = 0;
+  if (args[1].type == T_LIST) {
+    nargs = args[1].as.l->len;
+  } else if (args[1].type != T_NIL) {
+    return error(E_TYPE, id, "second argument must be list of strings");
+  }
+  const char **argv = dbt_alloc((nargs + 2) * sizeof *argv);
+  argv[0] = cstring(cmd);
+  for (size_t i = 0; i < nargs; i++) {
+    V a = list_get(args[1].as.l, i);
+    if (a.type != T_STRING) {
+      return error(E_TYPE, id, "second argument must be list of strings");
+    }
+    argv[i + 1] = cstring(*a.as.s);
+  }
+  argv[nargs + 1] = NULL;
+  const Str *input = NULL;
+  if (args[2].type == T_STRING) {
+    input = args[2].as.s;
+  } else if (args[2].type != T_NIL) {
+    return error(E_TYPE, id,
+                 "third argument must be string or nil for stdin");
+  }
+
+  // The child reports a failed exec through the status pipe
+  int in[2] = { -1, -1 }, out[2], err[2], status[2];
+  if ((input != NULL && !pipe_cloexec(in)) || !pipe_cloexec(out) ||
+      !pipe_cloexec(err) || !pipe_cloexec(status)) {
+    return io_error(id, cmd, errno);
+  }
+  fflush(stdout);
+  pid_t pid = fork();
+  if (pid < 0) {
+    return io_error(id, cmd, errno);
+  }
+  if (pid == 0) {
+    signal(SIGPIPE, SIG_DFL);
+    int stdin_fd = input != NULL ? in[0] : open("/dev/null", O_RDONLY);
+    dup2(stdin_fd, 0);
+    dup2(out[1], 1);
+    dup2(err[1], 2);
+    execvp(argv[0], (char *const *) argv);
+    int e = errno;
+    write_all(status[1], (const char *) &e, sizeof e);
+    _exit(127);
+  }
+  close(status[1]);
+  close(out[1]);
+  close(err[1]);
+  if (input != NULL) {
+    close(in[0]);
+  }
+  int exec_error;
+  ssize_t got;
+  while ((got = read(status[0], &exec_error, sizeof exec_error)) < 0 &&
+         errno == EINTR) {
+  }
+  close(status[0]);
+  if (got == sizeof exec_error) {
+    waitpid(pid, NULL, 0);
+    close(out[0]);
+    close(err[0]);
+    if (input != NULL) {
+      close(in[1]);
+    }
+    return io_error(id, cmd, exec_error);
+  }
+
+  // Feed stdin while reading the output so a chatty child can't deadlock us
+  // by filling up its stdout before it's done reading
+  Buf outputs[2] = { {0}, {0} };
+  int fds[2] = { out[0], err[0] };
+  size_t written = 0;
+  int writing = input != NULL;
+  if (writing && input->len == 0) {
+    close(in[1]);
+    writing = 0;
+  }
+  while (fds[0] >= 0 || fds[1] >= 0 || writing) {
+    struct pollfd polls[3];
+    int npolls = 0;
+    for (int i = 0; i < 2; i++) {
+      if (fds[i] >= 0) {
+        polls[npolls].fd = fds[i];
+        polls[npolls].events = POLLIN;
+        npolls++;
+      }
+    }
+    if (writing) {
+      polls[npolls].fd = in[1];
+      polls[npolls].events = POLLOUT;
+      npolls++;
+    }
+    if (poll(polls, npolls, -1) < 0) {
+      if (errno == EINTR) {
+        continue;
+      }
+      break;
+    }
+    for (int p = 0; p < npolls; p++) {
+      if (polls[p].revents == 0) {
+        continue;
+      }
+      if (writing && polls[p].fd == in[1]) {
+        ssize_t w = write(in[1], input->data + written, input->len - written);
+        // A child that exits without reading all of its input is fine
+        if (w < 0 && errno != EINTR && errno != EAGAIN) {
+          written = input->len;
+        } else if (w > 0) {
+          written += w;
+        }
+        if (written == input->len) {
+          close(in[1]);
+          writing = 0;
+        }
+        continue;
+      }
+      int i = polls[p].fd == fds[0] ? 0 : 1;
+      char chunk[65536];
+      ssize_t r = read(fds[i], chunk, sizeof chunk);
+      if (r > 0) {
+        buf_add(&outputs[i], chunk, r);
+      } else if (r == 0 || errno != EINTR) {
+        close(fds[i]);
+        fds[i] = -1;
+      }
+    }
+  }
+  int wstatus;
+  while (waitpid(pid, &wstatus, 0) < 0) {
+    if (errno != EINTR) {
+      return io_error(id, cmd, errno);
+    }
+  }
+
+  V items[3];
+  // No exit code means the process was killed by a signal
+  items[0] = dbt_int(WIFEXITED(wstatus) ? WEXITSTATUS(wstatus) : -1);
+  items[1] = utf8_lossy(outputs[0].data, outputs[0].len);
+  items[2] = utf8_lossy(outputs[1].data, outputs[1].len);
+  return list_of_items(items, 3);
+}
+
+static const struct {
+  const char *name;
+  Prim prim;
+} primitives[] = {
+  { "int", p_int }, { "float", p_float }, { "string", p_string },
+  { ">>", p_print },
+  { "read_file", p_files }, { "write_file", p_files },
+  { "append_file", p_files }, { "exists?", p_files },
+  { "delete_file", p_files }, { "list_dir", p_files },
+  { "getenv", p_environment }, { "env", p_environment },
+  { "run", p_process },
+  { "split", p_strings }, { "join", p_strings }, { "find", p_strings },
+  { "contains?", p_strings }, { "replace", p_strings },
+  { "starts_with?", p_strings }, { "ends_with?", p_strings },
+  { "trim", p_strings }, { "upper", p_strings }, { "lower", p_strings },
+  { "chars", p_strings }, { "ord", p_strings }, { "chr", p_strings },
+  { "repeat", p_strings },
+  { "format", p_format }, { "${}", p_interpolate },
+  { "re_match", p_regex }, { "re_find_all", p_regex },
+  { "re_replace", p_regex }, { "re_split", p_regex },
+  { "compare", p_ordering }, { "sort", p_ordering },
+  { "sort_by", p_ordering },
+  { "memo", p_memo }, { "memo_clear", p_memo },
+  { "+", p_add }, { "-", p_subtract }, { "*", p_multiply },
+  { "/", p_divide }, { "%", p_remainder },
+  { "!", p_not }, { "&", p_and }, { "|", p_or },
+  { "?", p_if }, { "=", p_equal }, { ">", p_greater }, { "<", p_less },
+  { "substr", p_substr }, { "strlen", p_strlen },
+  { "car", p_car }, { "cdr", p_cdr },
+  { "catch", p_catch }, { "raise", p_raise }, { "rethrow", p_rethrow },
+  { "wrap", p_wrap }, { "ensure", p_ensure }, { "exit", p_exit },
+  { "~", p_return }
+};
+
+static Prim find_primitive(const char *id) {
+  for (size_t i = 0; i < sizeof primitives / sizeof primitives[0]; i++) {
+    if (strcmp(primitives[i].name, id) == 0) {
+      return primitives[i].prim;
+    }
+  }
+  return NULL;
+}
+
+// RUNNING
+
+static Body program;
+static int program_has_main;
+static const List *program_args;
+static int exit_code;
+
+// Exit codes as in evaluator.rs
+static void *run_program(void *unused) {
+  (void) unused;
+  dbt_push_scope();
+  bind(ARGS_SLOT, 0, list_value(program_args));
+  V rc = program("[main program]");
+  dbt_pop_scope();
+  if (rc.type == T_EXCEPTION) {
+    const Exc *e = rc.as.e;
+    if (e->kind == E_EXIT && e->payload.type == T_INTEGER) {
+      exit_code = (int) e->payload.as.i;
+      return NULL;
+    }
+  } else if (rc.type == T_INTEGER && program_has_main) {
+    if (rc.as.i >= 0 && rc.as.i <= 255) {
+      exit_code = (int) rc.as.i;
+      return NULL;
+    }
+    rc = dbt_exception(E_RUNTIME, "main",
+                       "exit code %" PRId64 " is not between 0 and 255",
+                       rc.as.i);
+  } else {
+    exit_code = 0;
+    return NULL;
+  }
+  Buf b = {0};
+  show_report(&b, rc.as.e);
+  buf_cstr(&b, "\n");
+  fwrite(b.data, 1, b.len, stdout);
+  exit_code = 1;
+  return NULL;
+}
+
+static int dbt_main(int argc, char **argv, Body body, int has_main) {
+  // Writing to a closed pipe is an io error, not the end of the program
+  signal(SIGPIPE, SIG_IGN);
+  setvbuf(stdout, NULL, _IOLBF, 0);
+  program = body;
+  program_has_main = has_main;
+  program_args = &empty_list;
+  for (int i = 1; i < argc; i++) {
+    program_args = list_push(program_args, cstring_value(argv[i]));
+  }
+  // Deep recursion needs a lot more stack than the main thread gets
+  pthread_t thread;
+  pthread_attr_t attr;
+  pthread_attr_init(&attr);
+  pthread_attr_setstacksize(&attr,
+                            (size_t) (DBT_MAX_DEPTH + 64) * STACK_PER_CALL);
+  if (pthread_create(&thread, &attr, run_program, NULL) == 0) {
+    pthread_join(thread, NULL);
+  } else {
+    run_program(NULL);
+  }
+  fflush(stdout);
+  return exit_code;
+}
@@ -0,0 +1,77 @@
+// The symbol table: every identifier is interned as a Symbol, the same number
+// for the same name everywhere, so calls and definitions carry a number
+// rather than a copy of the name, bindings are kept in a vector indexed by
+// symbol rather than searching scopes for strings, and exception stacks are
+// lists of symbols.  Scoping is still dynamic, so which binding a name refers
+// to is only known at run time, but finding it is just a look at the top of
+// that symbol's binding stack.  The table is per thread, so symbols are only
+// good on the thread that made them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use primitives::Primitive;
+use primitives::PRIMITIVES;
+
+use encoding::Symbol;
+
+// Names the interpreter binds or looks for itself
+pub const SELF: Symbol = 0;
+pub const ARGS: Symbol = 1;
+pub const DOLLAR: Symbol = 2;
+// The primitives come next, in the order of primitives::PRIMITIVES
+pub const FIRST_PRIMITIVE: Symbol = 3;
+// The primitive string interpolation is parsed into, named so that no program
+// can bind it (or call it)
+pub const INTERPOLATE: Symbol =
+  FIRST_PRIMITIVE + Primitive::Interpolate as Symbol;
+
+struct Symbols {
+  symbols: HashMap<Rc<str>, Symbol>,
+  names: Vec<Rc<String>>
+}
+
+impl Symbols {
+  fn add(&mut self, name: &str) -> Symbol {
+    let symbol = self.names.len();
+    self.names.push(Rc::new(name.to_string()));
+    self.symbols.insert(Rc::from(name), symbol);
+    symbol
+  }
+}
+
+fn table() -> Symbols {
+  let mut symbols = Symbols { symbols: HashMap::new(), names: Vec::new() };
+  for n in &["self", "args", "$"] {
+    symbols.add(n);
+  }
+  for &(n, _) in PRIMITIVES {
+    symbols.add(n);
+  }
+  symbols
+}
+
+thread_local! {
+  static SYMBOLS: RefCell<Symbols> = RefCell::new(table());
+}
+
+pub fn intern(name: &str) -> Symbol {
+  SYMBOLS.with(|s| {
+    let mut symbols = s.borrow_mut();
+    match symbols.symbols.get(name) {
+      Some(&n) => n,
+      None => symbols.add(name),
+    }
+  })
+}
+
+// The name of a symbol, which doesn't copy it
+pub fn name(symbol: Symbol) -> Rc<String> {
+  SYMBOLS.with(|s| {
+    match s.borrow().names.get(symbol) {
+      Some(n) => n.clone(),
+      None => Rc::new(format!("<symbol {}>", symbol)),
+    }
+  })
+}
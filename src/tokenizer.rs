@@ -1,8 +1,64 @@
 // Super simple tokenizer/scanner:
 
 use encoding::Token;
+use encoding::StringPart;
 
-fn next_token(chars: &Vec<char>, start: usize) -> (Token, usize) {
+// Scan a string literal starting at its opening quote, splitting out any
+// ${...} interpolated code (tokenized separately) from the plain text
+fn scan_string(chars: &Vec<char>, start: usize) ->
+  Result<(Vec<StringPart>, usize), String> {
+  let mut parts = Vec::new();
+  let mut text = String::new();
+  let mut index = start + 1;
+  loop {
+    if index >= chars.len() {
+      return Err(format!("Unterminated string in source: {}", text));
+    }
+    let c = chars[index];
+    if c == '"' {
+      break;
+    } else if c == '$' && index + 1 < chars.len() && chars[index + 1] == '{' {
+      if !text.is_empty() {
+        parts.push(StringPart::Text(text));
+        text = String::new();
+      }
+      let end = scan_code(chars, index + 2)?;
+      let code: String = chars[index + 2..end].iter().cloned().collect();
+      parts.push(StringPart::Code(tokenize(&code)?));
+      index = end + 1;
+    } else {
+      text.push(c);
+      index += 1;
+    }
+  }
+  if !text.is_empty() {
+    parts.push(StringPart::Text(text));
+  }
+  Ok((parts, index + 1))
+}
+
+// Find the } closing interpolated code, skipping over anything nested in it
+fn scan_code(chars: &Vec<char>, start: usize) -> Result<usize, String> {
+  let mut depth = 0;
+  let mut index = start;
+  while index < chars.len() {
+    match chars[index] {
+      '{' => depth += 1,
+      '}' if depth == 0 => { return Ok(index); },
+      '}' => depth -= 1,
+      '"' => {
+        let (_, end) = scan_string(chars, index)?;
+        index = end;
+        continue;
+      },
+      _ => {},
+    }
+    index += 1;
+  }
+  Err("Unterminated ${ in string".to_string())
+}
+
+fn next_token(chars: &Vec<char>, start: usize) -> Result<(Token, usize), String> {
   let reserved = [':', ';', ',', '(', ')', '[', ']', '{', '}', '"', '#'];
 
   let mut index = start;
@@ -11,66 +67,73 @@ fn next_token(chars: &Vec<char>, start: usize) -> (Token, usize) {
     if index == chars.len() - 1 {
       // EOF is only returned with trailing whitespace (or closing comment), but
       // we need to return something when there's no "real" token left to return
-      return (Token::EOF, index + 1);
+      return Ok((Token::EOF, index + 1));
     }
     index += 1;
     c = chars[index];
   }
   let from = index;
   match c {
-    ':' => return (Token::Colon, index + 1),
-    ';' => return (Token::Semicolon, index + 1),
-    ',' => return (Token::Comma, index + 1),
-    '(' => return (Token::OpenParen, index + 1),
-    ')' => return (Token::CloseParen, index + 1),
-    '[' => return (Token::OpenBracket, index + 1),
-    ']' => return (Token::CloseBracket, index + 1),
-    '{' => return (Token::OpenBrace, index + 1),
-    '}' => return (Token::CloseBrace, index + 1),
+    ':' => Ok((Token::Colon, index + 1)),
+    ';' => Ok((Token::Semicolon, index + 1)),
+    ',' => Ok((Token::Comma, index + 1)),
+    '(' => Ok((Token::OpenParen, index + 1)),
+    ')' => Ok((Token::CloseParen, index + 1)),
+    '[' => Ok((Token::OpenBracket, index + 1)),
+    ']' => Ok((Token::CloseBracket, index + 1)),
+    '{' => Ok((Token::OpenBrace, index + 1)),
+    '}' => Ok((Token::CloseBrace, index + 1)),
     '#' => {
+      if index == chars.len() - 1 {
+        // comment with nothing after it at all
+        return Ok((Token::EOF, index + 1));
+      }
       index += 1;
       c = chars[index];
       while index < chars.len() - 1 && c != '\n' && c != '\r' {
         index += 1;
         c = chars[index];
       }
+      if c != '\n' && c != '\r' {
+        // comment runs to the end of the source
+        return Ok((Token::EOF, index + 1));
+      }
       // This is a comment, so we return the next token after it
       next_token(&chars, index)
     }
     '"' => {
-      index += 1;
-      c = chars[index];
-      while index < chars.len() - 1 && c != '"' {
-        index += 1;
-        c = chars[index];
+      let (mut parts, end) = scan_string(chars, index)?;
+      if parts.len() > 1 {
+        return Ok((Token::Interpolated(parts), end));
       }
-      let s = chars[from + 1..index].iter().cloned().collect();
-      if c != '"' {
-        // TODO: Do this in a more controlled way
-        panic!("Unterminated string in source: {}", s);
+      match parts.pop() {
+        Some(StringPart::Code(tokens)) => {
+          Ok((Token::Interpolated(vec![StringPart::Code(tokens)]), end))
+        },
+        Some(StringPart::Text(s)) => Ok((Token::String(s), end)),
+        None => Ok((Token::String("".to_string()), end)),
       }
-      (Token::String(s), index + 1)
     }
     _ => {
-      while index < chars.len() - 1 && !c.is_whitespace() &&
-        !reserved.contains(&c) {
+      // (this may run right up to the end, for interpolated code)
+      while index < chars.len() && !chars[index].is_whitespace() &&
+        !reserved.contains(&chars[index]) {
         index += 1;
-        c = chars[index];
       }
       let s:String = chars[from..index].iter().cloned().collect();
       if s == "true" {
-        return (Token::True, index);
+        return Ok((Token::True, index));
       } else if s == "false" {
-        return (Token::False, index);
+        return Ok((Token::False, index));
       } else if s == "nil" {
-        return (Token::Nil, index);
+        return Ok((Token::Nil, index));
       }
       match s.parse::<i64>() {
-        Ok(n) => (Token::Integer(n), index),
+        Ok(n) => Ok((Token::Integer(n), index)),
         _ => {
           match s.parse::<f64>() {
-            Ok(n) => (Token::Float(n), index),
-            _ => (Token::ID(s), index),
+            Ok(n) => Ok((Token::Float(n), index)),
+            _ => Ok((Token::ID(s), index)),
           }
         },
       }
@@ -78,18 +141,24 @@ fn next_token(chars: &Vec<char>, start: usize) -> (Token, usize) {
   }
 }
 
-pub fn tokenize(s: &str) -> Vec<Token> {
+pub fn tokenize(s: &str) -> Result<Vec<Token>, String> {
   let chars:Vec<char> = s.chars().collect();
 
   let mut tokens = Vec::new();
 
   let mut index = 0;
   while index < chars.len() {
-    let (token, change) = next_token(&chars, index);
+    let (token, change) = next_token(&chars, index)?;
     index = change;
     // For debugging:
     //println!("{}:{:?}", index, token);
     tokens.push(token);
   }
-  tokens
+  // EOF normally comes from trailing whitespace, but the parser needs it
+  // either way
+  match tokens.last() {
+    Some(&Token::EOF) => {},
+    _ => tokens.push(Token::EOF),
+  }
+  Ok(tokens)
 }
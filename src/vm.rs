@@ -0,0 +1,523 @@
+// Bytecode for blocks and a stack machine to run it, the alternative to
+// walking the syntax tree (see Interpreter.bytecode).  Each block compiles to
+// straight-line code once, function bodies on their first call, and calls
+// keep their state on the machine's own stacks rather than in Rust recursion.
+// Everything about the language stays the same, down to the order evaluation
+// steps are counted in and the calling context exceptions pick up on their
+// way out, so running with either gives the same results.  What the machine
+// gains is from working things out as it compiles: which calls would run a
+// primitive, so the everyday arithmetic can be done on the spot, and which
+// arguments and blocks can't define anything, so they need no scope.
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::rc::Weak;
+
+use evaluator;
+use memo;
+use primitives;
+use symbols;
+
+use encoding::Block;
+use encoding::Expression;
+use encoding::Definition;
+use encoding::Symbol;
+
+use encoding::Interpreter;
+use encoding::FunctionOrValue;
+use encoding::Evaluation;
+use encoding::ListEval;
+use encoding::Function;
+use encoding::ExceptionType;
+
+use memo::Memo;
+use memo::Key;
+use primitives::Primitive;
+
+pub struct Chunk {
+  code: Vec<Op>
+}
+
+// Exceptions leaving the called function get its id added to their stack
+struct CallOp {
+  id: Symbol,
+  // For primitives and error messages
+  name: Rc<String>,
+  // The primitive it runs if nothing is bound to the name
+  primitive: Option<&'static Primitive>,
+  args: usize,
+  // Just past the call's Apply, for when the arguments aren't needed
+  resume: usize,
+  // Body last called from here, which is nearly always the one called next
+  // time, so it can skip the search through Interpreter.compiled.  Weak since
+  // a recursive function's code would otherwise hold on to itself
+  last: RefCell<Option<(usize, Weak<Chunk>)>>
+}
+
+enum Op {
+  // Count an evaluation step; if a limit has been hit the termination is the
+  // expression's value, and everything up to the given op is skipped
+  Step(usize),
+  // A constant, which takes its own step and size check
+  Const(Evaluation),
+  // An empty block's value, which isn't a step
+  Nil,
+  // Gather up the given number of values into a list
+  List(usize),
+  // Look up what's being called; its arguments follow, each between Arg and
+  // ArgEnd, and then Apply
+  Call(Rc<CallOp>),
+  // An argument to a function is evaluated as a block of its own, though it
+  // only needs a scope of its own if it could define something
+  Arg,
+  ArgEnd(bool),
+  Apply,
+  Define(Rc<Definition>),
+  CheckSize,
+  EnterScope,
+  LeaveScope,
+  // After a statement: an exception ends the block, skipping to the given op,
+  // and anything else is thrown away
+  Statement(usize),
+  // After the last statement, whose value is the block's
+  LastStatement,
+  // End of a block, back to the caller
+  Return
+}
+
+// A call whose arguments are being evaluated
+struct Pending {
+  op: Rc<CallOp>,
+  // The function being called; arguments to functions are evaluated like
+  // blocks of their own, arguments to primitives aren't
+  func: Option<Function>,
+  // Evaluating the first argument of $, which is the function to call
+  callee: bool,
+  // Where the arguments start on the value stack
+  base: usize
+}
+
+// A function body's caller, to go back to when it returns, and where its
+// value goes in the cache if the function is memoized
+struct Frame {
+  code: Rc<Chunk>,
+  pc: usize,
+  context: Symbol,
+  memo: Option<(Rc<Memo>, Vec<Key>)>
+}
+
+struct Machine {
+  values: Vec<Evaluation>,
+  pending: Vec<Pending>,
+  frames: Vec<Frame>,
+  code: Rc<Chunk>,
+  pc: usize,
+  context: Symbol
+}
+
+fn compile_expression(e: &Expression, code: &mut Vec<Op>) {
+  let value = match e {
+    &Expression::Nil => Evaluation::Nil,
+    &Expression::True => Evaluation::True,
+    &Expression::False => Evaluation::False,
+    &Expression::Integer(x) => Evaluation::Integer(x),
+    &Expression::Float(x) => Evaluation::Float(x),
+    &Expression::String(ref s) => Evaluation::String(s.clone()),
+    _ => {
+      let step = code.len();
+      code.push(Op::Step(0));
+      compile_compound(e, code);
+      code.push(Op::CheckSize);
+      code[step] = Op::Step(code.len());
+      return;
+    },
+  };
+  code.push(Op::Const(value));
+}
+
+// Could evaluating this bind anything in the current scope?  Only a
+// definition can, but it could be anywhere in arguments to a primitive
+fn defines(e: &Expression) -> bool {
+  match e {
+    &Expression::List(ref list) => list.items.iter().any(defines),
+    &Expression::Call(ref call) => call.params.iter().any(defines),
+    &Expression::Definition(_) => true,
+    _ => false,
+  }
+}
+
+fn compile_compound(e: &Expression, code: &mut Vec<Op>) {
+  match e {
+    &Expression::List(ref list) => {
+      for i in &list.items {
+        compile_expression(i, code);
+      }
+      code.push(Op::List(list.items.len()));
+    },
+    &Expression::Call(ref call) => {
+      let at = code.len();
+      code.push(Op::Return);
+      for p in &call.params {
+        let scoped = defines(p);
+        if scoped {
+          code.push(Op::Arg);
+        }
+        compile_expression(p, code);
+        code.push(Op::ArgEnd(scoped));
+      }
+      code.push(Op::Apply);
+      code[at] = Op::Call(Rc::new(CallOp { id: call.id,
+                                           name: symbols::name(call.id),
+                                           primitive: primitives::primitive(call.id),
+                                           args: call.params.len(),
+                                           resume: code.len(),
+                                           last: RefCell::new(None) }));
+    },
+    &Expression::Definition(ref def) => {
+      code.push(Op::Define(Rc::new(def.clone())));
+    },
+    _ => {},
+  }
+}
+
+pub fn compile(block: &Block) -> Chunk {
+  // As with arguments, a block that can't define anything can do without a
+  // scope of its own
+  let scoped = block.expressions.iter().any(defines);
+  let mut code = Vec::new();
+  if scoped {
+    code.push(Op::EnterScope);
+  }
+  if block.expressions.is_empty() {
+    code.push(Op::Nil);
+  }
+  let mut exits = Vec::new();
+  for (i, e) in block.expressions.iter().enumerate() {
+    compile_expression(e, &mut code);
+    if i + 1 < block.expressions.len() {
+      exits.push(code.len());
+      code.push(Op::Statement(0));
+    } else {
+      code.push(Op::LastStatement);
+    }
+  }
+  let exit = code.len();
+  for x in exits {
+    code[x] = Op::Statement(exit);
+  }
+  if scoped {
+    code.push(Op::LeaveScope);
+  }
+  code.push(Op::Return);
+  Chunk { code: code }
+}
+
+fn block_key(block: &Rc<Block>) -> usize {
+  &**block as *const Block as usize
+}
+
+// Code for a function body, compiled the first time it's called
+fn body(block: &Rc<Block>, interp: &mut Interpreter) -> Rc<Chunk> {
+  let key = block_key(block);
+  if let Some(&(_, ref chunk)) = interp.compiled.get(&key) {
+    return chunk.clone();
+  }
+  let chunk = Rc::new(compile(block));
+  // Holding on to the block keeps its address from being reused
+  interp.compiled.insert(key, (block.clone(), chunk.clone()));
+  chunk
+}
+
+impl CallOp {
+  fn body(&self, block: &Rc<Block>, interp: &mut Interpreter) -> Rc<Chunk> {
+    let key = block_key(block);
+    if let Some((k, ref chunk)) = *self.last.borrow() {
+      if k == key {
+        if let Some(chunk) = chunk.upgrade() {
+          return chunk;
+        }
+      }
+    }
+    let chunk = body(block, interp);
+    *self.last.borrow_mut() = Some((key, Rc::downgrade(&chunk)));
+    chunk
+  }
+}
+
+// The start of Function::apply: the call depth check, then a scope with the
+// parameters in it.  Whoever calls this runs the body and ends the scope
+fn enter(func: &Function, args: Vec<Evaluation>, interp: &mut Interpreter,
+         context: Symbol) -> Option<Evaluation> {
+  if interp.depth >= interp.max_depth {
+    return Some(evaluator::exception(ExceptionType::StackOverflow,
+                                     &symbols::name(context),
+                                     format!("maximum call depth of {} exceeded",
+                                             interp.max_depth)));
+  }
+  interp.push_scope();
+  interp.bind(symbols::SELF,
+              FunctionOrValue::Value(Evaluation::Function(func.clone())));
+  for (y, eval) in args.into_iter().enumerate() {
+    interp.bind(func.params[y], FunctionOrValue::Value(eval));
+  }
+  interp.depth += 1;
+  None
+}
+
+impl Machine {
+  fn new(code: Rc<Chunk>, context: Symbol) -> Machine {
+    Machine { values: Vec::new(), pending: Vec::new(), frames: Vec::new(),
+              code: code, pc: 0, context: context }
+  }
+
+  fn pop(&mut self) -> Evaluation {
+    self.values.pop().unwrap_or(Evaluation::Nil)
+  }
+
+  // The call's value is known without (the rest of) its arguments
+  fn skip_call(&mut self, value: Evaluation, op: &CallOp) {
+    self.values.push(value);
+    self.pc = op.resume;
+  }
+
+  // Run until the outermost block returns
+  fn run(&mut self, interp: &mut Interpreter) -> Evaluation {
+    'switch: loop {
+      let code = self.code.clone();
+      loop {
+        let pc = self.pc;
+        self.pc += 1;
+        match code.code[pc] {
+          Op::Step(end) => {
+            if let Some(e) = interp.step() {
+              self.values.push(e);
+              self.pc = end;
+            }
+          },
+          Op::Const(ref value) => {
+            let value = match interp.step() {
+              Some(e) => e,
+              None => interp.check_size(value.clone()),
+            };
+            self.values.push(value);
+          },
+          Op::Nil => self.values.push(Evaluation::Nil),
+          Op::List(n) => {
+            let start = self.values.len() - n;
+            let items = self.values.split_off(start);
+            self.values.push(Evaluation::List(ListEval::from_items(items)));
+          },
+          Op::Call(ref op) => self.call(op, interp),
+          Op::Arg => {
+            if let Some(&Pending { func: Some(_), .. }) = self.pending.last() {
+              interp.push_scope();
+            }
+          },
+          Op::ArgEnd(scoped) => self.arg_end(scoped, interp),
+          Op::Apply => {
+            if self.apply(interp) {
+              continue 'switch;
+            }
+          },
+          Op::Define(ref def) => {
+            let rc = def.evaluate(interp);
+            self.values.push(rc);
+          },
+          Op::CheckSize => {
+            let value = self.pop();
+            self.values.push(interp.check_size(value));
+          },
+          Op::EnterScope => interp.push_scope(),
+          Op::LeaveScope => interp.pop_scope(),
+          Op::Statement(exit) => {
+            let value = self.pop();
+            if let Evaluation::Exception(_) = value {
+              self.values.push(evaluator::leave_block(value, self.context));
+              self.pc = exit;
+            }
+          },
+          Op::LastStatement => {
+            let value = self.pop();
+            self.values.push(evaluator::leave_block(value, self.context));
+          },
+          Op::Return => {
+            match self.frames.pop() {
+              Some(frame) => {
+                if let Some((memo, key)) = frame.memo {
+                  if let Some(value) = self.values.last() {
+                    memo.insert(key, value);
+                  }
+                }
+                interp.depth -= 1;
+                interp.pop_scope();
+                self.code = frame.code;
+                self.pc = frame.pc;
+                self.context = frame.context;
+                continue 'switch;
+              },
+              None => { return self.pop(); },
+            }
+          },
+        }
+      }
+    }
+  }
+
+  // Work out what sort of call this is, as in Call::evaluate
+  fn call(&mut self, op: &Rc<CallOp>, interp: &mut Interpreter) {
+    let binding = interp.lookup(op.id).map(|b| b.clone());
+    let base = self.values.len();
+    match binding {
+      Some(FunctionOrValue::Function(func)) => {
+        if op.args != func.params.len() {
+          let e = evaluator::exception(ExceptionType::ArityError, &op.name,
+                                       format!("expected {} arguments but got {}",
+                                               op.args, func.params.len()));
+          self.skip_call(e, op);
+          return;
+        }
+        self.pending.push(Pending { op: op.clone(), func: Some(func),
+                                    callee: false, base: base });
+      },
+      Some(FunctionOrValue::Value(value)) => {
+        // Already evaluated, i.e., it's a passed param
+        self.skip_call(value, op);
+      },
+      None if op.id == symbols::DOLLAR => {
+        if op.args < 1 {
+          let e = evaluator::exception(ExceptionType::ArityError, &op.name,
+                                       "expected at least 1 argument but got 0".to_string());
+          self.skip_call(e, op);
+          return;
+        }
+        self.pending.push(Pending { op: op.clone(), func: None, callee: true,
+                                    base: base });
+      },
+      None => {
+        self.pending.push(Pending { op: op.clone(), func: None, callee: false,
+                                    base: base });
+      },
+    }
+  }
+
+  fn arg_end(&mut self, scoped: bool, interp: &mut Interpreter) {
+    let (wrapped, callee) = match self.pending.last() {
+      Some(p) => (p.func.is_some(), p.callee),
+      None => { return; },
+    };
+    if wrapped {
+      let value = self.pop();
+      let context = self.pending.last().unwrap().op.id;
+      self.values.push(evaluator::leave_block(value, context));
+      if scoped {
+        interp.pop_scope();
+      }
+    } else if callee {
+      // $ has its function, the rest of the arguments are for it
+      let p = self.pending.pop().unwrap();
+      let callee = self.pop();
+      match callee {
+        Evaluation::Exception(_) => self.skip_call(callee, &p.op),
+        Evaluation::Function(func) => {
+          if p.op.args - 1 != func.params.len() {
+            let e = evaluator::exception(ExceptionType::ArityError, &p.op.name,
+                                         format!("called function expected {} arguments but got {}",
+                                                 p.op.args - 1,
+                                                 func.params.len()));
+            self.skip_call(e, &p.op);
+          } else {
+            let base = self.values.len();
+            self.pending.push(Pending { op: p.op, func: Some(func),
+                                        callee: false, base: base });
+          }
+        },
+        _ => {
+          let e = evaluator::exception(ExceptionType::TypeError, &p.op.name,
+                                       "function expected as first argument".to_string());
+          self.skip_call(e, &p.op);
+        },
+      }
+    }
+  }
+
+  // All the arguments are in, make the call.  True when it's a function, whose
+  // body is now running
+  fn apply(&mut self, interp: &mut Interpreter) -> bool {
+    let p = match self.pending.pop() {
+      Some(p) => p,
+      None => { return false; },
+    };
+    match p.func {
+      Some(func) => {
+        let args = self.values.split_off(p.base);
+        let cache = memo::entry(&func, &args);
+        if let Some((ref memo, ref key)) = cache {
+          if let Some(value) = memo.get(key) {
+            self.values.push(value);
+            return false;
+          }
+        }
+        if let Some(e) = enter(&func, args, interp, p.op.id) {
+          self.values.push(e);
+          return false;
+        }
+        let code = p.op.body(&func.block, interp);
+        let caller = Frame { code: mem::replace(&mut self.code, code),
+                             pc: self.pc,
+                             context: mem::replace(&mut self.context,
+                                                   p.op.id),
+                             memo: cache };
+        self.frames.push(caller);
+        self.pc = 0;
+        true
+      },
+      None => {
+        let quick = match p.op.primitive {
+          Some(op) if interp.terminated.is_none() => {
+            primitives::quick(op, &self.values[p.base..])
+          },
+          _ => None,
+        };
+        if let Some(rc) = quick {
+          self.values.truncate(p.base);
+          self.values.push(rc);
+          return false;
+        }
+        let args = self.values.split_off(p.base);
+        let rc = primitives::guarded_system_functions(p.op.id, args, interp);
+        self.values.push(rc);
+        false
+      },
+    }
+  }
+}
+
+// Run a whole program's block
+pub fn run(block: &Block, interp: &mut Interpreter, context: Symbol) ->
+  Evaluation {
+  Machine::new(Rc::new(compile(block)), context).run(interp)
+}
+
+// Function::apply for the machine, so primitives calling back into user
+// functions run them as bytecode too
+pub fn apply(func: &Function, args: Vec<Evaluation>, interp: &mut Interpreter,
+             context: Symbol) -> Evaluation {
+  let cache = memo::entry(func, &args);
+  if let Some((ref memo, ref key)) = cache {
+    if let Some(value) = memo.get(key) {
+      return value;
+    }
+  }
+  if let Some(e) = enter(func, args, interp, context) {
+    return e;
+  }
+  let code = body(&func.block, interp);
+  let rc = Machine::new(code, context).run(interp);
+  interp.depth -= 1;
+  interp.pop_scope();
+  if let Some((memo, key)) = cache {
+    memo.insert(key, &rc);
+  }
+  rc
+}